@@ -1,26 +1,270 @@
-use crate::error::{DecryptionErr, EncryptionErr};
-use argon2::password_hash::rand_core::{CryptoRng, OsRng, RngCore};
-use chacha20poly1305::aead::Aead;
-use chacha20poly1305::{AeadCore, ChaCha20Poly1305 as CCP, KeyInit, Nonce, aead};
+use crate::error::{CipherErr, DecryptionErr, EncryptionErr};
+use aes_gcm_siv::Aes256GcmSiv;
+use chacha20poly1305::aead::generic_array::GenericArray;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, XChaCha20Poly1305};
+use rand::RngCore;
+use zeroize::Zeroizing;
 
-pub fn encrypt(key: [u8; 32], nonce: [u8; 12], data: Vec<u8>) -> Result<Vec<u8>, EncryptionErr> {
-    let cipher = CCP::new(&key.into());
-    let nonce_ref = Nonce::from_slice(&nonce);
-    let encryped = cipher
-        .encrypt(nonce_ref, data.as_slice())
-        .map_err(|e| EncryptionErr::EncryptionErr)?;
-    Ok(encryped)
+// The AEAD suites a register's data can be sealed with. The id goes into the vault
+// header as TAG_CIPHER and the nonce next to it, at the length the suite takes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CipherId {
+    // 96-bit nonces; every vault written before the suites could be chosen.
+    ChaCha20Poly1305 = 1,
+    // 192-bit nonces, random ones never repeat in practice. The default.
+    XChaCha20Poly1305 = 2,
+    // Nonce-misuse resistant: a repeated nonce only shows that the same data was
+    // sealed twice, it doesn't give the key stream away.
+    Aes256GcmSiv = 3,
+}
+
+pub const DEFAULT_CIPHER: CipherId = CipherId::XChaCha20Poly1305;
+const ALL: [CipherId; 3] = [
+    CipherId::XChaCha20Poly1305,
+    CipherId::Aes256GcmSiv,
+    CipherId::ChaCha20Poly1305,
+];
+
+impl CipherId {
+    pub fn from_u8(id: u8) -> Option<Self> {
+        ALL.into_iter().find(|cipher| *cipher as u8 == id)
+    }
+
+    // As written in CREATE REGISTER ... WITH CIPHER '<name>', case and dashes aside.
+    pub fn from_name(name: &str) -> Result<Self, CipherErr> {
+        let wanted = squash(name);
+        ALL.into_iter()
+            .find(|cipher| squash(cipher.name()) == wanted)
+            .ok_or_else(|| CipherErr::Unknown {
+                name: name.to_string(),
+                known: ALL.map(|cipher| cipher.name()).join(", "),
+            })
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            CipherId::ChaCha20Poly1305 => "chacha20-poly1305",
+            CipherId::XChaCha20Poly1305 => "xchacha20-poly1305",
+            CipherId::Aes256GcmSiv => "aes-256-gcm-siv",
+        }
+    }
+
+    pub fn nonce_len(&self) -> usize {
+        match self {
+            CipherId::XChaCha20Poly1305 => 24,
+            CipherId::ChaCha20Poly1305 | CipherId::Aes256GcmSiv => 12,
+        }
+    }
+
+    pub fn generate_nonce(&self) -> Vec<u8> {
+        let mut nonce = vec![0u8; self.nonce_len()];
+        rand::rngs::OsRng.fill_bytes(&mut nonce);
+        nonce
+    }
+
+    // `aad` is authenticated alongside the ciphertext but not encrypted; vaults use it
+    // to bind their header and register folder to the data.
+    pub fn seal(
+        &self,
+        key: &[u8; 32],
+        nonce: &[u8],
+        data: &[u8],
+        aad: &[u8],
+    ) -> Result<Vec<u8>, EncryptionErr> {
+        if nonce.len() != self.nonce_len() {
+            return Err(EncryptionErr::EncryptionErr);
+        }
+        let payload = Payload { msg: data, aad };
+        match self {
+            CipherId::ChaCha20Poly1305 => seal_with::<ChaCha20Poly1305>(key, nonce, payload),
+            CipherId::XChaCha20Poly1305 => seal_with::<XChaCha20Poly1305>(key, nonce, payload),
+            CipherId::Aes256GcmSiv => seal_with::<Aes256GcmSiv>(key, nonce, payload),
+        }
+        .map_err(|_| EncryptionErr::EncryptionErr)
+    }
+
+    // The plaintext is wiped once the caller drops it.
+    pub fn open(
+        &self,
+        key: &[u8; 32],
+        nonce: &[u8],
+        sealed: &[u8],
+        aad: &[u8],
+    ) -> Result<Zeroizing<Vec<u8>>, DecryptionErr> {
+        if nonce.len() != self.nonce_len() {
+            return Err(DecryptionErr::DecryptionErr);
+        }
+        let payload = Payload { msg: sealed, aad };
+        match self {
+            CipherId::ChaCha20Poly1305 => open_with::<ChaCha20Poly1305>(key, nonce, payload),
+            CipherId::XChaCha20Poly1305 => open_with::<XChaCha20Poly1305>(key, nonce, payload),
+            CipherId::Aes256GcmSiv => open_with::<Aes256GcmSiv>(key, nonce, payload),
+        }
+        .map(Zeroizing::new)
+        .map_err(|_| DecryptionErr::DecryptionErr)
+    }
+}
+
+impl std::fmt::Display for CipherId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+fn squash(name: &str) -> String {
+    name.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+// Callers have checked the nonce length, `from_slice` would panic otherwise.
+fn seal_with<C: KeyInit + Aead>(
+    key: &[u8; 32],
+    nonce: &[u8],
+    payload: Payload,
+) -> Result<Vec<u8>, chacha20poly1305::aead::Error> {
+    let cipher = C::new_from_slice(key).map_err(|_| chacha20poly1305::aead::Error)?;
+    cipher.encrypt(GenericArray::from_slice(nonce), payload)
+}
+
+fn open_with<C: KeyInit + Aead>(
+    key: &[u8; 32],
+    nonce: &[u8],
+    payload: Payload,
+) -> Result<Vec<u8>, chacha20poly1305::aead::Error> {
+    let cipher = C::new_from_slice(key).map_err(|_| chacha20poly1305::aead::Error)?;
+    cipher.decrypt(GenericArray::from_slice(nonce), payload)
+}
+
+// ChaCha20-Poly1305 with a 96-bit nonce, for everything that isn't register data:
+// key slots, identities, entry shares and legacy vaults.
+pub fn encrypt(
+    key: [u8; 32],
+    nonce: [u8; 12],
+    data: Vec<u8>,
+    aad: &[u8],
+) -> Result<Vec<u8>, EncryptionErr> {
+    let data = Zeroizing::new(data);
+    CipherId::ChaCha20Poly1305.seal(&key, &nonce, &data, aad)
 }
 
 pub fn decrypt(
     key: [u8; 32],
     nonce: [u8; 12],
     encrypted: Vec<u8>,
-) -> Result<Vec<u8>, DecryptionErr> {
-    let mut cipther = CCP::new(&key.into());
-    let nonce_ref = Nonce::from_slice(&nonce);
-    let decrypted = cipther
-        .decrypt(nonce_ref, encrypted.as_slice())
-        .map_err(|e| DecryptionErr::DecryptionErr)?;
-    Ok(decrypted)
+    aad: &[u8],
+) -> Result<Zeroizing<Vec<u8>>, DecryptionErr> {
+    CipherId::ChaCha20Poly1305.open(&key, &nonce, &encrypted, aad)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RFC 8439 section 2.8.2 and draft-arciszewski-xchacha-03 appendix A.3.1 share
+    // the key, associated data and plaintext.
+    const KEY: &str = "808182838485868788898a8b8c8d8e8f909192939495969798999a9b9c9d9e9f";
+    const AAD: &str = "50515253c0c1c2c3c4c5c6c7";
+    const PLAINTEXT: &[u8] = b"Ladies and Gentlemen of the class of '99: \
+        If I could offer you only one tip for the future, sunscreen would be it.";
+
+    struct Vector {
+        cipher: CipherId,
+        key: &'static str,
+        nonce: &'static str,
+        aad: &'static str,
+        plaintext: &'static [u8],
+        sealed: &'static str,
+    }
+
+    const VECTORS: &[Vector] = &[
+        Vector {
+            cipher: CipherId::ChaCha20Poly1305,
+            key: KEY,
+            nonce: "070000004041424344454647",
+            aad: AAD,
+            plaintext: PLAINTEXT,
+            sealed: "d31a8d34648e60db7b86afbc53ef7ec2a4aded51296e08fea9e2b5a736ee62d6\
+                     3dbea45e8ca9671282fafb69da92728b1a71de0a9e060b2905d6a5b67ecd3b36\
+                     92ddbd7f2d778b8c9803aee328091b58fab324e4fad675945585808b4831d7bc\
+                     3ff4def08e4b7a9de576d26586cec64b6116\
+                     1ae10b594f09e26a7e902ecbd0600691",
+        },
+        Vector {
+            cipher: CipherId::XChaCha20Poly1305,
+            key: KEY,
+            nonce: "404142434445464748494a4b4c4d4e4f5051525354555657",
+            aad: AAD,
+            plaintext: PLAINTEXT,
+            sealed: "bd6d179d3e83d43b9576579493c0e939572a1700252bfaccbed2902c21396cbb\
+                     731c7f1b0b4aa6440bf3a82f4eda7e39ae64c6708c54c216cb96b72e1213b452\
+                     2f8c9ba40db5d945b11b69b982c1bb9e3f3fac2bc369488f76b2383565d3fff9\
+                     21f9664c97637da9768812f615c68b13b52e\
+                     c0875924c1c7987947deafd8780acf49",
+        },
+        // RFC 8452 appendix C.2.
+        Vector {
+            cipher: CipherId::Aes256GcmSiv,
+            key: "0100000000000000000000000000000000000000000000000000000000000000",
+            nonce: "030000000000000000000000",
+            aad: "",
+            plaintext: b"",
+            sealed: "07f5f4169bbf55a8400cd47ea6fd400f",
+        },
+        Vector {
+            cipher: CipherId::Aes256GcmSiv,
+            key: "0100000000000000000000000000000000000000000000000000000000000000",
+            nonce: "030000000000000000000000",
+            aad: "01",
+            plaintext: b"\x02\x00\x00\x00\x00\x00\x00\x00",
+            sealed: "1de22967237a813291213f267e3b452f02d01ae33e4ec854",
+        },
+    ];
+
+    fn unhex(s: &str) -> Vec<u8> {
+        hex::decode(s.split_whitespace().collect::<String>()).unwrap()
+    }
+
+    #[test]
+    fn test_every_suite_matches_its_vectors() {
+        for v in VECTORS {
+            let key: [u8; 32] = unhex(v.key).try_into().unwrap();
+            let (nonce, aad, sealed) = (unhex(v.nonce), unhex(v.aad), unhex(v.sealed));
+            assert_eq!(
+                v.cipher.seal(&key, &nonce, v.plaintext, &aad).unwrap(),
+                sealed,
+                "{}",
+                v.cipher
+            );
+            assert_eq!(
+                *v.cipher.open(&key, &nonce, &sealed, &aad).unwrap(),
+                v.plaintext
+            );
+
+            let mut altered = sealed.clone();
+            altered[0] ^= 0x01;
+            assert!(v.cipher.open(&key, &nonce, &altered, &aad).is_err());
+            assert!(v.cipher.open(&key, &nonce, &sealed, b"other").is_err());
+        }
+    }
+
+    #[test]
+    fn test_suites_by_id_and_name() {
+        for cipher in ALL {
+            assert_eq!(CipherId::from_u8(cipher as u8), Some(cipher));
+            assert_eq!(CipherId::from_name(cipher.name()).unwrap(), cipher);
+            assert_eq!(cipher.generate_nonce().len(), cipher.nonce_len());
+            // A nonce of another suite's length is refused rather than cut or padded.
+            let key = [7u8; 32];
+            assert!(cipher.seal(&key, &[0u8; 16], b"data", &[]).is_err());
+        }
+        assert_eq!(
+            CipherId::from_name("AES256_GCM_SIV").unwrap(),
+            CipherId::Aes256GcmSiv
+        );
+        assert!(CipherId::from_name("rot13").is_err());
+        assert_eq!(CipherId::from_u8(0), None);
+    }
 }
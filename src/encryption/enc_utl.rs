@@ -1,5 +1,7 @@
 // encryption utilities
 
+use sha2::{Digest, Sha256};
+
 #[derive(Debug)]
 pub enum KdfMode {
     // Encryption Mode (or read only mode): We typically require it to be
@@ -14,3 +16,11 @@ pub enum KdfMode {
     //
     DecryM,
 }
+
+// Four hex digits appended to strings that are copied by hand, such as shares and
+// public keys, so a typo is reported instead of silently giving a different key.
+// Case-insensitive, and no protection against deliberate changes.
+pub fn typo_check(body: &str) -> String {
+    let digest = Sha256::digest(body.to_lowercase().as_bytes());
+    hex::encode(&digest[..2])
+}
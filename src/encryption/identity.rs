@@ -0,0 +1,277 @@
+use crate::encryption::aead;
+use crate::encryption::enc_utl::typo_check;
+use crate::encryption::kdf::{KdfParams, derive_slow_key};
+use crate::error::{HomeDirErr, IdentityErr};
+use crate::storage::init::ROOT_REG;
+use sha2::{Digest, Sha256};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::{Zeroize, Zeroizing};
+
+// A personal X25519 key pair that registers can be granted to, see GRANT ACCESS. The
+// secret half is kept encrypted under a passphrase:
+//
+//   [magic 4][version 2][public key 32][kdf 12][salt 16][nonce 12][secret key 32 + tag 16]
+//
+// Everything in front of the secret key is authenticated with it. The public key is
+// in the clear so it can be shown without the passphrase.
+pub const IDENTITY_N: &str = "identity.key";
+const MAGIC: [u8; 4] = *b"PWID";
+const VERSION: u16 = 1;
+const HEADER_LEN: usize = 4 + 2 + 32 + 12 + 16 + 12;
+const FILE_LEN: usize = HEADER_LEN + 32 + 16;
+
+const PUBLIC_PREFIX: &str = "pwmnpk1";
+const AGREE_DOMAIN: &[u8] = b"pwmn-recipient-v1";
+
+pub struct Identity {
+    secret: StaticSecret,
+}
+
+impl Identity {
+    pub fn generate() -> Self {
+        Self {
+            secret: StaticSecret::random_from_rng(rand::rngs::OsRng),
+        }
+    }
+
+    pub fn public(&self) -> [u8; 32] {
+        PublicKey::from(&self.secret).to_bytes()
+    }
+
+    // The secret shared with whoever wrapped a key to us using `ephemeral`.
+    pub fn agree(&self, ephemeral: &[u8; 32]) -> Result<[u8; 32], IdentityErr> {
+        let shared = self.secret.diffie_hellman(&PublicKey::from(*ephemeral));
+        shared_key(shared, ephemeral, &self.public())
+    }
+
+    pub fn seal(&self, passphrase: &str, kdf: &KdfParams) -> Result<Vec<u8>, IdentityErr> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        bytes.extend_from_slice(&self.public());
+        for cost in [kdf.m_cost, kdf.t_cost, kdf.p_cost] {
+            bytes.extend_from_slice(&cost.to_le_bytes());
+        }
+        let salt: [u8; 16] = rand::random();
+        let nonce: [u8; 12] = rand::random();
+        bytes.extend_from_slice(&salt);
+        bytes.extend_from_slice(&nonce);
+
+        let key = derive_slow_key(passphrase, &salt, kdf);
+        let sealed = aead::encrypt(*key, nonce, self.secret.to_bytes().to_vec(), &bytes)
+            .map_err(|_| IdentityErr::Malformed)?;
+        bytes.extend_from_slice(&sealed);
+        Ok(bytes)
+    }
+
+    pub fn open(bytes: &[u8], passphrase: &str) -> Result<Self, IdentityErr> {
+        let public = public_of(bytes)?;
+        let field = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+        let kdf = KdfParams {
+            m_cost: field(38),
+            t_cost: field(42),
+            p_cost: field(46),
+        };
+        if !kdf.is_valid() {
+            return Err(IdentityErr::Malformed);
+        }
+        let salt = &bytes[50..66];
+        let nonce: [u8; 12] = bytes[66..HEADER_LEN].try_into().unwrap();
+
+        let key = derive_slow_key(passphrase, salt, &kdf);
+        let secret = Zeroizing::new(
+            aead::decrypt(
+                *key,
+                nonce,
+                bytes[HEADER_LEN..].to_vec(),
+                &bytes[..HEADER_LEN],
+            )
+            .map_err(|_| IdentityErr::WrongPassphrase)?,
+        );
+        let secret_key: Zeroizing<[u8; 32]> = Zeroizing::new(
+            secret
+                .as_slice()
+                .try_into()
+                .map_err(|_| IdentityErr::Malformed)?,
+        );
+        let identity = Self {
+            secret: StaticSecret::from(*secret_key),
+        };
+        if identity.public() != public {
+            return Err(IdentityErr::Malformed);
+        }
+        Ok(identity)
+    }
+}
+
+// Read without the passphrase.
+pub fn public_of(bytes: &[u8]) -> Result<[u8; 32], IdentityErr> {
+    if bytes.len() != FILE_LEN || bytes[..4] != MAGIC || bytes[4..6] != VERSION.to_le_bytes() {
+        return Err(IdentityErr::Malformed);
+    }
+    Ok(bytes[6..38].try_into().unwrap())
+}
+
+// The identity is personal, it lives under the home folder even when --root or
+// PWMN_HOME put the registers on a shared drive.
+pub fn path() -> Result<PathBuf, HomeDirErr> {
+    let home = dirs_next::home_dir().ok_or(HomeDirErr::InvalidHomeDir)?;
+    Ok(home.join(ROOT_REG).join(IDENTITY_N))
+}
+
+pub fn read() -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let p = path()?;
+    match fs::read(&p) {
+        Ok(bytes) => Ok(bytes),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            Err(Box::new(IdentityErr::NotFound {
+                path: p.display().to_string(),
+            }))
+        }
+        Err(e) => Err(Box::new(e)),
+    }
+}
+
+// Never overwrites an identity, registers may have been granted to it. Checked
+// before a passphrase is asked for, and again when writing.
+pub fn ensure_new() -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let p = path()?;
+    if let Ok(existing) = fs::read(&p) {
+        return Err(Box::new(IdentityErr::AlreadyExists {
+            path: p.display().to_string(),
+            public: public_of(&existing)
+                .map(|public| encode_public(&public))
+                .unwrap_or_else(|_| "unreadable".to_string()),
+        }));
+    }
+    Ok(p)
+}
+
+pub fn write(bytes: &[u8]) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let p = ensure_new()?;
+    fs::create_dir_all(p.parent().unwrap())?;
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&p)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    Ok(p)
+}
+
+// pwmnpk1-<hex>-<check>, how members hand out their public key.
+pub fn encode_public(public: &[u8; 32]) -> String {
+    let body = format!("{}-{}", PUBLIC_PREFIX, hex::encode(public));
+    let check = typo_check(&body);
+    format!("{}-{}", body, check)
+}
+
+pub fn decode_public(s: &str) -> Result<[u8; 32], IdentityErr> {
+    let invalid = |reason: &str| IdentityErr::InvalidPublicKey {
+        reason: reason.to_string(),
+    };
+    let s = s.trim();
+    let (body, check) = s
+        .rsplit_once('-')
+        .ok_or_else(|| invalid("not a public key"))?;
+    let Some((prefix, key)) = body.split_once('-') else {
+        return Err(invalid("not a public key"));
+    };
+    if !prefix.eq_ignore_ascii_case(PUBLIC_PREFIX) {
+        return Err(invalid("not a public key"));
+    }
+    if !typo_check(body).eq_ignore_ascii_case(check) {
+        return Err(invalid(
+            "it was mistyped, the check at the end doesn't match",
+        ));
+    }
+    hex::decode(key)
+        .ok()
+        .and_then(|key| key.try_into().ok())
+        .ok_or_else(|| invalid("bad key"))
+}
+
+// A one-off key pair agrees on a secret with `member`: returns the ephemeral public
+// key, which goes along with whatever the secret protects, and the secret.
+pub fn agree_with(member: &[u8; 32]) -> Result<([u8; 32], [u8; 32]), IdentityErr> {
+    let ephemeral = StaticSecret::random_from_rng(rand::rngs::OsRng);
+    let ephemeral_public = PublicKey::from(&ephemeral).to_bytes();
+    let shared = ephemeral.diffie_hellman(&PublicKey::from(*member));
+    Ok((
+        ephemeral_public,
+        shared_key(shared, &ephemeral_public, member)?,
+    ))
+}
+
+// Hashes the X25519 output together with both public keys. A low-order public key
+// would make the output predictable, it is refused.
+fn shared_key(
+    shared: x25519_dalek::SharedSecret,
+    ephemeral: &[u8; 32],
+    member: &[u8; 32],
+) -> Result<[u8; 32], IdentityErr> {
+    if !shared.was_contributory() {
+        return Err(IdentityErr::InvalidPublicKey {
+            reason: "a low-order point".to_string(),
+        });
+    }
+    Ok(Sha256::new()
+        .chain_update(AGREE_DOMAIN)
+        .chain_update(shared.as_bytes())
+        .chain_update(ephemeral)
+        .chain_update(member)
+        .finalize()
+        .into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_KDF: KdfParams = KdfParams {
+        m_cost: 64,
+        t_cost: 1,
+        p_cost: 1,
+    };
+
+    #[test]
+    fn test_identity_roundtrip_and_agreement() {
+        let identity = Identity::generate();
+        let sealed = identity.seal("passphrase", &TEST_KDF).unwrap();
+        assert_eq!(public_of(&sealed).unwrap(), identity.public());
+
+        let opened = Identity::open(&sealed, "passphrase").unwrap();
+        assert_eq!(opened.public(), identity.public());
+        assert!(matches!(
+            Identity::open(&sealed, "wrong"),
+            Err(IdentityErr::WrongPassphrase)
+        ));
+        // The public key in the clear is authenticated with the secret key.
+        let mut swapped = sealed.clone();
+        swapped[6] ^= 0x01;
+        assert!(Identity::open(&swapped, "passphrase").is_err());
+
+        let (ephemeral, shared) = agree_with(&identity.public()).unwrap();
+        assert_eq!(opened.agree(&ephemeral).unwrap(), shared);
+        assert_ne!(Identity::generate().agree(&ephemeral).unwrap(), shared);
+        assert!(agree_with(&[0u8; 32]).is_err());
+    }
+
+    #[test]
+    fn test_public_key_encoding() {
+        let public = Identity::generate().public();
+        let encoded = encode_public(&public);
+        assert!(encoded.starts_with("pwmnpk1-"));
+        assert_eq!(decode_public(&encoded).unwrap(), public);
+        assert_eq!(decode_public(&encoded.to_uppercase()).unwrap(), public);
+
+        let mut typo = encoded.clone().into_bytes();
+        typo[8] = if typo[8] == b'a' { b'b' } else { b'a' };
+        assert!(decode_public(&String::from_utf8(typo).unwrap()).is_err());
+        assert!(decode_public("pwmnpk1-00-0000").is_err());
+    }
+}
@@ -3,19 +3,74 @@ use crate::session;
 use argon2::password_hash::rand_core::{CryptoRng, OsRng, RngCore};
 use argon2::{Algorithm, Argon2, Params, Version};
 use chacha20poly1305::aead::Aead;
+use std::time::{Duration, Instant};
+use zeroize::Zeroizing;
 const TIME_COST: u32 = 2;
 const PARALLELISM: u32 = 1;
 const MAGIC: &[u8; 4] = b"PWMN";
 const M_COST: u32 = 0x10000;
+// Unlock time aimed for by REKEY when no explicit target is given.
+pub const DEFAULT_KDF_TARGET_MS: u64 = 1000;
 
-pub fn derive_slow_key(str: &str, salt: &[u8]) -> [u8; 32] {
-    let mut m_cost: u32;
-    let param = Params::new(M_COST, TIME_COST, PARALLELISM, None).unwrap();
+// Upper bounds for parameters read from a vault header, a tampered header must not
+// be able to make us allocate or spin for an unbounded amount of time.
+pub const MAX_M_COST: u32 = 0x100000; // 1 GiB
+pub const MAX_TIME_COST: u32 = 64;
+pub const MAX_PARALLELISM: u32 = 16;
+
+// Argon2id cost parameters used to derive a register key; they are stored in the
+// vault header so they can change without locking anyone out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KdfParams {
+    pub m_cost: u32,
+    pub t_cost: u32,
+    pub p_cost: u32,
+}
+
+impl Default for KdfParams {
+    // The parameters every vault was created with before they were recorded.
+    fn default() -> Self {
+        Self {
+            m_cost: M_COST,
+            t_cost: TIME_COST,
+            p_cost: PARALLELISM,
+        }
+    }
+}
+
+impl KdfParams {
+    pub fn is_valid(&self) -> bool {
+        (1..=MAX_PARALLELISM).contains(&self.p_cost)
+            && (1..=MAX_TIME_COST).contains(&self.t_cost)
+            && (8 * self.p_cost..=MAX_M_COST).contains(&self.m_cost)
+    }
+
+    // Rough amount of work, used to compare two parameter sets.
+    pub fn cost(&self) -> u64 {
+        self.m_cost as u64 * self.t_cost as u64
+    }
+}
+
+impl std::fmt::Display for KdfParams {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Argon2id {} MiB, {} pass(es), {} lane(s)",
+            self.m_cost / 1024,
+            self.t_cost,
+            self.p_cost
+        )
+    }
+}
+
+// Wiped when dropped, like every key derived from a password.
+pub fn derive_slow_key(str: &str, salt: &[u8], kdf: &KdfParams) -> Zeroizing<[u8; 32]> {
+    let param = Params::new(kdf.m_cost, kdf.t_cost, kdf.p_cost, None).unwrap();
 
     let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, param);
-    let mut output = [0u8; 32];
+    let mut output = Zeroizing::new([0u8; 32]);
     argon2
-        .hash_password_into(&str.as_bytes(), salt, &mut output)
+        .hash_password_into(&str.as_bytes(), salt, output.as_mut())
         .unwrap();
     output
 }
@@ -31,3 +86,96 @@ pub fn derive_fast_key(str: &str, salt: &[u8]) -> [u8; 32] {
         .unwrap();
     output
 }
+
+// Benchmarks Argon2id on this machine and returns parameters whose derivation takes
+// roughly `target`. Memory is raised first (it is what makes GPU guessing expensive),
+// then passes are added to fill the remaining time. The result is never weaker than
+// the defaults.
+pub fn calibrate(target: Duration) -> KdfParams {
+    let mut params = KdfParams {
+        m_cost: M_COST,
+        t_cost: 1,
+        p_cost: PARALLELISM,
+    };
+    let mut elapsed = bench(&params);
+    while elapsed * 4 < target && params.m_cost * 2 <= MAX_M_COST {
+        params.m_cost *= 2;
+        elapsed = bench(&params);
+    }
+
+    let passes = target.as_secs_f64() / elapsed.as_secs_f64().max(f64::EPSILON);
+    params.t_cost = (passes.floor() as u32).clamp(1, MAX_TIME_COST);
+
+    if params.cost() < KdfParams::default().cost() {
+        return KdfParams::default();
+    }
+    params
+}
+
+fn bench(params: &KdfParams) -> Duration {
+    let start = Instant::now();
+    derive_slow_key("calibration", &[0u8; 16], params);
+    start.elapsed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_params_outside_the_bounds_are_invalid() {
+        assert!(KdfParams::default().is_valid());
+        let at_limits = KdfParams {
+            m_cost: MAX_M_COST,
+            t_cost: MAX_TIME_COST,
+            p_cost: MAX_PARALLELISM,
+        };
+        assert!(at_limits.is_valid());
+
+        // What a hostile header could ask for.
+        let invalid = [
+            KdfParams {
+                m_cost: MAX_M_COST + 1,
+                ..at_limits
+            },
+            KdfParams {
+                t_cost: MAX_TIME_COST + 1,
+                ..at_limits
+            },
+            KdfParams {
+                p_cost: MAX_PARALLELISM + 1,
+                ..at_limits
+            },
+            KdfParams {
+                t_cost: 0,
+                ..at_limits
+            },
+            KdfParams {
+                p_cost: 0,
+                ..at_limits
+            },
+            KdfParams {
+                m_cost: u32::MAX,
+                t_cost: u32::MAX,
+                p_cost: u32::MAX,
+            },
+            // Argon2 needs 8 KiB per lane.
+            KdfParams {
+                m_cost: 8 * 4 - 1,
+                t_cost: 1,
+                p_cost: 4,
+            },
+        ];
+        for params in invalid {
+            assert!(!params.is_valid(), "{:?}", params);
+        }
+    }
+
+    #[test]
+    fn test_calibration_is_never_weaker_than_the_defaults() {
+        // One benchmark run at the default memory, then there is no time left to fill.
+        let params = calibrate(Duration::ZERO);
+        assert_eq!(params, KdfParams::default());
+        assert!(params.is_valid());
+    }
+}
@@ -0,0 +1,80 @@
+use crate::error::KeyfileErr;
+use sha2::{Digest, Sha256};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+const KEYFILE_DOMAIN: &[u8] = b"pwmn-keyfile-v1";
+// Size of the keyfiles written by GENERATE KEYFILE; any non-empty file can be used.
+pub const KEYFILE_LEN: usize = 64;
+
+type DynError = Box<dyn std::error::Error>;
+
+// Writes a new random keyfile, readable by its owner only. An existing file is never
+// overwritten, it may be the keyfile of a register.
+pub fn generate(p: &Path) -> Result<(), DynError> {
+    let mut bytes = [0u8; KEYFILE_LEN];
+    rand::RngCore::fill_bytes(&mut rand::rngs::OsRng, &mut bytes);
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(p)
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::AlreadyExists => Box::new(KeyfileErr::AlreadyExists {
+                path: p.display().to_string(),
+            }) as DynError,
+            _ => Box::new(e),
+        })?;
+    file.write_all(&bytes)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+// Only the hash of a keyfile takes part in the key, so its size doesn't matter.
+pub fn hash(p: &Path) -> Result<[u8; 32], KeyfileErr> {
+    let unreadable = |reason: String| KeyfileErr::Unreadable {
+        path: p.display().to_string(),
+        reason,
+    };
+    let bytes = fs::read(p).map_err(|e| unreadable(e.to_string()))?;
+    if bytes.is_empty() {
+        return Err(unreadable("the file is empty".to_string()));
+    }
+    Ok(Sha256::digest(&bytes).into())
+}
+
+// Binds the password-derived key to the keyfile; neither alone gives the register key.
+pub fn combine(pwd_key: [u8; 32], keyfile_hash: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(KEYFILE_DOMAIN);
+    hasher.update(pwd_key);
+    hasher.update(keyfile_hash);
+    hasher.finalize().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generated_keyfile_changes_the_key() {
+        let p = std::env::temp_dir().join(format!("pwmn-keyfile-{}", uuid::Uuid::new_v4()));
+        generate(&p).unwrap();
+        assert!(generate(&p).is_err());
+        assert_eq!(fs::read(&p).unwrap().len(), KEYFILE_LEN);
+
+        let pwd_key = [3u8; 32];
+        let first = combine(pwd_key, &hash(&p).unwrap());
+        assert_ne!(first, pwd_key);
+        assert_eq!(first, combine(pwd_key, &hash(&p).unwrap()));
+
+        fs::write(&p, b"another keyfile").unwrap();
+        assert_ne!(first, combine(pwd_key, &hash(&p).unwrap()));
+        fs::write(&p, b"").unwrap();
+        assert!(hash(&p).is_err());
+
+        fs::remove_file(&p).unwrap();
+    }
+}
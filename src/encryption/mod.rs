@@ -1,3 +1,8 @@
 pub mod aead;
 pub mod enc_utl;
+pub mod identity;
 pub mod kdf;
+pub mod keyfile;
+pub mod protected;
+pub mod recovery;
+pub mod shamir;
@@ -0,0 +1,129 @@
+use crate::encryption::aead::CipherId;
+use bincode::{Decode, Encode};
+use secrecy::{ExposeSecret, SecretString};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::sync::Mutex;
+use zeroize::{Zeroize, Zeroizing};
+
+// The entry passwords of a connected register stay sealed in memory under a key that
+// only lives as long as the session, and are opened only for the moment they are
+// shown, copied or written back to disk. A dump of the heap then holds ciphertext,
+// the way KeePass protects its in-memory strings.
+const CIPHER: CipherId = CipherId::XChaCha20Poly1305;
+
+// A static, so it never moves and `harden` can pin its page to RAM.
+pub static SESSION_KEY: Mutex<Option<[u8; 32]>> = Mutex::new(None);
+
+// Drawn on first use and again after every rotation, it never leaves the process.
+fn session_key() -> Zeroizing<[u8; 32]> {
+    let mut key = SESSION_KEY.lock().unwrap_or_else(|e| e.into_inner());
+    Zeroizing::new(*key.get_or_insert_with(rand::random))
+}
+
+// Called once the register has left memory (DISCONNECT, LOCK). Copies of its sealed
+// passwords that linger on the heap can't be opened under the next key.
+pub fn rotate_session_key() {
+    SESSION_KEY
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .zeroize();
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct ProtectedStr {
+    nonce: Vec<u8>,
+    sealed: Vec<u8>,
+}
+
+impl ProtectedStr {
+    // Takes the plaintext by value so it is wiped as soon as it is sealed.
+    pub fn new(text: String) -> Self {
+        let text = SecretString::new(text);
+        let nonce = CIPHER.generate_nonce();
+        // Only a nonce of the wrong length makes sealing fail.
+        let sealed = CIPHER
+            .seal(&session_key(), &nonce, text.expose_secret().as_bytes(), &[])
+            .unwrap();
+        Self { nonce, sealed }
+    }
+
+    // The plaintext, wiped again when the caller drops it.
+    pub fn reveal(&self) -> SecretString {
+        let mut opened = CIPHER
+            .open(&session_key(), &self.nonce, &self.sealed, &[])
+            .unwrap();
+        SecretString::new(String::from_utf8(std::mem::take(&mut *opened)).unwrap())
+    }
+}
+
+impl std::fmt::Debug for ProtectedStr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("[redacted]")
+    }
+}
+
+// On disk a protected string is the plain string it replaces; the register file has
+// its own encryption.
+impl Encode for ProtectedStr {
+    fn encode<E: bincode::enc::Encoder>(
+        &self,
+        encoder: &mut E,
+    ) -> Result<(), bincode::error::EncodeError> {
+        self.reveal().expose_secret().encode(encoder)
+    }
+}
+
+impl<Context> Decode<Context> for ProtectedStr {
+    fn decode<D: bincode::de::Decoder<Context = Context>>(
+        decoder: &mut D,
+    ) -> Result<Self, bincode::error::DecodeError> {
+        String::decode(decoder).map(ProtectedStr::new)
+    }
+}
+bincode::impl_borrow_decode!(ProtectedStr);
+
+impl Serialize for ProtectedStr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.reveal().expose_secret())
+    }
+}
+
+impl<'de> Deserialize<'de> for ProtectedStr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(ProtectedStr::new)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_protected_str_is_sealed_in_memory() {
+        let protected = ProtectedStr::new("hunter22".to_string());
+        assert!(
+            !protected
+                .sealed
+                .windows(8)
+                .any(|window| window == b"hunter22")
+        );
+        assert_eq!(protected.reveal().expose_secret(), "hunter22");
+        assert_eq!(format!("{:?}", protected), "[redacted]");
+
+        // The same text sealed twice doesn't look the same.
+        assert_ne!(protected, ProtectedStr::new("hunter22".to_string()));
+    }
+
+    #[test]
+    fn test_protected_str_is_encoded_as_a_plain_string() {
+        // Registers written before passwords were protected decode unchanged.
+        let config = bincode::config::standard();
+        let protected = ProtectedStr::new("hunter22".to_string());
+        let plain = bincode::encode_to_vec("hunter22", config).unwrap();
+        assert_eq!(bincode::encode_to_vec(&protected, config).unwrap(), plain);
+
+        let (decoded, _): (ProtectedStr, usize) =
+            bincode::decode_from_slice(&plain, config).unwrap();
+        assert_eq!(decoded.reveal().expose_secret(), "hunter22");
+    }
+}
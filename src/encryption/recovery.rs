@@ -0,0 +1,84 @@
+use crate::error::RecoveryErr;
+use bip39::{Language, Mnemonic};
+use rand::RngCore;
+use zeroize::Zeroize;
+
+// 256 random bits, written down as words from the BIP-39 English list. The last
+// word carries a checksum, so a mistyped key is caught before any unlock attempt.
+pub const RECOVERY_WORDS: usize = 24;
+
+pub fn generate() -> String {
+    let mut entropy = [0u8; 32];
+    rand::rngs::OsRng.fill_bytes(&mut entropy);
+    let mnemonic = Mnemonic::from_entropy_in(Language::English, &entropy)
+        .expect("32 bytes is a valid BIP-39 entropy length");
+    entropy.zeroize();
+    mnemonic.to_string()
+}
+
+// The form a recovery key is derived from, whatever case and spacing it was typed in.
+pub fn normalize(input: &str) -> Result<String, RecoveryErr> {
+    let mut words: Vec<String> = input.split_whitespace().map(str::to_lowercase).collect();
+    if words.len() != RECOVERY_WORDS {
+        return Err(RecoveryErr::Invalid {
+            reason: format!("expected {} words, got {}", RECOVERY_WORDS, words.len()),
+        });
+    }
+    let mut joined = words.join(" ");
+    words.zeroize();
+    let parsed = Mnemonic::parse_in_normalized(Language::English, &joined);
+    joined.zeroize();
+    parsed
+        .map(|mnemonic| mnemonic.to_string())
+        .map_err(|e| RecoveryErr::Invalid {
+            reason: e.to_string(),
+        })
+}
+
+// Numbered groups of four, the way the key is shown and printed.
+pub fn lines(phrase: &str) -> Vec<String> {
+    let words: Vec<&str> = phrase.split(' ').collect();
+    words
+        .chunks(4)
+        .enumerate()
+        .map(|(row, chunk)| {
+            chunk
+                .iter()
+                .enumerate()
+                .map(|(i, word)| format!("{:>2}. {:<9}", row * 4 + i + 1, word))
+                .collect::<Vec<_>>()
+                .join(" ")
+                .trim_end()
+                .to_string()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recovery_key_survives_being_retyped() {
+        let phrase = generate();
+        assert_eq!(phrase.split(' ').count(), RECOVERY_WORDS);
+        assert_ne!(phrase, generate());
+
+        let retyped = format!("  {}\n", phrase.to_uppercase().replace(' ', "   "));
+        assert_eq!(normalize(&retyped).unwrap(), phrase);
+
+        let numbered = lines(&phrase);
+        assert_eq!(numbered.len(), RECOVERY_WORDS / 4);
+        assert!(numbered[5].starts_with("21. "));
+
+        // A word short, a word not on the list, or a checksum that doesn't match.
+        let mut words: Vec<&str> = phrase.split(' ').collect();
+        words.pop();
+        assert!(normalize(&words.join(" ")).is_err());
+        words.push("notaword");
+        assert!(normalize(&words.join(" ")).is_err());
+        let zeros = format!("{} art", vec!["abandon"; 23].join(" "));
+        assert!(normalize(&zeros).is_ok());
+        assert!(normalize(&vec!["abandon"; 24].join(" ")).is_err());
+    }
+}
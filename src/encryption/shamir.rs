@@ -0,0 +1,292 @@
+use crate::encryption::enc_utl::typo_check;
+use crate::error::ShareErr;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use zeroize::Zeroize;
+
+// Shamir's secret sharing over GF(256), byte by byte: every byte of the secret is the
+// constant term of its own random polynomial of degree threshold - 1, and a share is
+// that polynomial evaluated at the share's x. Any `threshold` shares interpolate the
+// secret back, fewer tell nothing about it.
+//
+// A check over the secret is shared along with it, so shares that were altered or
+// come from different splits are told apart from a valid result.
+
+const SHARE_PREFIX: &str = "PWMN1";
+const CHECK_DOMAIN: &[u8] = b"pwmn-shares-v1";
+const CHECK_LEN: usize = 16;
+pub const MAX_SHARES: usize = 255;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Share {
+    // Identifies the split the share belongs to.
+    pub set: [u8; 4],
+    pub threshold: u8,
+    pub x: u8,
+    pub y: Vec<u8>,
+}
+
+impl Drop for Share {
+    fn drop(&mut self) {
+        self.y.zeroize();
+    }
+}
+
+impl Share {
+    // PWMN1-<set>-<threshold>-<x>-<y>-<check>, hex apart from the two numbers. The
+    // trailing check only catches typos, tampering is caught by `combine`.
+    pub fn encode(&self) -> String {
+        let body = format!(
+            "{}-{}-{}-{}-{}",
+            SHARE_PREFIX,
+            hex::encode(self.set),
+            self.threshold,
+            self.x,
+            hex::encode(&self.y)
+        );
+        let check = typo_check(&body);
+        format!("{}-{}", body, check)
+    }
+
+    pub fn decode(s: &str) -> Result<Share, ShareErr> {
+        let malformed = |reason: &str| ShareErr::Malformed {
+            reason: reason.to_string(),
+        };
+        let s = s.trim();
+        let (body, check) = s.rsplit_once('-').ok_or_else(|| malformed("not a share"))?;
+        let parts: Vec<&str> = body.split('-').collect();
+        if parts.len() != 5 || !parts[0].eq_ignore_ascii_case(SHARE_PREFIX) {
+            return Err(malformed("not a share"));
+        }
+        if !typo_check(body).eq_ignore_ascii_case(check) {
+            return Err(malformed(
+                "it was mistyped, the check at the end doesn't match",
+            ));
+        }
+        let set = hex::decode(parts[1])
+            .ok()
+            .and_then(|set| <[u8; 4]>::try_from(set).ok())
+            .ok_or_else(|| malformed("bad set id"))?;
+        let threshold: u8 = parts[2].parse().map_err(|_| malformed("bad threshold"))?;
+        let x: u8 = parts[3]
+            .parse()
+            .map_err(|_| malformed("bad share number"))?;
+        let y = hex::decode(parts[4]).map_err(|_| malformed("bad share value"))?;
+        if threshold < 2 || x == 0 || y.len() <= CHECK_LEN {
+            return Err(malformed("out of range"));
+        }
+        Ok(Share {
+            set,
+            threshold,
+            x,
+            y,
+        })
+    }
+}
+
+fn check(secret: &[u8]) -> [u8; CHECK_LEN] {
+    let digest = Sha256::new()
+        .chain_update(CHECK_DOMAIN)
+        .chain_update(secret)
+        .finalize();
+    let mut check = [0u8; CHECK_LEN];
+    check.copy_from_slice(&digest[..CHECK_LEN]);
+    check
+}
+
+// Splits `secret` into `shares` shares, any `threshold` of which rebuild it.
+pub fn split(
+    secret: &[u8],
+    shares: usize,
+    threshold: usize,
+    set: [u8; 4],
+) -> Result<Vec<Share>, ShareErr> {
+    if threshold < 2 || threshold > shares || shares > MAX_SHARES {
+        return Err(ShareErr::InvalidSplit { shares, threshold });
+    }
+    let mut payload = secret.to_vec();
+    payload.extend_from_slice(&check(secret));
+
+    let mut out: Vec<Share> = (1..=shares)
+        .map(|x| Share {
+            set,
+            threshold: threshold as u8,
+            x: x as u8,
+            y: Vec::with_capacity(payload.len()),
+        })
+        .collect();
+    let mut coefficients = vec![0u8; threshold];
+    for &byte in &payload {
+        coefficients[0] = byte;
+        rand::rngs::OsRng.fill_bytes(&mut coefficients[1..]);
+        for share in out.iter_mut() {
+            share.y.push(evaluate(&coefficients, share.x));
+        }
+    }
+    coefficients.zeroize();
+    payload.zeroize();
+    Ok(out)
+}
+
+// Rebuilds the secret from at least `threshold` shares of the same split.
+pub fn combine(shares: &[Share]) -> Result<Vec<u8>, ShareErr> {
+    let first = shares.first().ok_or(ShareErr::TooFew { needed: 2 })?;
+    if shares.len() < first.threshold as usize {
+        return Err(ShareErr::TooFew {
+            needed: first.threshold as usize,
+        });
+    }
+    for (i, share) in shares.iter().enumerate() {
+        if share.set != first.set
+            || share.threshold != first.threshold
+            || share.y.len() != first.y.len()
+        {
+            return Err(ShareErr::Mismatch);
+        }
+        if shares[..i].iter().any(|other| other.x == share.x) {
+            return Err(ShareErr::Duplicate { x: share.x });
+        }
+    }
+
+    // Lagrange interpolation at x = 0; subtraction is xor in GF(256).
+    let mut payload = vec![0u8; first.y.len()];
+    for (i, share) in shares.iter().enumerate() {
+        let mut basis = 1u8;
+        for (j, other) in shares.iter().enumerate() {
+            if i != j {
+                basis = mul(basis, mul(other.x, inverse(other.x ^ share.x)));
+            }
+        }
+        for (byte, &y) in payload.iter_mut().zip(&share.y) {
+            *byte ^= mul(basis, y);
+        }
+    }
+
+    let shared_check = payload.split_off(payload.len() - CHECK_LEN);
+    if check(&payload) != shared_check[..] {
+        payload.zeroize();
+        return Err(ShareErr::Tampered);
+    }
+    Ok(payload)
+}
+
+// Horner's rule, highest coefficient first.
+fn evaluate(coefficients: &[u8], x: u8) -> u8 {
+    coefficients
+        .iter()
+        .rev()
+        .fold(0u8, |acc, &c| mul(acc, x) ^ c)
+}
+
+// Multiplication modulo x^8 + x^4 + x^3 + x + 1, without branches or tables that
+// depend on the secret.
+fn mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0u8;
+    for _ in 0..8 {
+        product ^= a & (b & 1).wrapping_neg();
+        let carry = (a >> 7).wrapping_neg();
+        a = (a << 1) ^ (carry & 0x1b);
+        b >>= 1;
+    }
+    product
+}
+
+// a^254, the inverse of any non-zero a.
+fn inverse(a: u8) -> u8 {
+    let mut result = 1u8;
+    let mut base = a;
+    let mut exponent = 254u8;
+    while exponent > 0 {
+        if exponent & 1 == 1 {
+            result = mul(result, base);
+        }
+        base = mul(base, base);
+        exponent >>= 1;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every subset of `shares`, as bit masks.
+    fn subsets(shares: &[Share]) -> impl Iterator<Item = Vec<Share>> + '_ {
+        (1u32..1 << shares.len()).map(move |mask| {
+            shares
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| mask & (1 << i) != 0)
+                .map(|(_, share)| share.clone())
+                .collect()
+        })
+    }
+
+    #[test]
+    fn test_gf256_arithmetic() {
+        // The worked example of FIPS 197, section 4.2.
+        assert_eq!(mul(0x57, 0x83), 0xc1);
+        assert_eq!(mul(0x57, 0x13), 0xfe);
+        for a in 1..=255u8 {
+            assert_eq!(mul(a, inverse(a)), 1);
+        }
+    }
+
+    #[test]
+    fn test_every_threshold_combination_rebuilds_the_secret() {
+        let secret: [u8; 32] = rand::random();
+        for shares in 2..=5 {
+            for threshold in 2..=shares {
+                let split = split(&secret, shares, threshold, [1, 2, 3, 4]).unwrap();
+                assert_eq!(split.len(), shares);
+                for subset in subsets(&split) {
+                    let combined = combine(&subset);
+                    if subset.len() >= threshold {
+                        assert_eq!(combined.unwrap(), secret, "{} of {}", threshold, shares);
+                    } else {
+                        assert!(matches!(combined, Err(ShareErr::TooFew { .. })));
+                    }
+                }
+            }
+        }
+        assert!(split(&secret, 3, 1, [0; 4]).is_err());
+        assert!(split(&secret, 3, 4, [0; 4]).is_err());
+        assert!(split(&secret, 256, 2, [0; 4]).is_err());
+    }
+
+    #[test]
+    fn test_tampered_shares_are_rejected() {
+        let secret: [u8; 32] = rand::random();
+        let shares = split(&secret, 5, 3, [9, 9, 9, 9]).unwrap();
+
+        let encoded = shares[0].encode();
+        assert_eq!(Share::decode(&encoded).unwrap(), shares[0]);
+        assert_eq!(
+            Share::decode(&format!(" {} ", encoded.to_uppercase())).unwrap(),
+            shares[0]
+        );
+
+        // A typo is caught by the check at the end of the string.
+        let mut typo = encoded.clone().into_bytes();
+        let at = typo.len() - 10;
+        typo[at] = if typo[at] == b'0' { b'1' } else { b'0' };
+        assert!(Share::decode(&String::from_utf8(typo).unwrap()).is_err());
+
+        // An altered value or share number is caught by the shared check.
+        for i in 0..3 {
+            let mut altered = shares[..3].to_vec();
+            altered[i].y[5] ^= 0x40;
+            assert!(matches!(combine(&altered), Err(ShareErr::Tampered)));
+            let mut moved = shares[..3].to_vec();
+            moved[i].x = 200;
+            assert!(matches!(combine(&moved), Err(ShareErr::Tampered)));
+        }
+
+        // Shares of another split, or the same share twice.
+        let other = split(&secret, 5, 3, [8, 8, 8, 8]).unwrap();
+        let mixed = vec![shares[0].clone(), shares[1].clone(), other[2].clone()];
+        assert!(matches!(combine(&mixed), Err(ShareErr::Mismatch)));
+        let twice = vec![shares[0].clone(), shares[1].clone(), shares[1].clone()];
+        assert!(matches!(combine(&twice), Err(ShareErr::Duplicate { x: 2 })));
+    }
+}
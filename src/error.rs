@@ -34,6 +34,17 @@ pub enum ParserErr {
         givenkind: TokenKind,
         span: Span,
     },
+
+    InvalidUnit {
+        input: String,
+        given: String,
+        span: Span,
+    },
+
+    DurationOutOfRange {
+        input: String,
+        span: Span,
+    },
 }
 
 #[derive(Debug)]
@@ -55,6 +66,8 @@ pub enum SessionErr {
     SessionNotConnected,
     PermissionDenied,
     AnotherSessionIsRunningErr,
+    // Another pwmn process holds a conflicting lock on the register or the root folder.
+    Locked { target: String, pid: Option<u32> },
 }
 
 #[derive(Debug)]
@@ -251,6 +264,33 @@ impl<'a> Display for ParserErr {
                     err_formatter(err_title.as_str(), input, span.start, Some(&span.end), None)
                 )
             }
+
+            Self::InvalidUnit { input, given, span } => {
+                let err_title = format!("Unknown time unit '{}'", given);
+                write!(
+                    f,
+                    "{}",
+                    err_formatter(
+                        err_title.as_str(),
+                        input,
+                        span.start,
+                        Some(&span.end),
+                        Some("Use 's' for seconds or 'ms' for milliseconds, e.g. 1s or 500ms")
+                    )
+                )
+            }
+
+            Self::DurationOutOfRange { input, span } => write!(
+                f,
+                "{}",
+                err_formatter(
+                    "Duration out of range",
+                    input,
+                    span.start,
+                    Some(&span.end),
+                    None
+                )
+            ),
         }
     }
 }
@@ -332,7 +372,21 @@ impl std::fmt::Display for SessionErr {
                 write!(f, "{err_title}")
             }
 
-            _ => todo!(),
+            Self::PermissionDenied => {
+                let err_title = "The register is connected READ ONLY. DISCONNECT and CONNECT without READ ONLY to modify it";
+                write!(f, "{err_title}")
+            }
+
+            Self::Locked { target, pid } => {
+                let holder = match pid {
+                    Some(pid) => format!("another pwmn process (PID {pid})"),
+                    None => "another pwmn process".to_string(),
+                };
+                write!(
+                    f,
+                    "{target} is in use by {holder}. Disconnect it there first"
+                )
+            }
         }
     }
 }
@@ -371,7 +425,8 @@ In order for this program to work properly, it needs access to your system's hom
   Usually this refers to:
       C:\Users\<username>
 
-Please ensure your system environment variables are correctly configured and try again."#
+Please ensure your system environment variables are correctly configured and try again,
+or choose the vault location yourself with PWMN_HOME=<path> or --root <path>."#
         )
     }
 }
@@ -391,6 +446,32 @@ pub enum VaultValidationErr {
 
     #[error("Mismatched File Header; The file may have been manipulated or destroyed.")]
     MismatchedFileHeader,
+
+    // The password was right but the header, ciphertext or register folder did not authenticate.
+    #[error(
+        "The vault failed authentication; its header or data was modified, or the file was copied from another register."
+    )]
+    TamperedVault,
+
+    #[error("The root metadata file (rvault.bin) is damaged or was written by a newer version.")]
+    InvalidRootMeta,
+
+    // A new salt would hide every register hashed with the lost one.
+    #[error(
+        "The root metadata file (rvault.bin) is missing, but registers were created with it. Run VERIFY ALL to restore it from their copies, or restore the root from a backup."
+    )]
+    MissingRootMeta,
+
+    #[error(
+        "The copies of rvault.bin kept by the registers disagree, so none of them is restored. Restore the root from a backup."
+    )]
+    RootMetaCopiesDiffer,
+
+    // Found before any password is tried, see `Vault::header_check`.
+    #[error(
+        "The vault header was modified or damaged, its key slots can't be trusted. VERIFY REGISTER <name> repairs it from the intact copy."
+    )]
+    TamperedHeader,
 }
 
 #[derive(Debug, Error)]
@@ -399,6 +480,53 @@ pub enum DropErr {
     VaultNotExists { vault: String },
 }
 
+#[derive(Debug, Error)]
+pub enum AlterErr {
+    #[error("The new passwords do not match, the register password was left unchanged.")]
+    PasswordMismatch,
+}
+
+#[derive(Debug, Error)]
+pub enum BackupErr {
+    #[error(
+        "No backup taken at '{}'. Use SHOW BACKUPS to list the available ones.",
+        timestamp
+    )]
+    NotFound { timestamp: String },
+    #[error("The backup retention file contains an invalid line: '{0}'")]
+    InvalidRetention(String),
+}
+
+#[derive(Debug, Error)]
+pub enum KeyfileErr {
+    #[error(
+        "This register requires its keyfile. Use WITH KEYFILE '<path>' or enter the path when asked."
+    )]
+    Required,
+    #[error("This register does not use a keyfile, leave out WITH KEYFILE.")]
+    NotUsed,
+    #[error("Couldn't read the keyfile '{path}': {reason}")]
+    Unreadable { path: String, reason: String },
+    #[error("'{path}' already exists, a keyfile is never overwritten.")]
+    AlreadyExists { path: String },
+}
+
+#[derive(Debug, Error)]
+pub enum KeySlotErr {
+    #[error("There is no key slot {index}, SHOW KEYSLOTS lists them.")]
+    NotFound { index: usize },
+    #[error("The last password key slot can't be removed, the register would never open again.")]
+    LastSlot,
+    #[error("All {max} key slots are in use, remove one first.")]
+    Full { max: usize },
+}
+
+#[derive(Debug, Error)]
+pub enum ConfigErr {
+    #[error("Invalid configuration in {path}:\n{reason}")]
+    Invalid { path: String, reason: String },
+}
+
 #[derive(Debug, Error)]
 pub enum FileReqErr {
     #[error(
@@ -413,6 +541,8 @@ pub enum AuthErr {
         "The Auth file does not exist, which may indicate that the vault folder has been damaged or tampered with."
     )]
     AuthFileNotFound,
+    #[error("The vault header has to be loaded before an auth file without one can be checked.")]
+    HeaderNotLoaded,
 }
 
 fn err_formatter(
@@ -445,3 +575,111 @@ fn err_formatter(
     }
     without_hint
 }
+
+#[derive(Debug, Error)]
+pub enum RecoveryErr {
+    #[error("Not a valid recovery key: {reason}")]
+    Invalid { reason: String },
+    #[error("This register has no recovery key.")]
+    NotSet,
+    #[error("The recovery key doesn't open this register.")]
+    WrongKey,
+    #[error(
+        "WITH RECOVERY KEY resets the password, it can't be combined with READ ONLY, WITH KEYFILE, WITH SHARES or WITH IDENTITY."
+    )]
+    Combined,
+    #[error("'{path}' already exists, an emergency kit is never overwritten.")]
+    KitExists { path: String },
+}
+
+#[derive(Debug, Error)]
+pub enum ShareErr {
+    #[error("Not a valid share: {reason}")]
+    Malformed { reason: String },
+    #[error(
+        "A split needs 2 <= THRESHOLD <= SHARES <= 255, got {shares} shares with threshold {threshold}."
+    )]
+    InvalidSplit { shares: usize, threshold: usize },
+    #[error("{needed} shares are needed to rebuild the key.")]
+    TooFew { needed: usize },
+    #[error("Share {x} was entered twice.")]
+    Duplicate { x: u8 },
+    #[error("The shares come from different splits.")]
+    Mismatch,
+    #[error("The shares don't fit together, one of them was altered or belongs elsewhere.")]
+    Tampered,
+    #[error("This register's key was never split, SPLIT REGISTER KEY creates shares.")]
+    NotSplit,
+    #[error(
+        "No key slot of this register takes these shares, they were revoked or belong to another register."
+    )]
+    NotFound,
+    #[error("WITH SHARES opens the register without a keyfile, leave out WITH KEYFILE.")]
+    WithKeyfile,
+    #[error("'{path}' already exists, a share file is never overwritten.")]
+    FileExists { path: String },
+}
+
+#[derive(Debug, Error)]
+pub enum IdentityErr {
+    #[error("No identity at '{path}', create one with `pwmn keygen`.")]
+    NotFound { path: String },
+    #[error("An identity already exists at '{path}', its public key is {public}")]
+    AlreadyExists { path: String, public: String },
+    #[error("The identity file is damaged or not a pwmn identity.")]
+    Malformed,
+    #[error("Wrong passphrase for the identity.")]
+    WrongPassphrase,
+    #[error("Not a valid public key: {reason}")]
+    InvalidPublicKey { reason: String },
+    #[error("This register was not granted to your identity.")]
+    NotGranted,
+    #[error("{public} already has access through key slot {index}.")]
+    AlreadyGranted { public: String, index: usize },
+    #[error("{public} has no access to revoke.")]
+    NotMember { public: String },
+    #[error("WITH IDENTITY opens the register on its own, leave out WITH KEYFILE and WITH SHARES.")]
+    Combined,
+    #[error(
+        "The identity that unlocked the register can't revoke itself, unlock with a password instead."
+    )]
+    RevokeSelf,
+    #[error("REVOKE ACCESS keeps the register's password, unlock with a password instead.")]
+    PasswordNeeded,
+}
+
+#[derive(Debug, Error)]
+pub enum BundleErr {
+    #[error("No entry '{id}' in this register.")]
+    EntryNotFound { id: String },
+    #[error("'{path}' already exists, a share file is never overwritten.")]
+    FileExists { path: String },
+    #[error("Not a pwmn share file, or it is damaged.")]
+    Malformed,
+    #[error("Wrong passphrase, or the share file was altered.")]
+    WrongPassphrase,
+    #[error("The share file was altered.")]
+    Tampered,
+    #[error("The share was made for {public}, not for your identity.")]
+    NotForYou { public: String },
+    #[error("The share expired on {at}.")]
+    Expired { at: String },
+}
+
+#[derive(Debug, Error)]
+pub enum ThrottleErr {
+    #[error("{failures} failed unlock attempts in a row, try again in {secs} s.")]
+    Backoff { failures: u32, secs: i64 },
+    #[error(
+        "The record of failed unlock attempts of this register was altered, try again in {secs} s."
+    )]
+    Altered { secs: i64 },
+    #[error("'{path}' is damaged, remove it to start the failed unlock records over.")]
+    BadKey { path: String },
+}
+
+#[derive(Debug, Error)]
+pub enum CipherErr {
+    #[error("Unknown cipher '{name}', choose one of: {known}.")]
+    Unknown { name: String, known: String },
+}
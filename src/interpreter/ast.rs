@@ -51,11 +51,63 @@ pub enum BinaryOperator {
 pub enum Stmt {
     Empty,
     Init,
-    Create { reg_name: String },
-    Connect { reg_name: String },
+    Create {
+        reg_name: String,
+        opts: CreateOpts,
+    },
+    Connect {
+        reg_name: String,
+        read_only: bool,
+        keyfile: Option<String>,
+        // WITH RECOVERY KEY, asks for the recovery key and then for a new password.
+        recovery: bool,
+        // WITH SHARES, asks for shares of a SPLIT REGISTER KEY instead of the password.
+        shares: bool,
+        // WITH IDENTITY, opens with the identity of `pwmn keygen` the register was granted to.
+        identity: bool,
+    },
     DropTree(DropTree),
+    AlterTree(AlterTree),
+    Rekey {
+        kdf_calibrate_ms: Option<u64>,
+    },
+    Backup(BackupTree),
+    Verify(VerifyTree),
+    KeySlot(KeySlotTree),
+    Access(AccessTree),
+    // SPLIT REGISTER KEY INTO <n> SHARES THRESHOLD <k> [TO '<dir>']
+    SplitKey {
+        shares: usize,
+        threshold: usize,
+        dir: Option<String>,
+    },
     Disconnect,
-    Select { cols: Box<Expr> },
+    Status,
+    // LOCK
+    Lock,
+    // SET AUTOLOCK <n> MINUTES, 0 turns it off.
+    SetAutolock {
+        minutes: u64,
+    },
+    // GENERATE KEYFILE '<path>'
+    GenerateKeyfile {
+        path: String,
+    },
+    // EXPORT ENTRY '<id>' TO FILE '<path>' FOR '<public key>' | WITH PASSPHRASE
+    //     [EXPIRES IN <n> DAYS]
+    ExportEntry {
+        entry_id: String,
+        path: String,
+        to: ShareTo,
+        expires_days: Option<u32>,
+    },
+    // IMPORT SHARE '<path>'
+    ImportShare {
+        path: String,
+    },
+    Select {
+        cols: Box<Expr>,
+    },
 }
 
 #[derive(Debug, Clone)]
@@ -64,14 +116,101 @@ pub enum DropTree {
     Ent(String),
 }
 
+#[derive(Debug, Clone, Default)]
+pub struct CreateOpts {
+    // WITH KDF CALIBRATE <duration>, the target unlock time in milliseconds.
+    pub kdf_calibrate_ms: Option<u64>,
+    // WITH KEYFILE '<path>', required together with the password to unlock.
+    pub keyfile: Option<String>,
+    // WITH RECOVERY KEY [KIT '<path>'], a recovery key that opens the register on its
+    // own, optionally written to an emergency kit at <path>.
+    pub recovery_key: bool,
+    pub recovery_kit: Option<String>,
+    // WITH CIPHER '<name>', the AEAD suite the register data is sealed with.
+    pub cipher: Option<String>,
+}
+
+// ALTER always targets the currently connected register.
+#[derive(Debug, Clone)]
+pub enum AlterTree {
+    SetPassword,
+}
+
+#[derive(Debug, Clone)]
+pub enum VerifyTree {
+    Register(String),
+    All,
+}
+
+// Key slots of the connected register.
+#[derive(Debug, Clone)]
+pub enum KeySlotTree {
+    // ADD KEYSLOT PROMPT [WITH KEYFILE '<path>']
+    Add { keyfile: Option<String> },
+    // REMOVE KEYSLOT <n>, numbered as in SHOW KEYSLOTS.
+    Remove(usize),
+    Show,
+}
+
+// Who can open an exported entry.
+#[derive(Debug, Clone)]
+pub enum ShareTo {
+    // FOR '<public key>', the identity of `pwmn keygen` behind it.
+    Member(String),
+    // WITH PASSPHRASE, asked for when exporting.
+    Passphrase,
+}
+
+// Members of a team register, by the public key of their identity.
+#[derive(Debug, Clone)]
+pub enum AccessTree {
+    // GRANT ACCESS TO '<public key>'
+    Grant(String),
+    // REVOKE ACCESS FROM '<public key>'
+    Revoke(String),
+}
+
+#[derive(Debug, Clone)]
+pub enum BackupTree {
+    // SHOW BACKUPS [<name>], the connected register when no name is given.
+    Show(Option<String>),
+    // RESTORE REGISTER [<name>] FROM BACKUP '<timestamp>'
+    Restore {
+        reg_name: Option<String>,
+        timestamp: String,
+    },
+    // SET BACKUP RETENTION [LAST n] [DAILY n] [WEEKLY n], unset rules keep their value.
+    SetRetention(RetentionOpts),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RetentionOpts {
+    pub last: Option<usize>,
+    pub daily: Option<usize>,
+    pub weekly: Option<usize>,
+}
+
 impl Inner for Expr {
     fn extract(&self) -> Result<Stmt, ParserErr> {
         match self {
-            Expr::Statment(Stmt::Create { reg_name: s }) => Ok(Stmt::Create {
+            Expr::Statment(Stmt::Create { reg_name: s, opts }) => Ok(Stmt::Create {
                 reg_name: s.to_owned(),
+                opts: opts.clone(),
             }),
-            Expr::Statment(Stmt::Connect { reg_name: s }) => Ok(Stmt::Connect {
+            Expr::Statment(Stmt::Connect {
+                reg_name: s,
+                read_only,
+                keyfile,
+                recovery,
+                shares,
+                identity,
+            }) => Ok(Stmt::Connect {
                 reg_name: s.to_owned(),
+                read_only: *read_only,
+                keyfile: keyfile.clone(),
+                recovery: *recovery,
+                shares: *shares,
+                identity: *identity,
             }),
             Expr::Statment(Stmt::Init) => Ok(Stmt::Init),
             Expr::Statment(Stmt::DropTree(DropTree::Reg(s))) => {
@@ -80,7 +219,48 @@ impl Inner for Expr {
             Expr::Statment(Stmt::DropTree(DropTree::Ent(s))) => {
                 Ok(Stmt::DropTree(DropTree::Ent(s.to_owned())))
             }
+            Expr::Statment(Stmt::AlterTree(AlterTree::SetPassword)) => {
+                Ok(Stmt::AlterTree(AlterTree::SetPassword))
+            }
+            Expr::Statment(Stmt::Rekey { kdf_calibrate_ms }) => Ok(Stmt::Rekey {
+                kdf_calibrate_ms: *kdf_calibrate_ms,
+            }),
+            Expr::Statment(Stmt::Backup(backup)) => Ok(Stmt::Backup(backup.clone())),
+            Expr::Statment(Stmt::Verify(verify)) => Ok(Stmt::Verify(verify.clone())),
+            Expr::Statment(Stmt::KeySlot(slots)) => Ok(Stmt::KeySlot(slots.clone())),
+            Expr::Statment(Stmt::Access(access)) => Ok(Stmt::Access(access.clone())),
+            Expr::Statment(Stmt::SplitKey {
+                shares,
+                threshold,
+                dir,
+            }) => Ok(Stmt::SplitKey {
+                shares: *shares,
+                threshold: *threshold,
+                dir: dir.clone(),
+            }),
             Expr::Statment(Stmt::Disconnect) => Ok(Stmt::Disconnect),
+            Expr::Statment(Stmt::Status) => Ok(Stmt::Status),
+            Expr::Statment(Stmt::Lock) => Ok(Stmt::Lock),
+            Expr::Statment(Stmt::SetAutolock { minutes }) => {
+                Ok(Stmt::SetAutolock { minutes: *minutes })
+            }
+            Expr::Statment(Stmt::GenerateKeyfile { path }) => {
+                Ok(Stmt::GenerateKeyfile { path: path.clone() })
+            }
+            Expr::Statment(Stmt::ExportEntry {
+                entry_id,
+                path,
+                to,
+                expires_days,
+            }) => Ok(Stmt::ExportEntry {
+                entry_id: entry_id.clone(),
+                path: path.clone(),
+                to: to.clone(),
+                expires_days: *expires_days,
+            }),
+            Expr::Statment(Stmt::ImportShare { path }) => {
+                Ok(Stmt::ImportShare { path: path.clone() })
+            }
             _ => unreachable!(),
         }
     }
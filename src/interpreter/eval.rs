@@ -1,6 +1,9 @@
-use crate::interpreter::ast::{self, DropTree, Stmt};
+use crate::interpreter::ast::{self, AlterTree, BackupTree, DropTree, Stmt, VerifyTree};
 use crate::session::session_conn::SessionConn;
-use crate::statements::{connect, create, disconnect, drop};
+use crate::statements::{
+    access, alter, autolock, backup, connect, create, disconnect, drop, entryshare, keyfile,
+    keyslot, rekey, shares, status, verify,
+};
 use crate::storage::init;
 pub trait eval {
     fn eval(self, session: &mut SessionConn) -> Result<(), Box<dyn std::error::Error>>;
@@ -8,15 +11,60 @@ pub trait eval {
 
 impl eval for Stmt {
     fn eval(self, session: &mut SessionConn) -> Result<(), Box<dyn std::error::Error>> {
+        // A locked register comes back with the first statement that works on it.
+        if session.is_locked() && uses_connected_register(&self, session.get_connected_reg_name()) {
+            autolock::AutolockExec::resume(session)?;
+        }
         match self {
             Self::Init => init::init()?,
-            Self::Create { reg_name } => create::CreateRegExec::execute(&reg_name, session)?,
-            Self::Connect { reg_name } => connect::VaultConnection::execute(&reg_name, session)?,
+            Self::Create { reg_name, opts } => {
+                create::CreateRegExec::execute(&reg_name, opts, session)?
+            }
+            Self::Connect {
+                reg_name,
+                read_only,
+                keyfile,
+                recovery,
+                shares,
+                identity,
+            } => connect::VaultConnection::execute(
+                &reg_name,
+                read_only,
+                keyfile.as_deref(),
+                recovery,
+                shares,
+                identity,
+                session,
+            )?,
             Self::DropTree(DropTree::Reg(s)) => drop::Drop::execute(DropTree::Reg(s), &session)?,
             Self::DropTree(DropTree::Ent(s)) => drop::Drop::execute(DropTree::Ent(s), &session)?,
+            Self::AlterTree(alter) => alter::AlterRegExec::execute(alter, session)?,
+            Self::Rekey { kdf_calibrate_ms } => {
+                rekey::RekeyExec::execute(kdf_calibrate_ms, session)?
+            }
             Self::Disconnect => {
                 disconnect::Disconnect { session }.disconnect();
             }
+            Self::Backup(tree) => backup::BackupExec::execute(tree, session)?,
+            Self::Verify(tree) => verify::VerifyExec::execute(tree, session)?,
+            Self::KeySlot(tree) => keyslot::KeySlotExec::execute(tree, session)?,
+            Self::Access(tree) => access::AccessExec::execute(tree, session)?,
+            Self::SplitKey {
+                shares,
+                threshold,
+                dir,
+            } => shares::SharesExec::split(shares, threshold, dir.as_deref(), session)?,
+            Self::Status => status::StatusExec::execute(session),
+            Self::Lock => autolock::AutolockExec::lock(session)?,
+            Self::SetAutolock { minutes } => autolock::AutolockExec::set(minutes, session),
+            Self::GenerateKeyfile { path } => keyfile::KeyfileExec::execute(&path)?,
+            Self::ExportEntry {
+                entry_id,
+                path,
+                to,
+                expires_days,
+            } => entryshare::EntryShareExec::export(&entry_id, &path, to, expires_days, session)?,
+            Self::ImportShare { path } => entryshare::EntryShareExec::import(&path, session)?,
             other => {
                 println!("TODO -> {:?}", other);
             }
@@ -24,3 +72,67 @@ impl eval for Stmt {
         Ok(())
     }
 }
+
+// Statements that name another register, or none at all, leave a locked one locked.
+fn uses_connected_register(stmt: &Stmt, connected: Option<&str>) -> bool {
+    let is_connected = |name: &str| connected == Some(name);
+    match stmt {
+        Stmt::Init
+        | Stmt::Create { .. }
+        | Stmt::Connect { .. }
+        | Stmt::Disconnect
+        | Stmt::Status
+        | Stmt::Lock
+        | Stmt::SetAutolock { .. }
+        | Stmt::GenerateKeyfile { .. }
+        | Stmt::DropTree(DropTree::Reg(_))
+        | Stmt::Verify(VerifyTree::All) => false,
+        Stmt::Verify(VerifyTree::Register(name))
+        | Stmt::Backup(BackupTree::Show(Some(name)))
+        | Stmt::Backup(BackupTree::Restore {
+            reg_name: Some(name),
+            ..
+        }) => is_connected(name),
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_statements_on_the_connected_register_resume_it() {
+        let connected = Some("personal");
+        let show = |name: Option<&str>| Stmt::Backup(BackupTree::Show(name.map(String::from)));
+        let verify = |name: &str| Stmt::Verify(VerifyTree::Register(name.to_string()));
+
+        assert!(uses_connected_register(&show(None), connected));
+        assert!(uses_connected_register(&show(Some("personal")), connected));
+        assert!(uses_connected_register(&verify("personal"), connected));
+        assert!(uses_connected_register(
+            &Stmt::Rekey {
+                kdf_calibrate_ms: None
+            },
+            connected
+        ));
+
+        assert!(!uses_connected_register(&show(Some("work")), connected));
+        assert!(!uses_connected_register(&verify("work"), connected));
+        assert!(!uses_connected_register(
+            &Stmt::Verify(VerifyTree::All),
+            connected
+        ));
+        assert!(!uses_connected_register(&Stmt::Init, connected));
+        assert!(!uses_connected_register(
+            &Stmt::DropTree(DropTree::Reg("work".to_string())),
+            connected
+        ));
+        assert!(!uses_connected_register(
+            &Stmt::GenerateKeyfile {
+                path: "work.key".to_string()
+            },
+            connected
+        ));
+    }
+}
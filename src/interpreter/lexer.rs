@@ -29,13 +29,22 @@ pub struct LexResult<'a> {
 }
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
 pub enum TokenKind {
+    Access,
     Add,
+    All,
+    Alter,
     As,
     Audit,
+    Autolock,
+    Backup,
+    Calibrate,
+    Cipher,
     Connect,
     Create,
     Contains,
     Conn, // shorthand for connection
+    Daily,
+    Days,
     Delete,
     Drop,
     Describe,
@@ -44,28 +53,59 @@ pub enum TokenKind {
     Disconnect,
     Enable,
     Entry,
+    Expires,
+    Export,
+    File,
+    For,
     From,
     Generate,
     Generated,
+    Grant,
+    Identity,
+    Import,
+    In,
     Init,
     Insert,
     Into,
+    Kdf,
+    Key,
+    Keyfile,
+    Keyslot,
+    Kit,
+    Last,
     List,
     Limit,
+    Lock,
     Metadata,
     Minus,
+    Minutes,
+    Only,
+    Passphrase,
     Password,
     Percent,
     Slash,
     Plus,
     Prompt,
+    Read,
+    Recovery,
     Register,
+    Rekey,
+    Remove,
+    Restore,
+    Retention,
+    Revoke,
     Rotate,
     Set,
+    Shares,
+    Show,
     Select,
+    Split,
     Status,
+    Threshold,
     Update,
+    Verify,
     Where,
+    Weekly,
     With,
 
     Bool(bool),
@@ -158,7 +198,19 @@ impl<'a> Lexer<'a> {
                 '-' => {
                     start = self.pos;
                     self.next_char();
-                    push_token(&mut tokens, TokenKind::Minus, start, self.pos);
+                    // A minus directly followed by a digit is a negative literal (LIMIT -5),
+                    // anything else stays an operator.
+                    if matches!(self.chars.peek(), Some(ch) if ch.is_ascii_digit()) {
+                        match self.extract_number() {
+                            Ok(TokenKind::Number(n)) => {
+                                push_token(&mut tokens, TokenKind::Number(-n), start, self.pos)
+                            }
+                            Ok(tok) => push_token(&mut tokens, tok, start, self.pos),
+                            Err(e) => return Err(e),
+                        }
+                    } else {
+                        push_token(&mut tokens, TokenKind::Minus, start, self.pos);
+                    }
                 }
 
                 '0'..='9' => {
@@ -273,13 +325,23 @@ impl<'a> Lexer<'a> {
                     let upper = word.to_uppercase();
 
                     let kind = match upper.as_str() {
+                        "ACCESS" => TokenKind::Access,
                         "ADD" => TokenKind::Add,
+                        "ALL" => TokenKind::All,
+                        "ALTER" => TokenKind::Alter,
                         "AS" => TokenKind::As,
                         "AUDIT" => TokenKind::Audit,
+                        "AUTOLOCK" => TokenKind::Autolock,
+                        "BACKUP" => TokenKind::Backup,
+                        "BACKUPS" => TokenKind::Backup,
+                        "CALIBRATE" => TokenKind::Calibrate,
+                        "CIPHER" => TokenKind::Cipher,
                         "CONNECT" => TokenKind::Connect,
                         "CREATE" => TokenKind::Create,
                         "CONTAINS" => TokenKind::Contains,
                         "CONN" => TokenKind::Connect,
+                        "DAILY" => TokenKind::Daily,
+                        "DAYS" => TokenKind::Days,
                         "DROP" => TokenKind::Drop,
                         "DELETE" => TokenKind::Delete,
                         "DESCRIBE" => TokenKind::Describe,
@@ -287,27 +349,61 @@ impl<'a> Lexer<'a> {
                         "DISABLE" => TokenKind::Disable,
                         "DISCONNECT" => TokenKind::Disconnect,
                         "ENABLE" => TokenKind::Enable,
-                        "ENTRT" => TokenKind::Entry,
+                        "ENTRY" => TokenKind::Entry,
+                        "EXPIRES" => TokenKind::Expires,
+                        "EXPORT" => TokenKind::Export,
+                        "FILE" => TokenKind::File,
+                        "FOR" => TokenKind::For,
                         "FROM" => TokenKind::From,
                         "GENERATE" => TokenKind::Generate,
                         "GENERATED" => TokenKind::Generated,
+                        "GRANT" => TokenKind::Grant,
+                        "IDENTITY" => TokenKind::Identity,
+                        "IMPORT" => TokenKind::Import,
+                        "IN" => TokenKind::In,
                         "INIT" => TokenKind::Init,
                         "INTO" => TokenKind::Into,
                         "INSERT" => TokenKind::Insert,
+                        "KDF" => TokenKind::Kdf,
+                        "KEY" => TokenKind::Key,
+                        "KEYFILE" => TokenKind::Keyfile,
+                        "KEYSLOT" => TokenKind::Keyslot,
+                        "KEYSLOTS" => TokenKind::Keyslot,
+                        "KIT" => TokenKind::Kit,
+                        "LAST" => TokenKind::Last,
                         "LIST" => TokenKind::List,
                         "LOG" => TokenKind::Log,
                         "LIMIT" => TokenKind::Limit,
+                        "LOCK" => TokenKind::Lock,
                         "METADATA" => TokenKind::Metadata,
+                        "MINUTE" => TokenKind::Minutes,
+                        "MINUTES" => TokenKind::Minutes,
+                        "ONLY" => TokenKind::Only,
+                        "PASSPHRASE" => TokenKind::Passphrase,
                         "PASSWORD" => TokenKind::Password,
                         "PROMPT" => TokenKind::Prompt,
+                        "READ" => TokenKind::Read,
+                        "RECOVERY" => TokenKind::Recovery,
                         "REGISTER" => TokenKind::Register,
                         "REG" => TokenKind::Register, // shorthand for register;
+                        "REKEY" => TokenKind::Rekey,
+                        "REMOVE" => TokenKind::Remove,
+                        "RESTORE" => TokenKind::Restore,
+                        "RETENTION" => TokenKind::Retention,
+                        "REVOKE" => TokenKind::Revoke,
                         "ROTATE" => TokenKind::Rotate,
                         "SELECT" => TokenKind::Select,
                         "SET" => TokenKind::Set,
+                        "SHARE" => TokenKind::Shares,
+                        "SHARES" => TokenKind::Shares,
+                        "SHOW" => TokenKind::Show,
+                        "SPLIT" => TokenKind::Split,
                         "STATUS" => TokenKind::Status,
+                        "THRESHOLD" => TokenKind::Threshold,
                         "UPDATE" => TokenKind::Update,
+                        "VERIFY" => TokenKind::Verify,
                         "WHERE" => TokenKind::Where,
+                        "WEEKLY" => TokenKind::Weekly,
                         "WITH" => TokenKind::With,
                         "TO" => TokenKind::To,
                         "AND" => TokenKind::And,
@@ -16,12 +16,21 @@ pub fn push_token(collector: &mut Vec<Token>, tTokind: lexer::TokenKind, start:
 
 pub fn token_name(tokind: &TokenKind) -> &str {
     match tokind {
+        TokenKind::Access => "Access",
         TokenKind::Add => "Add",
+        TokenKind::All => "All",
+        TokenKind::Alter => "Alter",
         TokenKind::As => "As",
         TokenKind::Audit => "Audit",
+        TokenKind::Autolock => "Autolock",
+        TokenKind::Backup => "Backup",
+        TokenKind::Calibrate => "Calibrate",
+        TokenKind::Cipher => "Cipher",
         TokenKind::Connect => "Connect",
         TokenKind::Create => "Create",
         TokenKind::Conn => "Conn",
+        TokenKind::Daily => "Daily",
+        TokenKind::Days => "Days",
         TokenKind::Contains => "Contains",
         TokenKind::Delete => "Delete",
         TokenKind::Drop => "Drop",
@@ -31,29 +40,60 @@ pub fn token_name(tokind: &TokenKind) -> &str {
         TokenKind::Disconnect => "Disconnect",
         TokenKind::Enable => "Enable",
         TokenKind::Entry => "Entry",
+        TokenKind::Expires => "Expires",
+        TokenKind::Export => "Export",
+        TokenKind::File => "File",
+        TokenKind::For => "For",
         TokenKind::From => "From",
         TokenKind::Generate => "Generate",
         TokenKind::Generated => "Generated",
+        TokenKind::Grant => "Grant",
+        TokenKind::Identity => "Identity",
+        TokenKind::Import => "Import",
+        TokenKind::In => "In",
         TokenKind::Init => "Init",
         TokenKind::Insert => "Insert",
         TokenKind::Into => "Into",
+        TokenKind::Kdf => "Kdf",
+        TokenKind::Key => "Key",
+        TokenKind::Keyfile => "Keyfile",
+        TokenKind::Keyslot => "Keyslot",
+        TokenKind::Kit => "Kit",
+        TokenKind::Last => "Last",
         TokenKind::List => "List",
         TokenKind::Limit => "Limit",
+        TokenKind::Lock => "Lock",
         TokenKind::EmptyIdentifer => "Identifier",
         TokenKind::Metadata => "Metadata",
         TokenKind::Minus => "Minus",
+        TokenKind::Minutes => "Minutes",
+        TokenKind::Only => "Only",
+        TokenKind::Passphrase => "Passphrase",
         TokenKind::Password => "Password",
         TokenKind::Percent => "Percent",
         TokenKind::Prompt => "Prompt",
+        TokenKind::Read => "Read",
+        TokenKind::Recovery => "Recovery",
         TokenKind::Plus => "Plus",
         TokenKind::Register => "Register",
+        TokenKind::Rekey => "Rekey",
+        TokenKind::Remove => "Remove",
+        TokenKind::Restore => "Restore",
+        TokenKind::Retention => "Retention",
+        TokenKind::Revoke => "Revoke",
         TokenKind::Rotate => "Rotate",
         TokenKind::Set => "Set",
+        TokenKind::Shares => "Shares",
+        TokenKind::Show => "Show",
         TokenKind::Select => "Select",
+        TokenKind::Split => "Split",
         TokenKind::Status => "Status",
+        TokenKind::Threshold => "Threshold",
         TokenKind::Slash => "Slash",
         TokenKind::Update => "Update",
+        TokenKind::Verify => "Verify",
         TokenKind::Where => "Where",
+        TokenKind::Weekly => "Weekly",
         TokenKind::With => "With",
         TokenKind::Bool(_) => "Bool",
         TokenKind::Identifier(_) => "Identifier",
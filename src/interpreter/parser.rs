@@ -1,7 +1,13 @@
 use crate::error::ParserErr;
-use crate::interpreter::ast::{self, Expr::Statment as ExprStmt, Inner, Stmt};
+use crate::interpreter::ast::{
+    self, AccessTree, BackupTree, CreateOpts, Expr::Statment as ExprStmt, Inner, KeySlotTree,
+    RetentionOpts, ShareTo, Stmt, VerifyTree,
+};
 use crate::interpreter::lexer::{LexResult, Span, Token, TokenKind};
-use crate::interpreter::{ast::DropTree, lexer};
+use crate::interpreter::{
+    ast::{AlterTree, DropTree},
+    lexer,
+};
 use std::error::Error;
 use std::fmt::Display;
 //
@@ -81,9 +87,7 @@ impl<'t> Parser<'t> {
                     return Ok(ast::Expr::Add(Box::new(expr), Box::new(right)));
                 }
             }
-            None => {
-                println!("ERR");
-            }
+            None => {}
         }
 
         Ok(expr)
@@ -100,9 +104,7 @@ impl<'t> Parser<'t> {
                     return Ok(ast::Expr::Add(Box::new(expr), Box::new(right)));
                 }
             }
-            None => {
-                println!("ERR");
-            }
+            None => {}
         }
 
         Ok(expr)
@@ -137,8 +139,47 @@ impl<'t> Parser<'t> {
                                 let (tok, kind) = token;
                                 match kind {
                                     TokenKind::Identifier(name) => {
+                                        self.consume(TokenKind::Identifier(name.clone()))?;
+                                        // CONNECT <name> READ ONLY shares the register with
+                                        // other readers instead of locking it exclusively.
+                                        let (mut read_only, mut keyfile) = (false, None);
+                                        let (mut recovery, mut shares) = (false, false);
+                                        let mut identity = false;
+                                        loop {
+                                            match self.peek_token() {
+                                                Some((_, TokenKind::Read)) => {
+                                                    self.consume(TokenKind::Read)?;
+                                                    self.consume(TokenKind::Only)?;
+                                                    read_only = true;
+                                                }
+                                                Some((_, TokenKind::With)) => {
+                                                    self.consume(TokenKind::With)?;
+                                                    match self.peek_token() {
+                                                        Some((_, TokenKind::Recovery)) => {
+                                                            self.parse_recovery_key()?;
+                                                            recovery = true;
+                                                        }
+                                                        Some((_, TokenKind::Shares)) => {
+                                                            self.consume(TokenKind::Shares)?;
+                                                            shares = true;
+                                                        }
+                                                        Some((_, TokenKind::Identity)) => {
+                                                            self.consume(TokenKind::Identity)?;
+                                                            identity = true;
+                                                        }
+                                                        _ => keyfile = Some(self.parse_keyfile()?),
+                                                    }
+                                                }
+                                                _ => break,
+                                            }
+                                        }
                                         return Ok(ast::Expr::Statment(ast::Stmt::Connect {
                                             reg_name: name,
+                                            read_only,
+                                            keyfile,
+                                            recovery,
+                                            shares,
+                                            identity,
                                         }));
                                     }
                                     other => {
@@ -166,8 +207,11 @@ impl<'t> Parser<'t> {
                                 let (tk, kind) = token;
                                 match kind {
                                     TokenKind::Identifier(name) => {
+                                        self.consume(TokenKind::Identifier(name.clone()))?;
+                                        let opts = self.parse_create_opts()?;
                                         return Ok(ast::Expr::Statment(ast::Stmt::Create {
                                             reg_name: name,
+                                            opts,
                                         }));
                                     }
                                     other => {
@@ -263,9 +307,209 @@ impl<'t> Parser<'t> {
                         }
                     }
 
+                    TokenKind::Alter => {
+                        self.consume(TokenKind::Alter)?;
+                        self.consume(TokenKind::Register)?;
+                        self.consume(TokenKind::Set)?;
+                        self.consume(TokenKind::Password)?;
+                        self.consume(TokenKind::Prompt)?;
+                        return Ok(ExprStmt(Stmt::AlterTree(AlterTree::SetPassword)));
+                    }
+
+                    TokenKind::Rekey => {
+                        self.consume(TokenKind::Rekey)?;
+                        let mut kdf_calibrate_ms = None;
+                        if let Some((_, TokenKind::With)) = self.peek_token() {
+                            self.consume(TokenKind::With)?;
+                            kdf_calibrate_ms = Some(self.parse_kdf_calibrate()?);
+                        }
+                        return Ok(ExprStmt(Stmt::Rekey { kdf_calibrate_ms }));
+                    }
+
                     TokenKind::Disconnect => {
                         return Ok(ast::Expr::Statment((ast::Stmt::Disconnect)));
                     }
+
+                    TokenKind::Show => {
+                        self.consume(TokenKind::Show)?;
+                        if let Some((_, TokenKind::Keyslot)) = self.peek_token() {
+                            self.consume(TokenKind::Keyslot)?;
+                            return Ok(ExprStmt(Stmt::KeySlot(KeySlotTree::Show)));
+                        }
+                        self.consume(TokenKind::Backup)?;
+                        let reg_name = self.parse_opt_identifier()?;
+                        return Ok(ExprStmt(Stmt::Backup(BackupTree::Show(reg_name))));
+                    }
+
+                    TokenKind::Add => {
+                        self.consume(TokenKind::Add)?;
+                        self.consume(TokenKind::Keyslot)?;
+                        self.consume(TokenKind::Prompt)?;
+                        let mut keyfile = None;
+                        if let Some((_, TokenKind::With)) = self.peek_token() {
+                            self.consume(TokenKind::With)?;
+                            keyfile = Some(self.parse_keyfile()?);
+                        }
+                        return Ok(ExprStmt(Stmt::KeySlot(KeySlotTree::Add { keyfile })));
+                    }
+
+                    TokenKind::Split => {
+                        self.consume(TokenKind::Split)?;
+                        self.consume(TokenKind::Register)?;
+                        self.consume(TokenKind::Key)?;
+                        self.consume(TokenKind::Into)?;
+                        let shares = self.parse_number(0)? as usize;
+                        self.consume(TokenKind::Shares)?;
+                        self.consume(TokenKind::Threshold)?;
+                        let threshold = self.parse_number(0)? as usize;
+                        let mut dir = None;
+                        if let Some((_, TokenKind::To)) = self.peek_token() {
+                            self.consume(TokenKind::To)?;
+                            dir = Some(self.parse_string()?);
+                        }
+                        return Ok(ExprStmt(Stmt::SplitKey {
+                            shares,
+                            threshold,
+                            dir,
+                        }));
+                    }
+
+                    TokenKind::Grant => {
+                        self.consume(TokenKind::Grant)?;
+                        self.consume(TokenKind::Access)?;
+                        self.consume(TokenKind::To)?;
+                        let public = self.parse_string()?;
+                        return Ok(ExprStmt(Stmt::Access(AccessTree::Grant(public))));
+                    }
+
+                    TokenKind::Revoke => {
+                        self.consume(TokenKind::Revoke)?;
+                        self.consume(TokenKind::Access)?;
+                        self.consume(TokenKind::From)?;
+                        let public = self.parse_string()?;
+                        return Ok(ExprStmt(Stmt::Access(AccessTree::Revoke(public))));
+                    }
+
+                    TokenKind::Remove => {
+                        self.consume(TokenKind::Remove)?;
+                        self.consume(TokenKind::Keyslot)?;
+                        let index = self.parse_number(0)? as usize;
+                        return Ok(ExprStmt(Stmt::KeySlot(KeySlotTree::Remove(index))));
+                    }
+
+                    TokenKind::Restore => {
+                        self.consume(TokenKind::Restore)?;
+                        self.consume(TokenKind::Register)?;
+                        let reg_name = self.parse_opt_identifier()?;
+                        self.consume(TokenKind::From)?;
+                        self.consume(TokenKind::Backup)?;
+                        let timestamp = self.parse_string()?;
+                        return Ok(ExprStmt(Stmt::Backup(BackupTree::Restore {
+                            reg_name,
+                            timestamp,
+                        })));
+                    }
+
+                    TokenKind::Set => {
+                        self.consume(TokenKind::Set)?;
+                        if let Some((_, TokenKind::Autolock)) = self.peek_token() {
+                            self.consume(TokenKind::Autolock)?;
+                            let minutes = self.parse_number(0)?;
+                            self.consume(TokenKind::Minutes)?;
+                            return Ok(ExprStmt(Stmt::SetAutolock { minutes }));
+                        }
+                        self.consume(TokenKind::Backup)?;
+                        self.consume(TokenKind::Retention)?;
+                        let opts = self.parse_retention_opts()?;
+                        return Ok(ExprStmt(Stmt::Backup(BackupTree::SetRetention(opts))));
+                    }
+
+                    TokenKind::Verify => {
+                        self.consume(TokenKind::Verify)?;
+                        if let Some((_, TokenKind::All)) = self.peek_token() {
+                            self.consume(TokenKind::All)?;
+                            return Ok(ExprStmt(Stmt::Verify(VerifyTree::All)));
+                        }
+                        self.consume(TokenKind::Register)?;
+                        let (token, kind) = self
+                            .peek_token()
+                            .ok_or(self.end_of_input(TokenKind::Identifier(String::new())))?;
+                        let TokenKind::Identifier(name) = kind else {
+                            return Err(ParserErr::ExpectedIdentifier {
+                                input: self.query.to_string(),
+                                givenkind: kind,
+                                span: token.span,
+                            });
+                        };
+                        self.consume(TokenKind::Identifier(name.clone()))?;
+                        return Ok(ExprStmt(Stmt::Verify(VerifyTree::Register(name))));
+                    }
+
+                    TokenKind::Status => {
+                        self.consume(TokenKind::Status)?;
+                        return Ok(ExprStmt(Stmt::Status));
+                    }
+
+                    TokenKind::Lock => {
+                        self.consume(TokenKind::Lock)?;
+                        return Ok(ExprStmt(Stmt::Lock));
+                    }
+
+                    TokenKind::Generate => {
+                        self.consume(TokenKind::Generate)?;
+                        self.consume(TokenKind::Keyfile)?;
+                        let path = self.parse_string()?;
+                        return Ok(ExprStmt(Stmt::GenerateKeyfile { path }));
+                    }
+
+                    TokenKind::Export => {
+                        self.consume(TokenKind::Export)?;
+                        self.consume(TokenKind::Entry)?;
+                        let entry_id = self.parse_string()?;
+                        self.consume(TokenKind::To)?;
+                        self.consume(TokenKind::File)?;
+                        let path = self.parse_string()?;
+                        let to = match self.peek_token() {
+                            Some((_, TokenKind::For)) => {
+                                self.consume(TokenKind::For)?;
+                                ShareTo::Member(self.parse_string()?)
+                            }
+                            Some((_, TokenKind::With)) => {
+                                self.consume(TokenKind::With)?;
+                                self.consume(TokenKind::Passphrase)?;
+                                ShareTo::Passphrase
+                            }
+                            Some((token, kind)) => {
+                                return Err(ParserErr::TypeMismatch {
+                                    input: self.query.to_string(),
+                                    expectedkind: vec![TokenKind::For, TokenKind::With],
+                                    givenkind: kind,
+                                    span: token.span,
+                                });
+                            }
+                            None => return Err(self.end_of_input(TokenKind::For)),
+                        };
+                        let mut expires_days = None;
+                        if let Some((_, TokenKind::Expires)) = self.peek_token() {
+                            self.consume(TokenKind::Expires)?;
+                            self.consume(TokenKind::In)?;
+                            expires_days = Some(self.parse_number(1)? as u32);
+                            self.consume(TokenKind::Days)?;
+                        }
+                        return Ok(ExprStmt(Stmt::ExportEntry {
+                            entry_id,
+                            path,
+                            to,
+                            expires_days,
+                        }));
+                    }
+
+                    TokenKind::Import => {
+                        self.consume(TokenKind::Import)?;
+                        self.consume(TokenKind::Shares)?;
+                        let path = self.parse_string()?;
+                        return Ok(ExprStmt(Stmt::ImportShare { path }));
+                    }
                     _ => todo!(),
                 }
             }
@@ -273,4 +517,252 @@ impl<'t> Parser<'t> {
         }
         Ok(ast::Expr::Empty)
     }
+
+    // Optional clauses after CREATE REGISTER <name>, each introduced by WITH.
+    fn parse_create_opts(&mut self) -> Result<CreateOpts, ParserErr> {
+        let mut opts = CreateOpts::default();
+        while let Some((_, TokenKind::With)) = self.peek_token() {
+            self.consume(TokenKind::With)?;
+            match self.peek_token() {
+                Some((_, TokenKind::Keyfile)) => opts.keyfile = Some(self.parse_keyfile()?),
+                Some((_, TokenKind::Recovery)) => {
+                    self.parse_recovery_key()?;
+                    opts.recovery_key = true;
+                    if let Some((_, TokenKind::Kit)) = self.peek_token() {
+                        self.consume(TokenKind::Kit)?;
+                        opts.recovery_kit = Some(self.parse_string()?);
+                    }
+                }
+                Some((_, TokenKind::Cipher)) => {
+                    self.consume(TokenKind::Cipher)?;
+                    opts.cipher = Some(self.parse_string()?);
+                }
+                Some((token, kind)) if kind != TokenKind::Kdf => {
+                    return Err(ParserErr::TypeMismatch {
+                        input: self.query.to_string(),
+                        expectedkind: vec![
+                            TokenKind::Kdf,
+                            TokenKind::Keyfile,
+                            TokenKind::Recovery,
+                            TokenKind::Cipher,
+                        ],
+                        givenkind: kind,
+                        span: token.span,
+                    });
+                }
+                _ => opts.kdf_calibrate_ms = Some(self.parse_kdf_calibrate()?),
+            }
+        }
+        Ok(opts)
+    }
+
+    // KEYFILE '<path>', the WITH has already been consumed.
+    fn parse_keyfile(&mut self) -> Result<String, ParserErr> {
+        self.consume(TokenKind::Keyfile)?;
+        self.parse_string()
+    }
+
+    // RECOVERY KEY, the WITH has already been consumed.
+    fn parse_recovery_key(&mut self) -> Result<(), ParserErr> {
+        self.consume(TokenKind::Recovery)?;
+        self.consume(TokenKind::Key)?;
+        Ok(())
+    }
+
+    // KDF CALIBRATE <n>[s|ms], the WITH has already been consumed.
+    fn parse_kdf_calibrate(&mut self) -> Result<u64, ParserErr> {
+        self.consume(TokenKind::Kdf)?;
+        self.consume(TokenKind::Calibrate)?;
+        self.parse_duration_ms()
+    }
+
+    fn parse_duration_ms(&mut self) -> Result<u64, ParserErr> {
+        let number = self.peek_token().map(|(token, _)| token.span);
+        let n = self.parse_number(1)?;
+
+        // A bare number is read as seconds.
+        let mut factor = 1000;
+        if let Some((unit_token, TokenKind::Identifier(unit))) = self.peek_token() {
+            factor = match unit.to_lowercase().as_str() {
+                "s" => 1000,
+                "ms" => 1,
+                _ => {
+                    return Err(ParserErr::InvalidUnit {
+                        input: self.query.to_string(),
+                        given: unit,
+                        span: unit_token.span,
+                    });
+                }
+            };
+            self.consume(TokenKind::Identifier(unit))?;
+        }
+        n.checked_mul(factor)
+            .ok_or_else(|| ParserErr::DurationOutOfRange {
+                input: self.query.to_string(),
+                span: number.unwrap(),
+            })
+    }
+    // A number literal no smaller than `min`.
+    fn parse_number(&mut self, min: i32) -> Result<u64, ParserErr> {
+        let (token, kind) = self
+            .peek_token()
+            .ok_or(self.end_of_input(TokenKind::Number(0)))?;
+        match kind {
+            TokenKind::Number(n) if n >= min => {
+                self.consume(kind)?;
+                Ok(n as u64)
+            }
+            other => Err(ParserErr::TypeMismatch {
+                input: self.query.to_string(),
+                expectedkind: vec![TokenKind::Number(0)],
+                givenkind: other,
+                span: token.span,
+            }),
+        }
+    }
+
+    fn parse_string(&mut self) -> Result<String, ParserErr> {
+        let (token, kind) = self
+            .peek_token()
+            .ok_or(self.end_of_input(TokenKind::String(String::new())))?;
+        match kind {
+            TokenKind::String(value) => {
+                self.consume(TokenKind::String(value.clone()))?;
+                Ok(value)
+            }
+            other => Err(ParserErr::TypeMismatch {
+                input: self.query.to_string(),
+                expectedkind: vec![TokenKind::String(String::new())],
+                givenkind: other,
+                span: token.span,
+            }),
+        }
+    }
+
+    // Register names that may be left out, e.g. SHOW BACKUPS [<name>].
+    fn parse_opt_identifier(&mut self) -> Result<Option<String>, ParserErr> {
+        match self.peek_token() {
+            Some((_, TokenKind::Identifier(name))) => {
+                self.consume(TokenKind::Identifier(name.clone()))?;
+                Ok(Some(name))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn end_of_input(&self, tokind: TokenKind) -> ParserErr {
+        ParserErr::UnexpectedEndOfExpression {
+            input: self.query.to_string(),
+            tokind,
+            span: Span {
+                start: self.query.len(),
+                end: self.query.len() + 1,
+            },
+        }
+    }
+
+    // [LAST n] [DAILY n] [WEEKLY n], in any order but at least one of them.
+    fn parse_retention_opts(&mut self) -> Result<RetentionOpts, ParserErr> {
+        let mut opts = RetentionOpts::default();
+        loop {
+            match self.peek_token() {
+                Some((_, TokenKind::Last)) => {
+                    self.consume(TokenKind::Last)?;
+                    opts.last = Some(self.parse_number(0)? as usize);
+                }
+                Some((_, TokenKind::Daily)) => {
+                    self.consume(TokenKind::Daily)?;
+                    opts.daily = Some(self.parse_number(0)? as usize);
+                }
+                Some((_, TokenKind::Weekly)) => {
+                    self.consume(TokenKind::Weekly)?;
+                    opts.weekly = Some(self.parse_number(0)? as usize);
+                }
+                Some((token, kind))
+                    if opts.last.is_none() && opts.daily.is_none() && opts.weekly.is_none() =>
+                {
+                    return Err(ParserErr::TypeMismatch {
+                        input: self.query.to_string(),
+                        expectedkind: vec![TokenKind::Last, TokenKind::Daily, TokenKind::Weekly],
+                        givenkind: kind,
+                        span: token.span,
+                    });
+                }
+                None if opts.last.is_none() && opts.daily.is_none() && opts.weekly.is_none() => {
+                    return Err(self.end_of_input(TokenKind::Last));
+                }
+                _ => return Ok(opts),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::lexer::Lexer;
+
+    fn parse(input: &str) -> Result<ast::Stmt, ParserErr> {
+        Parser::parse(Lexer::tokenize(input).unwrap())
+    }
+
+    #[test]
+    fn test_alter_register_set_password() {
+        assert!(matches!(
+            parse("ALTER REGISTER SET PASSWORD PROMPT;"),
+            Ok(Stmt::AlterTree(AlterTree::SetPassword))
+        ));
+        // The password is never typed into the statement.
+        assert!(parse("ALTER REGISTER SET PASSWORD 'hunter22';").is_err());
+        assert!(parse("ALTER REGISTER SET PASSWORD;").is_err());
+    }
+
+    #[test]
+    fn test_connect_options_in_any_order() {
+        for input in [
+            "CONNECT personal WITH RECOVERY KEY READ ONLY;",
+            "CONNECT personal READ ONLY WITH RECOVERY KEY;",
+        ] {
+            assert!(
+                matches!(
+                    parse(input),
+                    Ok(Stmt::Connect {
+                        read_only: true,
+                        recovery: true,
+                        ..
+                    })
+                ),
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_kdf_calibrate_durations() {
+        for (input, ms) in [("2s", 2000), ("500ms", 500), ("500 MS", 500), ("3", 3000)] {
+            let stmt = parse(&format!("REKEY WITH KDF CALIBRATE {};", input));
+            assert!(
+                matches!(stmt, Ok(Stmt::Rekey { kdf_calibrate_ms: Some(n) }) if n == ms),
+                "{}",
+                input
+            );
+        }
+        assert!(parse("REKEY;").is_ok());
+
+        assert!(matches!(
+            parse("REKEY WITH KDF CALIBRATE 5min;"),
+            Err(ParserErr::InvalidUnit { .. })
+        ));
+        assert!(parse("REKEY WITH KDF CALIBRATE 0s;").is_err());
+        assert!(parse("REKEY WITH KDF CALIBRATE -1s;").is_err());
+        assert!(parse("REKEY WITH KDF CALIBRATE s;").is_err());
+        // Too large for a number literal, it never reaches the multiplication.
+        assert!(Lexer::tokenize("REKEY WITH KDF CALIBRATE 99999999999999999999s;").is_err());
+        let largest = parse(&format!("REKEY WITH KDF CALIBRATE {}s;", i32::MAX));
+        assert!(matches!(
+            largest,
+            Ok(Stmt::Rekey { kdf_calibrate_ms: Some(n) }) if n == i32::MAX as u64 * 1000
+        ));
+    }
 }
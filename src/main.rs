@@ -2,6 +2,8 @@
 use chrono::{Local, Utc};
 use rustyline::DefaultEditor;
 use rustyline::error::ReadlineError;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
 use std::time::Instant;
 mod encryption;
 mod engine;
@@ -17,11 +19,33 @@ use crate::engine::Executor;
 use dirs_next;
 use hex;
 use session::session_conn::SessionConn;
+use storage::config::Config;
+use storage::init;
 use zeroize;
 
+const USAGE: &str = "Usage: pwmn [--root <path>]\n       pwmn keygen";
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // Before anything is decrypted, `pwmn keygen` included.
+    for measure in session::harden::harden().measures() {
+        if !measure.is_on() {
+            eprintln!("Warning: {}", measure);
+        }
+    }
+    if let Err(e) = parse_args() {
+        eprintln!("{}", e);
+        std::process::exit(2);
+    }
     let mut rl = DefaultEditor::new()?;
-    let mut session_status = SessionConn::new()?;
+    let session_status = Arc::new(Mutex::new(SessionConn::new()?));
+    // A config that doesn't load leaves autolock off; the statements that need it report why.
+    if let Ok(config) = init::root_path().and_then(|root| Ok(Config::load(&root)?)) {
+        session_status
+            .lock()
+            .unwrap()
+            .set_autolock(config.autolock_minutes);
+    }
+    statements::autolock::AutolockExec::watch(Arc::clone(&session_status));
     let arr = ["hamza", "something"];
     let result = arr
         .iter()
@@ -33,12 +57,15 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         let command = match read_command(&mut rl) {
             Ok(cmd) => {
                 let start = Instant::now();
-                match Executor::execute(&cmd, &mut session_status) {
+                let mut session = session_status.lock().unwrap();
+                match Executor::execute(&cmd, &mut session) {
                     Ok(_) => {}
                     Err(e) => {
                         println!("\nERROR: {}", e);
                     }
                 }
+                session.touch();
+                drop(session);
                 let end = start.elapsed();
                 println!("\nTime elapsed: {:.2} s", end.as_secs_f64());
                 cmd
@@ -71,6 +98,38 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+// --root <path> picks the root vault for this run, ahead of PWMN_HOME and ~/.pwmn.
+fn parse_args() -> Result<(), Box<dyn std::error::Error>> {
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--root" => {
+                let p = args
+                    .next()
+                    .ok_or(format!("--root expects a path\n{}", USAGE))?;
+                init::set_root_override(PathBuf::from(p))?;
+            }
+            other if other.starts_with("--root=") => {
+                init::set_root_override(PathBuf::from(&other["--root=".len()..]))?;
+            }
+            // Creates the identity that team registers are granted to, then exits.
+            "keygen" => {
+                if let Err(e) = statements::keygen::KeygenExec::execute() {
+                    eprintln!("ERROR: {}", e);
+                    std::process::exit(1);
+                }
+                std::process::exit(0);
+            }
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                std::process::exit(0);
+            }
+            other => return Err(format!("Unknown argument '{}'\n{}", other, USAGE).into()),
+        }
+    }
+    Ok(())
+}
+
 fn read_command(rl: &mut DefaultEditor) -> Result<String, ReadlineError> {
     let mut buffer = String::new();
     let mut line_number = 0;
@@ -100,7 +159,7 @@ fn read_command(rl: &mut DefaultEditor) -> Result<String, ReadlineError> {
 
         if line_number == 1 {
             let upper = buffer.trim().to_uppercase();
-            if matches!(upper.as_str(), "QUIT" | "EXIT" | "HELP" | "STATUS") {
+            if matches!(upper.as_str(), "QUIT" | "EXIT" | "HELP" | "STATUS" | "LOCK") {
                 rl.add_history_entry(&buffer)?;
                 return Ok(buffer);
             }
@@ -0,0 +1,199 @@
+use crate::encryption::protected;
+use std::io;
+use std::sync::OnceLock;
+
+// Keeps the decrypted register out of crash dumps and swap: no core files, no
+// ptrace or gcore by other processes of the same user, the whole process pinned to
+// RAM where that is allowed, and at least the page holding the in-memory session
+// key. Applied once at startup, a measure that fails is warned about and shown by
+// STATUS.
+pub struct Hardening {
+    pub no_core_dumps: Measure,
+    pub not_dumpable: Measure,
+    pub memory_locked: Measure,
+    pub key_memory_locked: Measure,
+}
+
+pub struct Measure {
+    what: &'static str,
+    on: &'static str,
+    off: &'static str,
+    pub outcome: Result<(), String>,
+}
+
+impl Measure {
+    pub fn is_on(&self) -> bool {
+        self.outcome.is_ok()
+    }
+}
+
+impl std::fmt::Display for Measure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.outcome {
+            Ok(()) => write!(f, "{}: {}", self.what, self.on),
+            Err(e) => write!(f, "{}: {} ({})", self.what, self.off, e),
+        }
+    }
+}
+
+static APPLIED: OnceLock<Hardening> = OnceLock::new();
+
+pub fn harden() -> &'static Hardening {
+    APPLIED.get_or_init(|| Hardening {
+        no_core_dumps: Measure {
+            what: "Core dumps",
+            on: "disabled",
+            off: "still enabled",
+            outcome: disable_core_dumps(),
+        },
+        not_dumpable: Measure {
+            what: "Dumpable",
+            on: "no",
+            off: "yes",
+            outcome: set_not_dumpable(),
+        },
+        memory_locked: Measure {
+            what: "Process memory",
+            on: "locked",
+            off: "not locked, only the session key is",
+            outcome: lock_all_memory(),
+        },
+        key_memory_locked: Measure {
+            what: "Key memory",
+            on: "locked",
+            off: "not locked",
+            outcome: lock_key_memory(),
+        },
+    })
+}
+
+// None until `harden` ran.
+pub fn applied() -> Option<&'static Hardening> {
+    APPLIED.get()
+}
+
+impl Hardening {
+    pub fn measures(&self) -> [&Measure; 4] {
+        [
+            &self.no_core_dumps,
+            &self.not_dumpable,
+            &self.memory_locked,
+            &self.key_memory_locked,
+        ]
+    }
+}
+
+fn disable_core_dumps() -> Result<(), String> {
+    // Hard limit too, so nothing later in the process can raise it again.
+    let limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    check(unsafe { libc::setrlimit(libc::RLIMIT_CORE, &limit) })
+}
+
+#[cfg(target_os = "linux")]
+fn set_not_dumpable() -> Result<(), String> {
+    check(unsafe { libc::prctl(libc::PR_SET_DUMPABLE, 0, 0, 0, 0) })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_not_dumpable() -> Result<(), String> {
+    Err("not supported on this platform".to_string())
+}
+
+// Every page of the process, now and later. With later pages locked too, any
+// allocation past RLIMIT_MEMLOCK fails, Argon2's included, so this is only done
+// when the limit doesn't apply: it is unlimited, or the process has CAP_IPC_LOCK.
+#[cfg(target_os = "linux")]
+fn lock_all_memory() -> Result<(), String> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    check(unsafe { libc::getrlimit(libc::RLIMIT_MEMLOCK, &mut limit) })?;
+    if limit.rlim_cur != libc::RLIM_INFINITY && !has_ipc_lock() {
+        return Err(format!("RLIMIT_MEMLOCK is {} KiB", limit.rlim_cur / 1024));
+    }
+    check(unsafe { libc::mlockall(libc::MCL_CURRENT | libc::MCL_FUTURE) })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn lock_all_memory() -> Result<(), String> {
+    Err("not supported on this platform".to_string())
+}
+
+// CAP_IPC_LOCK in the effective set, read from /proc/self/status.
+#[cfg(target_os = "linux")]
+fn has_ipc_lock() -> bool {
+    const CAP_IPC_LOCK: u32 = 14;
+    std::fs::read_to_string("/proc/self/status")
+        .ok()
+        .and_then(|status| {
+            let caps = status.lines().find_map(|l| l.strip_prefix("CapEff:"))?;
+            u64::from_str_radix(caps.trim(), 16).ok()
+        })
+        .is_some_and(|caps| caps & (1 << CAP_IPC_LOCK) != 0)
+}
+
+// The fallback when the whole process can't be locked. The session key is a
+// static, so this locks the page it sits in for good, through
+// every rotation. Under a RLIMIT_MEMLOCK of 0 it fails with EPERM or ENOMEM.
+fn lock_key_memory() -> Result<(), String> {
+    let key = &protected::SESSION_KEY;
+    check(unsafe { libc::mlock(std::ptr::from_ref(key).cast(), size_of_val(key)) })
+}
+
+fn check(ret: libc::c_int) -> Result<(), String> {
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error().to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn proc_field(file: &str, name: &str) -> String {
+        fs::read_to_string(file)
+            .unwrap()
+            .lines()
+            .find(|line| line.starts_with(name))
+            .unwrap()
+            .to_string()
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_hardening_shows_in_proc() {
+        let hardening = harden();
+        assert!(applied().is_some());
+
+        assert!(hardening.no_core_dumps.is_on());
+        let core = proc_field("/proc/self/limits", "Max core file size");
+        assert_eq!(core.split_whitespace().nth(4), Some("0"));
+        assert_eq!(core.split_whitespace().nth(5), Some("0"));
+
+        assert!(hardening.not_dumpable.is_on());
+        assert_eq!(unsafe { libc::prctl(libc::PR_GET_DUMPABLE) }, 0);
+
+        // A sandbox may forbid locking memory, then it has to say so.
+        let locked_kb: u64 = proc_field("/proc/self/status", "VmLck:")
+            .split_whitespace()
+            .nth(1)
+            .unwrap()
+            .parse()
+            .unwrap();
+        match &hardening.key_memory_locked.outcome {
+            Ok(()) => assert!(locked_kb > 0),
+            Err(e) => assert!(!e.is_empty()),
+        }
+        // Locking everything covers far more than the key's page.
+        if hardening.memory_locked.is_on() {
+            assert!(locked_kb > 64, "{} KiB locked", locked_kb);
+        }
+    }
+}
@@ -0,0 +1,108 @@
+use crate::error::SessionErr;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+pub const LOCK_N: &str = "pwmn.lock";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockMode {
+    // READ ONLY connects, any number of them may hold the register together.
+    Shared,
+    // Writers, they hold the register alone.
+    Exclusive,
+}
+
+// Advisory flock on a `pwmn.lock` file inside the locked folder, released when dropped.
+// The file records the PID of the latest holder so a blocked session can name it.
+#[derive(Debug)]
+pub struct DirLock {
+    file: File,
+    mode: LockMode,
+    path: PathBuf,
+}
+
+impl DirLock {
+    // `target` names what is being locked in the error shown to a blocked session.
+    pub fn acquire(
+        dir: &Path,
+        mode: LockMode,
+        target: &str,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = dir.join(LOCK_N);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+
+        let locked = match mode {
+            LockMode::Shared => file.try_lock_shared(),
+            LockMode::Exclusive => file.try_lock(),
+        };
+        match locked {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                return Err(Box::new(SessionErr::Locked {
+                    target: target.to_string(),
+                    pid: DirLock::read_pid(&mut file),
+                }));
+            }
+            Err(TryLockError::Error(e)) => return Err(Box::new(e)),
+        }
+
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        write!(file, "{}", std::process::id())?;
+        file.sync_all()?;
+        Ok(Self { file, mode, path })
+    }
+
+    pub fn mode(&self) -> LockMode {
+        self.mode
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    // The flock follows the open file, so a lock survives its folder being renamed;
+    // only the recorded path needs updating.
+    pub fn moved_to(&mut self, dir: &Path) {
+        self.path = dir.join(LOCK_N);
+    }
+
+    fn read_pid(file: &mut File) -> Option<u32> {
+        let mut content = String::new();
+        file.read_to_string(&mut content).ok()?;
+        content.trim().parse().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lock_modes_conflict() {
+        let dir = std::env::temp_dir().join(format!("pwmn-lock-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let reader = DirLock::acquire(&dir, LockMode::Shared, "Register").unwrap();
+        let second = DirLock::acquire(&dir, LockMode::Shared, "Register").unwrap();
+        let err = DirLock::acquire(&dir, LockMode::Exclusive, "Register").unwrap_err();
+        let err = err.downcast::<SessionErr>().unwrap();
+        assert!(
+            matches!(*err, SessionErr::Locked { pid: Some(pid), .. } if pid == std::process::id())
+        );
+        drop((reader, second));
+
+        let writer = DirLock::acquire(&dir, LockMode::Exclusive, "Register").unwrap();
+        assert!(DirLock::acquire(&dir, LockMode::Shared, "Register").is_err());
+        drop(writer);
+        assert!(DirLock::acquire(&dir, LockMode::Shared, "Register").is_ok());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
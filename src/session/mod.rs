@@ -1,2 +1,4 @@
+pub mod harden;
+pub mod lock;
 pub mod session_conn;
-pub use session_conn::SessionConn;
+pub use session_conn::{SessionConn, UnlockMethod};
@@ -1,43 +1,169 @@
-use crate::error::HomeDirErr;
+use super::lock::{DirLock, LockMode};
+use crate::error::SessionErr;
 use crate::{
     error,
-    storage::{init::ROOT_REG, types::Register},
+    storage::{init, types::Register},
 };
 use bincode::error as bin_err;
 use std::{
     env,
     path::{Path, PathBuf},
+    time::{Duration, Instant},
 };
 
+// How the connected register was opened; a locked register is resumed the same way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnlockMethod {
+    Password,
+    RecoveryKey,
+    Shares,
+    Identity,
+}
+
 pub struct SessionConn {
     // May be connected, may not.
     current_connected_register: Option<Register>,
     // Path to the current active vault.
     base_path: PathBuf,
+    // The ROOT folder, base_path falls back to it once the register is disconnected.
+    root_path: PathBuf,
+    // Held for as long as the register is connected, shared for READ ONLY sessions.
+    lock: Option<DirLock>,
+    // The keyfile the register was unlocked with, statements that derive the key again reuse it.
+    keyfile: Option<PathBuf>,
+    // Set while connected.
+    unlock: Option<UnlockMethod>,
+    // Name of the register while it is locked, see `lock`.
+    locked: Option<String>,
+    // Idle time after which the register is locked, None never locks.
+    autolock: Option<Duration>,
+    // End of the last statement.
+    last_active: Instant,
 }
 
 impl SessionConn {
     pub fn new() -> Result<Self, Box<dyn std::error::Error>> {
-        let home_dir = dirs_next::home_dir().ok_or(HomeDirErr::InvalidHomeDir)?;
+        let root = init::root_path()?;
         Ok(Self {
             current_connected_register: None,
             // No connection yet! Wrap the ROOT folder until we establish a connection.
-            base_path: home_dir.join(ROOT_REG),
+            base_path: root.clone(),
+            root_path: root,
+            lock: None,
+            keyfile: None,
+            unlock: None,
+            locked: None,
+            autolock: None,
+            last_active: Instant::now(),
         })
     }
 
-    pub fn connect_to(&mut self, register: Register) {
+    pub fn connect_to(
+        &mut self,
+        register: Register,
+        reg_path: PathBuf,
+        lock: DirLock,
+        keyfile: Option<PathBuf>,
+        unlock: UnlockMethod,
+    ) {
         self.current_connected_register = Some(register);
+        self.base_path = reg_path;
+        self.lock = Some(lock);
+        self.keyfile = keyfile;
+        self.unlock = Some(unlock);
+        self.locked = None;
     }
 
-    pub fn is_connected(&self) -> bool {
+    // Swaps in a register that was written to disk behind the session, e.g. by RESTORE,
+    // or brings back a locked one.
+    pub fn reload(&mut self, register: Register) {
+        self.current_connected_register = Some(register);
+        self.locked = None;
+    }
+
+    // Wipes the register from memory but stays connected: the register lock, path and
+    // keyfile are kept and the next statement on the register unlocks it again. There
+    // are no transactions: every statement saves its changes before it returns, so
+    // there is nothing unsaved to keep. Should statements ever stage changes in memory,
+    // those have to be kept encrypted across a lock rather than dropped here.
+    pub fn lock(&mut self) -> Result<(), SessionErr> {
+        let Some(reg) = self.current_connected_register.take() else {
+            return Err(SessionErr::SessionNotConnected);
+        };
+        self.locked = Some(reg.r_name.clone());
+        Ok(())
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.is_some()
+    }
+
+    pub fn set_autolock(&mut self, minutes: u64) {
+        self.autolock = (minutes > 0).then(|| Duration::from_secs(minutes * 60));
+    }
+
+    pub fn get_autolock(&self) -> Option<Duration> {
+        self.autolock
+    }
+
+    pub fn touch(&mut self) {
+        self.last_active = Instant::now();
+    }
+
+    // The register is in memory and has been idle for longer than the autolock.
+    pub fn autolock_due(&self) -> bool {
         self.current_connected_register.is_some()
+            && self
+                .autolock
+                .is_some_and(|idle| self.last_active.elapsed() >= idle)
+    }
+
+    pub fn get_base_path(&self) -> &PathBuf {
+        &self.base_path
+    }
+
+    pub fn get_root_path(&self) -> &PathBuf {
+        &self.root_path
+    }
+
+    pub fn get_lock(&self) -> Option<&DirLock> {
+        self.lock.as_ref()
+    }
+
+    pub fn get_keyfile(&self) -> Option<&Path> {
+        self.keyfile.as_deref()
+    }
+
+    pub fn get_unlock_method(&self) -> Option<UnlockMethod> {
+        self.unlock
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.lock
+            .as_ref()
+            .is_some_and(|lock| lock.mode() == LockMode::Shared)
+    }
+
+    // Statements that save the register go through here first.
+    pub fn ensure_writable(&self) -> Result<(), SessionErr> {
+        if !self.is_connected() {
+            return Err(SessionErr::SessionNotConnected);
+        }
+        if self.is_read_only() {
+            return Err(SessionErr::PermissionDenied);
+        }
+        Ok(())
+    }
+
+    // A locked register is still connected, only its contents are gone.
+    pub fn is_connected(&self) -> bool {
+        self.current_connected_register.is_some() || self.is_locked()
     }
 
     pub fn get_connected_reg_name(&self) -> Option<&str> {
         match self.current_connected_register.as_ref() {
             Some(reg) => Some(reg.r_name.as_str()),
-            None => None,
+            None => self.locked.as_deref(),
         }
     }
 
@@ -57,7 +183,146 @@ impl SessionConn {
         }
     }
 
+    // Dropping the register wipes the passwords it holds.
     pub fn disconnect_from(&mut self) {
         self.current_connected_register = None;
+        self.base_path = self.root_path.clone();
+        // Dropping the lock file handle releases the flock.
+        self.lock = None;
+        self.keyfile = None;
+        self.unlock = None;
+        self.locked = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // `new` looks up the root through init, a test session is built around a temp dir.
+    fn temp_session() -> (SessionConn, PathBuf) {
+        let root = env::temp_dir().join(format!("pwmn-session-{}", uuid::Uuid::new_v4()));
+        let reg_dir = root.join(".register");
+        std::fs::create_dir_all(&reg_dir).unwrap();
+        let session = SessionConn {
+            current_connected_register: None,
+            base_path: root.clone(),
+            root_path: root,
+            lock: None,
+            keyfile: None,
+            unlock: None,
+            locked: None,
+            autolock: None,
+            last_active: Instant::now(),
+        };
+        (session, reg_dir)
+    }
+
+    fn connect(session: &mut SessionConn, reg_dir: &Path, mode: LockMode) {
+        let lock = DirLock::acquire(reg_dir, mode, "Register").unwrap();
+        session.connect_to(
+            Register::new("personal"),
+            reg_dir.to_path_buf(),
+            lock,
+            None,
+            UnlockMethod::Shares,
+        );
+    }
+
+    #[test]
+    fn test_lock_keeps_the_connection_but_not_the_register() {
+        let (mut session, reg_dir) = temp_session();
+        assert!(!session.is_connected());
+        assert!(matches!(
+            session.lock(),
+            Err(SessionErr::SessionNotConnected)
+        ));
+
+        connect(&mut session, &reg_dir, LockMode::Exclusive);
+        assert!(session.is_connected() && !session.is_locked());
+        session.lock().unwrap();
+        assert!(session.is_locked());
+        assert!(session.is_connected());
+        assert_eq!(session.get_connected_reg_name(), Some("personal"));
+        assert!(session.get_reg_as_immt().is_err());
+        assert_eq!(session.get_base_path(), &reg_dir);
+        assert_eq!(session.get_unlock_method(), Some(UnlockMethod::Shares));
+        // Nobody else gets the register while it is locked.
+        assert!(DirLock::acquire(&reg_dir, LockMode::Shared, "Register").is_err());
+        assert!(matches!(
+            session.lock(),
+            Err(SessionErr::SessionNotConnected)
+        ));
+
+        session.reload(Register::new("personal"));
+        assert!(!session.is_locked());
+        assert_eq!(session.get_reg_as_immt().unwrap().r_name, "personal");
+
+        session.lock().unwrap();
+        session.disconnect_from();
+        assert!(!session.is_connected() && !session.is_locked());
+        assert_eq!(session.get_connected_reg_name(), None);
+        assert_eq!(session.get_unlock_method(), None);
+        assert_eq!(session.get_base_path(), session.get_root_path());
+        assert!(DirLock::acquire(&reg_dir, LockMode::Exclusive, "Register").is_ok());
+
+        std::fs::remove_dir_all(session.get_root_path()).unwrap();
+    }
+
+    #[test]
+    fn test_autolock_is_due_once_idle_long_enough() {
+        let (mut session, reg_dir) = temp_session();
+        let idle = |session: &mut SessionConn, secs| {
+            session.last_active = Instant::now() - Duration::from_secs(secs);
+        };
+        connect(&mut session, &reg_dir, LockMode::Exclusive);
+
+        // Off unless set, and 0 turns it off again.
+        idle(&mut session, 300);
+        assert!(!session.autolock_due());
+        session.set_autolock(0);
+        assert_eq!(session.get_autolock(), None);
+        assert!(!session.autolock_due());
+
+        session.set_autolock(2);
+        assert_eq!(session.get_autolock(), Some(Duration::from_secs(120)));
+        idle(&mut session, 60);
+        assert!(!session.autolock_due());
+        idle(&mut session, 120);
+        assert!(session.autolock_due());
+        session.touch();
+        assert!(!session.autolock_due());
+
+        // A locked register has nothing left to lock.
+        idle(&mut session, 120);
+        session.lock().unwrap();
+        assert!(!session.autolock_due());
+
+        session.disconnect_from();
+        std::fs::remove_dir_all(session.get_root_path()).unwrap();
+    }
+
+    #[test]
+    fn test_read_only_sessions_are_not_writable() {
+        let (mut session, reg_dir) = temp_session();
+        assert!(matches!(
+            session.ensure_writable(),
+            Err(SessionErr::SessionNotConnected)
+        ));
+
+        connect(&mut session, &reg_dir, LockMode::Shared);
+        assert!(session.is_read_only());
+        assert!(matches!(
+            session.ensure_writable(),
+            Err(SessionErr::PermissionDenied)
+        ));
+        session.disconnect_from();
+
+        connect(&mut session, &reg_dir, LockMode::Exclusive);
+        assert!(!session.is_read_only());
+        assert!(session.ensure_writable().is_ok());
+
+        session.disconnect_from();
+        std::fs::remove_dir_all(session.get_root_path()).unwrap();
     }
 }
@@ -0,0 +1,256 @@
+use super::connect::VaultConnection;
+use super::stmt_utl::confirm;
+use crate::encryption::identity;
+use crate::error::{IdentityErr, KeySlotErr};
+use crate::interpreter::ast::AccessTree;
+use crate::session::SessionConn;
+use crate::storage::throttle;
+use crate::storage::vault::{KeySlot, MAX_KEY_SLOTS, SlotKind, VAULT_N, Vault};
+use crate::storage::vaultmanager::VaultManager;
+use crate::storage::vaultmod::VaultMod;
+use std::fs;
+use std::path::PathBuf;
+use zeroize::{Zeroize, Zeroizing};
+
+type DynError = Box<dyn std::error::Error>;
+
+// What opened the register: the data key, the slot it came from, and the password
+// when there was one, so REVOKE can wrap the new key under it.
+pub struct Unlocked {
+    pub plaintext: Zeroizing<Vec<u8>>,
+    pub data_key: [u8; 32],
+    pub slot: usize,
+    pub password: Option<String>,
+}
+
+impl Drop for Unlocked {
+    fn drop(&mut self) {
+        self.data_key.zeroize();
+        if let Some(password) = self.password.as_mut() {
+            password.zeroize();
+        }
+    }
+}
+
+pub struct AccessExec;
+
+impl AccessExec {
+    pub fn execute(access: AccessTree, session: &SessionConn) -> Result<(), DynError> {
+        match access {
+            AccessTree::Grant(public) => AccessExec::grant(&public, session),
+            AccessTree::Revoke(public) => AccessExec::revoke(&public, session),
+        }
+    }
+
+    // A new key slot wraps the data key to the member's public key, the member opens
+    // it with CONNECT ... WITH IDENTITY. No secret of theirs is needed here.
+    pub fn grant(public: &str, session: &SessionConn) -> Result<(), DynError> {
+        session.ensure_writable()?;
+        let member = identity::decode_public(public)?;
+        let encoded = identity::encode_public(&member);
+
+        let (mut vault, mut header) = AccessExec::load(session)?;
+        if let Some(index) = header.member_slot(&member) {
+            return Err(Box::new(IdentityErr::AlreadyGranted {
+                public: encoded,
+                index,
+            }));
+        }
+        if header.slots.len() >= MAX_KEY_SLOTS {
+            return Err(Box::new(KeySlotErr::Full { max: MAX_KEY_SLOTS }));
+        }
+
+        let unlocked = AccessExec::unlock(&vault, &header, session)?;
+        header
+            .slots
+            .push(header.recipient_slot(&member, &unlocked.data_key)?);
+        let index = header.slots.len() - 1;
+
+        vault.rewrap(header)?;
+        println!("Granted access to {} in key slot {}", encoded, index);
+        Ok(())
+    }
+
+    // Dropping the member's slot isn't enough, whoever held it may have kept the data
+    // key. The register moves to a new data key, wrapped again for the members that
+    // stay and for the password that unlocked it. Recovery, shares and other password
+    // slots can't be wrapped again without their secrets, they are dropped once the
+    // user agrees. Backups under the old data key move to the new one; older copies
+    // that can't move but still hold the member's slot are deleted once the user agrees.
+    pub fn revoke(public: &str, session: &SessionConn) -> Result<(), DynError> {
+        session.ensure_writable()?;
+        let member = identity::decode_public(public)?;
+        let encoded = identity::encode_public(&member);
+
+        let (mut vault, header) = AccessExec::load(session)?;
+        let revoked = header
+            .member_slot(&member)
+            .ok_or_else(|| IdentityErr::NotMember {
+                public: encoded.clone(),
+            })?;
+
+        let unlocked = AccessExec::unlock(&vault, &header, session)?;
+        if unlocked.slot == revoked {
+            return Err(Box::new(IdentityErr::RevokeSelf));
+        }
+        // The password slots would all go, and the register opens with a password.
+        if unlocked.password.is_none() && header.password_slots().next().is_some() {
+            return Err(Box::new(IdentityErr::PasswordNeeded));
+        }
+        let dropped: Vec<usize> = (0..header.slots.len())
+            .filter(|&i| {
+                i != unlocked.slot && i != revoked && header.slots[i].kind != SlotKind::Recipient
+            })
+            .collect();
+        if !dropped.is_empty() {
+            println!("These key slots can't follow the new data key and will be removed:");
+            for &i in &dropped {
+                println!("  {} ({})", i, header.slots[i].kind);
+            }
+            if !confirm("Revoke access anyway?")? {
+                println!("Left unchanged");
+                return Ok(());
+            }
+        }
+        let exposed = AccessExec::exposed_copies(&vault, &unlocked.data_key, &member)?;
+        if !exposed.is_empty() {
+            println!(
+                "These older copies still open with the member's identity and can't follow the new data key, they will be deleted:"
+            );
+            for p in &exposed {
+                println!("  {}", p.display());
+            }
+            if !confirm("Delete them and revoke access?")? {
+                println!("Left unchanged");
+                return Ok(());
+            }
+        }
+
+        let new_key = Zeroizing::new(Vault::generate_data_key());
+        let rebuilt = AccessExec::rebuild(&header, &unlocked, revoked, &new_key, session)?;
+        let mut rotated = header.clone();
+        rotated.slots = rebuilt.clone();
+        vault.seal_with(rotated, *new_key, unlocked.plaintext.to_vec())?;
+        // The copy of the register as it was is among the backups by now.
+        let resealed = vault.reseal_backups(unlocked.data_key, *new_key, &rebuilt)?;
+        for p in &exposed {
+            fs::remove_file(p)?;
+        }
+
+        println!(
+            "Revoked access for {} and moved the register to a new data key",
+            encoded
+        );
+        if resealed > 0 {
+            println!("Moved {} backup(s) to the new data key", resealed);
+        }
+        if !exposed.is_empty() {
+            println!(
+                "Deleted {} older file(s) that still let the member in",
+                exposed.len()
+            );
+        }
+        Ok(())
+    }
+
+    // Older copies of the register that hold the member's slot and don't open with
+    // the current data key, so `reseal_backups` can't move them.
+    fn exposed_copies(
+        vault: &VaultMod,
+        data_key: &[u8; 32],
+        member: &[u8; 32],
+    ) -> Result<Vec<PathBuf>, DynError> {
+        let mut exposed = vec![];
+        for p in vault.older_copies()? {
+            let bytes = fs::read(&p)?;
+            let Ok((header, _)) = Vault::from_bytes(&bytes) else {
+                continue;
+            };
+            let resealable = !header.is_outdated() && vault.open(*data_key, &bytes).is_ok();
+            if header.member_slot(member).is_some() && !resealable {
+                exposed.push(p);
+            }
+        }
+        Ok(exposed)
+    }
+
+    // The slots that stay, in their order, wrapping `new_key`.
+    fn rebuild(
+        header: &Vault,
+        unlocked: &Unlocked,
+        revoked: usize,
+        new_key: &[u8; 32],
+        session: &SessionConn,
+    ) -> Result<Vec<KeySlot>, DynError> {
+        let mut slots = vec![];
+        for (i, slot) in header.slots.iter().enumerate() {
+            if i == revoked {
+                continue;
+            }
+            if let Some(recipient) = &slot.recipient {
+                slots.push(header.recipient_slot(&recipient.member, new_key)?);
+            } else if i == unlocked.slot {
+                let password = unlocked.password.as_deref().unwrap();
+                let keyfile = session
+                    .get_keyfile()
+                    .filter(|_| header.slot_uses_keyfile(slot));
+                slots.push(header.password_slot(password, keyfile, slot.kdf, new_key)?);
+            }
+        }
+        Ok(slots)
+    }
+
+    pub fn load(session: &SessionConn) -> Result<(VaultMod, Vault), DynError> {
+        let manager = VaultManager::load()?;
+        let mut vault = manager.external_vault_load(session.get_base_path())?;
+        vault.validate_f_header()?;
+        let header = vault.load_header()?;
+        Ok((vault, header))
+    }
+
+    // A current password, or the member's own identity when the answer is left empty.
+    // Wrong passwords count against the register like they do on CONNECT.
+    pub fn unlock(
+        vault: &VaultMod,
+        header: &Vault,
+        session: &SessionConn,
+    ) -> Result<Unlocked, DynError> {
+        throttle::attempt(&vault.p, || AccessExec::unlock_once(vault, header, session))
+    }
+
+    fn unlock_once(
+        vault: &VaultMod,
+        header: &Vault,
+        session: &SessionConn,
+    ) -> Result<Unlocked, DynError> {
+        let mut password = rpassword::prompt_password(
+            "Enter a current password (leave empty to use your identity): ",
+        )?;
+        if !password.is_empty() {
+            let opened = VaultConnection::unlock_slot(vault, &password, session.get_keyfile());
+            let (plaintext, data_key, slot) = match opened {
+                Ok(opened) => opened,
+                Err(e) => {
+                    password.zeroize();
+                    return Err(e);
+                }
+            };
+            return Ok(Unlocked {
+                plaintext,
+                data_key,
+                slot,
+                password: Some(password),
+            });
+        }
+
+        let identity = VaultConnection::load_identity(header)?;
+        let (data_key, slot) = header.unlock_identity(&identity)?;
+        let bytes = fs::read(vault.p.join(VAULT_N))?;
+        Ok(Unlocked {
+            plaintext: vault.open(data_key, &bytes)?,
+            data_key,
+            slot,
+            password: None,
+        })
+    }
+}
@@ -0,0 +1,118 @@
+use super::connect::VaultConnection;
+use crate::error::{AlterErr, CreateErr, KeySlotErr};
+use crate::interpreter::ast::AlterTree;
+use crate::session::SessionConn;
+use crate::storage::throttle;
+use crate::storage::vault::MAX_KEY_SLOTS;
+use crate::storage::vaultmanager::VaultManager;
+use crate::storage::vaultmod::VaultMod;
+use zeroize::Zeroize;
+
+type DynError = Box<dyn std::error::Error>;
+
+pub struct AlterRegExec;
+
+impl AlterRegExec {
+    pub fn execute(alter: AlterTree, session: &SessionConn) -> Result<(), DynError> {
+        match alter {
+            AlterTree::SetPassword => AlterRegExec::set_password(session),
+        }
+    }
+
+    pub fn set_password(session: &SessionConn) -> Result<(), DynError> {
+        session.ensure_writable()?;
+
+        let manager = VaultManager::load()?;
+        let mut vault = manager.external_vault_load(session.get_base_path())?;
+        vault.validate_f_header()?;
+        let keyfile = session.get_keyfile();
+
+        // The current password has to open the register as it is on disk, being
+        // connected alone is not enough to replace the key.
+        let (_, data_key, slot) = throttle::attempt(&vault.p, || {
+            let mut current = rpassword::prompt_password("Enter the current password: ")?;
+            let unlocked = VaultConnection::unlock_slot(&vault, &current, keyfile);
+            Zeroize::zeroize(&mut current);
+            unlocked
+        })?;
+
+        let mut password = AlterRegExec::prompt_new_password()?;
+
+        // Only the slot the current password opened is replaced; it keeps its KDF
+        // parameters and gets a new salt. The register data is left as it is.
+        let mut header = vault.load_header()?;
+        let kdf = header.slots[slot].kdf;
+        // A slot that opened without the session's keyfile doesn't start needing it.
+        let keyfile = keyfile.filter(|_| header.slot_uses_keyfile(&header.slots[slot]));
+        let new_slot = header.password_slot(&password, keyfile, kdf, &data_key);
+        Zeroize::zeroize(&mut password);
+        header.slots[slot] = new_slot?;
+
+        vault.rewrap(header)?;
+
+        println!("Password changed successfully");
+        Ok(())
+    }
+
+    // After a connect WITH RECOVERY KEY. With a single password slot that is the
+    // forgotten password and the new one replaces it; with several there is no telling
+    // which was forgotten, so the new password gets a slot of its own. The new slot
+    // needs no keyfile, it may have been lost along with the password.
+    pub fn reset_password(vault: &mut VaultMod, data_key: &[u8; 32]) -> Result<(), DynError> {
+        println!("Opened with the recovery key, a new password has to be set");
+        let mut header = vault.load_header()?;
+        let password_slots: Vec<usize> = header.password_slots().map(|(i, _)| i).collect();
+        if password_slots.len() > 1 && header.slots.len() >= MAX_KEY_SLOTS {
+            return Err(Box::new(KeySlotErr::Full { max: MAX_KEY_SLOTS }));
+        }
+        let kdf = password_slots
+            .first()
+            .map(|&i| header.slots[i].kdf)
+            .unwrap_or_default();
+
+        let mut password = AlterRegExec::prompt_new_password()?;
+        let new_slot = header.password_slot(&password, None, kdf, data_key);
+        Zeroize::zeroize(&mut password);
+        let new_slot = new_slot?;
+
+        let message = match password_slots.as_slice() {
+            [only] => {
+                header.slots[*only] = new_slot;
+                format!(
+                    "Password reset, key slot {} opens with the new password",
+                    only
+                )
+            }
+            _ => {
+                header.slots.push(new_slot);
+                format!(
+                    "Added key slot {} for the new password, REMOVE KEYSLOT the forgotten one",
+                    header.slots.len() - 1
+                )
+            }
+        };
+        vault.rewrap(header)?;
+        println!("{}", message);
+        Ok(())
+    }
+
+    pub fn prompt_new_password() -> Result<String, DynError> {
+        let mut password = rpassword::prompt_password("Enter the new password: ")?;
+        if password.len() < 8 {
+            Zeroize::zeroize(&mut password);
+            return Err(Box::new(CreateErr::ShortLenErr {
+                temp: "'password'".to_string(),
+                target_len: 8,
+            }));
+        }
+
+        let mut confirmation = rpassword::prompt_password("Confirm the new password: ")?;
+        let matched = password == confirmation;
+        Zeroize::zeroize(&mut confirmation);
+        if !matched {
+            Zeroize::zeroize(&mut password);
+            return Err(Box::new(AlterErr::PasswordMismatch));
+        }
+        Ok(password)
+    }
+}
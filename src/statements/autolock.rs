@@ -0,0 +1,96 @@
+use super::access::AccessExec;
+use super::connect::VaultConnection;
+use crate::encryption::protected;
+use crate::error::SessionErr;
+use crate::session::{SessionConn, UnlockMethod};
+use crate::storage::throttle;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use zeroize::Zeroizing;
+
+type DynError = Box<dyn std::error::Error>;
+
+// How often the idle time of the session is looked at.
+const CHECK_EVERY: Duration = Duration::from_secs(1);
+
+pub struct AutolockExec;
+
+impl AutolockExec {
+    // LOCK: wipes the register from memory right away.
+    pub fn lock(session: &mut SessionConn) -> Result<(), DynError> {
+        if session.is_locked() {
+            println!("The register is already locked");
+            return Ok(());
+        }
+        session.lock()?;
+        protected::rotate_session_key();
+        println!("Register locked, the next statement on it unlocks it again");
+        Ok(())
+    }
+
+    // SET AUTOLOCK <n> MINUTES, for this session only; autolock_minutes in config.toml
+    // sets what every session starts with. 0 turns it off.
+    pub fn set(minutes: u64, session: &mut SessionConn) {
+        session.set_autolock(minutes);
+        match minutes {
+            0 => println!("Autolock turned off for this session"),
+            n => println!("The register locks after {} idle minute(s)", n),
+        }
+    }
+
+    // Brings a locked register back into memory, asking for the same secret it was
+    // connected with. The session still holds the register lock, so what is on disk is
+    // what was there when it was locked.
+    pub fn resume(session: &mut SessionConn) -> Result<(), DynError> {
+        let name = session
+            .get_connected_reg_name()
+            .unwrap_or_default()
+            .to_string();
+        println!("Register '{}' is locked", name);
+        let (vault, header) = AccessExec::load(session)?;
+        let method = session
+            .get_unlock_method()
+            .ok_or(SessionErr::SessionNotConnected)?;
+        let keyfile = session.get_keyfile();
+        let (plaintext, key) = throttle::attempt(&vault.p, || match method {
+            UnlockMethod::Password => {
+                let password =
+                    Zeroizing::new(rpassword::prompt_password("Enter the vault's password: ")?);
+                let (plaintext, key, _) = VaultConnection::unlock_slot(&vault, &password, keyfile)?;
+                Ok((plaintext, key))
+            }
+            UnlockMethod::RecoveryKey => VaultConnection::unlock_recovery(&vault, &header),
+            UnlockMethod::Shares => VaultConnection::unlock_shares(&vault, &header),
+            UnlockMethod::Identity => VaultConnection::unlock_identity(&vault, &header),
+        })?;
+        // Only the register is needed back.
+        drop(Zeroizing::new(key));
+        let reg = VaultConnection::load_register(&plaintext)?;
+        session.reload(reg);
+        println!("Unlocked");
+        Ok(())
+    }
+
+    // Locks the session from the background once it has been idle for longer than its
+    // autolock, even while the prompt waits for input. Statements hold the session for
+    // as long as they run, so none is cut short.
+    pub fn watch(session: Arc<Mutex<SessionConn>>) {
+        thread::spawn(move || {
+            loop {
+                thread::sleep(CHECK_EVERY);
+                let Ok(mut session) = session.lock() else {
+                    return;
+                };
+                if session.autolock_due() && session.lock().is_ok() {
+                    protected::rotate_session_key();
+                    println!(
+                        "\nRegister '{}' locked after {} idle minute(s), the next statement on it unlocks it again",
+                        session.get_connected_reg_name().unwrap_or_default(),
+                        session.get_autolock().unwrap_or_default().as_secs() / 60
+                    );
+                }
+            }
+        });
+    }
+}
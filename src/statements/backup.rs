@@ -0,0 +1,146 @@
+use super::connect::VaultConnection;
+use crate::error::SessionErr;
+use crate::interpreter::ast::{BackupTree, RetentionOpts};
+use crate::session::SessionConn;
+use crate::session::lock::{DirLock, LockMode};
+use crate::storage::backup::{self, RetentionPolicy};
+use crate::storage::throttle;
+use crate::storage::vault::Vault;
+use crate::storage::vaultmanager::VaultManager;
+use std::fs;
+use std::path::PathBuf;
+use zeroize::{Zeroize, Zeroizing};
+
+type DynError = Box<dyn std::error::Error>;
+
+pub struct BackupExec;
+
+impl BackupExec {
+    pub fn execute(backup: BackupTree, session: &mut SessionConn) -> Result<(), DynError> {
+        match backup {
+            BackupTree::Show(reg_name) => BackupExec::show(reg_name.as_deref(), session),
+            BackupTree::Restore {
+                reg_name,
+                timestamp,
+            } => BackupExec::restore(reg_name.as_deref(), &timestamp, session),
+            BackupTree::SetRetention(opts) => BackupExec::set_retention(opts, session),
+        }
+    }
+
+    pub fn show(reg_name: Option<&str>, session: &SessionConn) -> Result<(), DynError> {
+        let reg_dir = match reg_name {
+            Some(name) => VaultManager::load()?.validate_register(name, false)?.1,
+            None if session.is_connected() => session.get_base_path().clone(),
+            None => return Err(Box::new(SessionErr::SessionNotConnected)),
+        };
+
+        let backups = backup::list(&reg_dir)?;
+        let stale = backup::list_stale(&reg_dir)?;
+        println!("Retention: {}", RetentionPolicy::load(&reg_dir)?);
+        if backups.is_empty() && stale.is_empty() {
+            println!("No backups yet, one is taken before every save");
+            return Ok(());
+        }
+        println!("{:<24} {:>10}", "TIMESTAMP", "BYTES");
+        for b in &backups {
+            println!("{:<24} {:>10}", b.timestamp, b.size);
+        }
+        println!("{} backup(s)", backups.len());
+        if !stale.is_empty() {
+            println!(
+                "Set aside when the register moved folders, kept outside the retention rules:"
+            );
+            for b in &stale {
+                println!("{:<24} {:>10}", b.timestamp, b.size);
+            }
+            println!("{} backup(s)", stale.len());
+        }
+        Ok(())
+    }
+
+    // Replaces vault.bin with a backup once the backup has been shown to decrypt under
+    // the entered password. The file being replaced is backed up in turn, so a restore
+    // can itself be undone. A backup set aside by a move to another folder is sealed
+    // for this folder on the way.
+    pub fn restore(
+        reg_name: Option<&str>,
+        timestamp: &str,
+        session: &mut SessionConn,
+    ) -> Result<(), DynError> {
+        let manager = VaultManager::load()?;
+        let connected = match (reg_name, session.get_connected_reg_name()) {
+            (None, _) => true,
+            (Some(name), Some(current)) if name == current => true,
+            (Some(_), Some(_)) => return Err(Box::new(SessionErr::AnotherSessionIsRunningErr)),
+            (Some(_), None) => false,
+        };
+
+        // A connected session already holds the register lock, otherwise take it here.
+        let (reg_dir, _lock): (PathBuf, Option<DirLock>) = if connected {
+            session.ensure_writable()?;
+            (session.get_base_path().clone(), None)
+        } else {
+            let name = reg_name.unwrap();
+            let (_, child_p) = manager.validate_register(name, false)?;
+            let lock = DirLock::acquire(
+                &child_p,
+                LockMode::Exclusive,
+                &format!("Register '{}'", name),
+            )?;
+            (child_p, Some(lock))
+        };
+
+        let backup = backup::find(&reg_dir, timestamp)?;
+        let bytes = fs::read(&backup.path)?;
+        let mut vault = manager.external_vault_load(&reg_dir)?;
+
+        // The backup may predate adding or removing a keyfile, its own header decides.
+        let (header, _) = Vault::from_bytes(&bytes)?;
+        let given = match connected && header.accepts_keyfile() {
+            true => session.get_keyfile(),
+            false => None,
+        };
+        let keyfile = VaultConnection::keyfile_for(&header, given)?;
+
+        let bound = vault.bound_to(backup.bound_to.as_deref().unwrap_or(&vault.id));
+        let (data_as_bytes, key) = throttle::attempt(&reg_dir, || {
+            let mut password = rpassword::prompt_password("Enter the password of the backup: ")?;
+            let unlocked =
+                VaultConnection::unlock_bytes(&bound, &bytes, &password, keyfile.as_deref());
+            Zeroize::zeroize(&mut password);
+            unlocked
+        })?;
+        let key = Zeroizing::new(key);
+        let reg = VaultConnection::load_register(&data_as_bytes)?;
+
+        match backup.bound_to {
+            Some(_) => vault.replace(&vault.rebind(&bytes, *key, &data_as_bytes)?)?,
+            None => vault.replace(&bytes)?,
+        }
+        if connected {
+            session.reload(reg);
+        }
+
+        println!(
+            "Register restored from the backup taken at {}; the replaced version was backed up first",
+            timestamp
+        );
+        Ok(())
+    }
+
+    pub fn set_retention(opts: RetentionOpts, session: &SessionConn) -> Result<(), DynError> {
+        session.ensure_writable()?;
+        let reg_dir = session.get_base_path();
+
+        let mut policy = RetentionPolicy::load(reg_dir)?;
+        policy.last = opts.last.unwrap_or(policy.last);
+        policy.daily = opts.daily.unwrap_or(policy.daily);
+        policy.weekly = opts.weekly.unwrap_or(policy.weekly);
+        policy.save(reg_dir)?;
+
+        let kept = backup::prune(reg_dir, &policy)?;
+        println!("Backup retention set to {}", policy);
+        println!("{} backup(s) kept", kept.len());
+        Ok(())
+    }
+}
@@ -1,25 +1,69 @@
 use std::{
-    fs::{OpenOptions, write},
+    fs::{self, OpenOptions, write},
     io::{Read, Seek, SeekFrom},
-    path::PathBuf,
+    path::{Path, PathBuf},
 };
 
+use super::alter::AlterRegExec;
 use super::create;
+use super::shares::SharesExec;
+use super::stmt_utl::prompt_line;
 use crate::{
     encryption::{
         aead::decrypt,
         enc_utl::KdfMode,
+        identity::{self, Identity},
         kdf::{derive_fast_key, derive_slow_key},
+        recovery, shamir,
+    },
+    error::{self, IdentityErr, KeyfileErr, RecoveryErr, ShareErr},
+    session::{
+        SessionConn, UnlockMethod,
+        lock::{DirLock, LockMode},
+    },
+    storage::{
+        self, throttle,
+        types::Register,
+        vault::{SlotKind, VAULT_N, VAULT_VERSION, Vault},
+        vaultmod::VaultMod,
     },
-    error,
-    session::SessionConn,
-    storage::{self, types::Register, vault::VAULT_N, vaultmod::VaultMod},
 };
+use zeroize::{Zeroize, Zeroizing};
 type DynErr = Box<dyn std::error::Error>;
 
 pub struct VaultConnection;
 impl VaultConnection {
-    pub fn execute(reg_name: &str, session: &mut SessionConn) -> Result<(), DynErr> {
+    // Runs before anything is read. A recovery key always resets the password, which
+    // a READ ONLY session couldn't write, so the two are never combined.
+    fn check_options(
+        read_only: bool,
+        keyfile: Option<&str>,
+        recovery: bool,
+        shares: bool,
+        identity: bool,
+    ) -> Result<(), DynErr> {
+        if recovery && (read_only || keyfile.is_some() || shares || identity) {
+            return Err(Box::new(RecoveryErr::Combined));
+        }
+        if identity && (keyfile.is_some() || shares) {
+            return Err(Box::new(IdentityErr::Combined));
+        }
+        if shares && keyfile.is_some() {
+            return Err(Box::new(ShareErr::WithKeyfile));
+        }
+        Ok(())
+    }
+
+    pub fn execute(
+        reg_name: &str,
+        read_only: bool,
+        keyfile: Option<&str>,
+        recovery: bool,
+        shares: bool,
+        identity: bool,
+        session: &mut SessionConn,
+    ) -> Result<(), DynErr> {
+        VaultConnection::check_options(read_only, keyfile, recovery, shares, identity)?;
         // Since the logic of validation is the same for both registering and reconnecting
         // to a database or system, it's generally more efficient to reuse existing code
         // rather than re-implementing it.
@@ -40,7 +84,18 @@ impl VaultConnection {
 
         // We need to get the key here in case the function fails
         // to reach it so that we can properly deallocate the register
-        let (_, child_p) = manager.validate_register(reg_name, false)?;
+        // The root lock keeps a concurrent DROP from removing the folder between the
+        // lookup and taking the register lock; it is released once we hold the latter.
+        let root_lock =
+            DirLock::acquire(manager.get_root_path(), LockMode::Shared, "The root vault")?;
+        let (_, mut child_p) = manager.validate_register(reg_name, false)?;
+        let mode = if read_only {
+            LockMode::Shared
+        } else {
+            LockMode::Exclusive
+        };
+        let mut lock = DirLock::acquire(&child_p, mode, &format!("Register '{}'", reg_name))?;
+        drop(root_lock);
 
         // Seeking to register with its path or return an error,
         // the path is required to decrypt the ciphertext later.
@@ -52,40 +107,270 @@ impl VaultConnection {
 
         vault.validate_f_header();
 
-        let bytes_data = VaultConnection::connect(&mut vault)?;
+        let header = vault.load_header()?;
+        // Wrong passwords, recovery keys and shares make the next try wait longer.
+        let (keyfile, (bytes_data, key)) = throttle::attempt(&child_p, || {
+            Ok(if recovery {
+                (None, VaultConnection::unlock_recovery(&vault, &header)?)
+            } else if shares {
+                (None, VaultConnection::unlock_shares(&vault, &header)?)
+            } else if identity {
+                (None, VaultConnection::unlock_identity(&vault, &header)?)
+            } else {
+                let keyfile = VaultConnection::keyfile_for(&header, keyfile.map(Path::new))?;
+                let unlocked = VaultConnection::connect(&mut vault, keyfile.as_deref())?;
+                (keyfile, unlocked)
+            })
+        })?;
 
-        let reg = VaultConnection::load_register(bytes_data)?;
+        // Wiped when the statement ends, like the keys it may be replaced with below.
+        let mut key = Zeroizing::new(key);
+        let reg = VaultConnection::load_register(&bytes_data)?;
 
-        session.connect_to(reg);
+        // A READ ONLY session never writes, so format upgrades and the auth resync wait
+        // for the next read-write connect.
+        if !read_only {
+            // Older vault formats are rewritten in the current layout on the first
+            // successful connect, while the key is at hand. From then on the data is
+            // under a new data key.
+            let mut legacy_key = None;
+            if let Some((old_version, data_key)) = vault.migrate(*key, &bytes_data)? {
+                legacy_key = Some(Zeroizing::new(*key));
+                *key = data_key;
+                println!(
+                    "Upgraded the register from vault format v{} to v{} (previous file kept as {}.v{}.bak)",
+                    old_version, VAULT_VERSION, VAULT_N, old_version
+                );
+            }
+
+            // A crash between the two writes of the last save leaves auth.pwmn behind.
+            vault.sync_auth()?;
+
+            // Registers still named with the legacy salt move to the root's own salt.
+            if manager.is_legacy(reg_name, &child_p) {
+                match manager.rehash(
+                    reg_name,
+                    &mut vault,
+                    *key,
+                    legacy_key.as_deref().copied(),
+                    &bytes_data,
+                ) {
+                    Ok(new_lock) => {
+                        lock = new_lock;
+                        child_p = vault.p.clone();
+                        println!("Moved the register to this installation's name salt");
+                    }
+                    Err(e) => println!(
+                        "Couldn't move the register off the legacy name salt, retrying on the next CONNECT: {}",
+                        e
+                    ),
+                }
+            } else {
+                manager.save_root_meta_copy(&child_p)?;
+            }
+
+            // Whoever needed the recovery key has lost the password; the session only
+            // starts once a new one is set.
+            if recovery {
+                AlterRegExec::reset_password(&mut vault, &key)?;
+            }
+        }
+
+        let unlock = if recovery {
+            UnlockMethod::RecoveryKey
+        } else if shares {
+            UnlockMethod::Shares
+        } else if identity {
+            UnlockMethod::Identity
+        } else {
+            UnlockMethod::Password
+        };
+        session.connect_to(reg, child_p, lock, keyfile, unlock);
 
         println!("CONNECTED");
 
         Ok(())
     }
 
-    pub fn connect(vault_mod: &mut VaultMod) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-        let mut vault = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open(&vault_mod.pathfP.as_ref().unwrap())?;
-        let mut salt = vault_mod.load_salt()?;
-        let mut nonce = vault_mod.load_nonce()?;
-        let mut encrypted = Vec::new();
-        vault.seek(SeekFrom::Start(22 + 12))?;
-        vault.read_to_end(&mut encrypted);
-        let password = rpassword::prompt_password("Enter the vault's password: ")?;
-        let in_key = derive_slow_key(&password, &salt);
-        let _e_data = decrypt(in_key, nonce, encrypted)?;
-        Ok(_e_data)
+    pub fn connect(
+        vault_mod: &mut VaultMod,
+        keyfile: Option<&Path>,
+    ) -> Result<(Zeroizing<Vec<u8>>, [u8; 32]), Box<dyn std::error::Error>> {
+        VaultConnection::unlock(vault_mod, "Enter the vault's password: ", keyfile)
     }
 
-    pub fn load_register(bytes_data: Vec<u8>) -> Result<Register, Box<dyn std::error::Error>> {
+    // Like `connect`, with the recovery key in place of the password and keyfile.
+    pub fn unlock_recovery(
+        vault_mod: &VaultMod,
+        header: &Vault,
+    ) -> Result<(Zeroizing<Vec<u8>>, [u8; 32]), DynErr> {
+        if !header.has_slot(SlotKind::Recovery) {
+            return Err(Box::new(RecoveryErr::NotSet));
+        }
+        let mut typed = rpassword::prompt_password("Enter the recovery key: ")?;
+        let phrase = recovery::normalize(&typed);
+        Zeroize::zeroize(&mut typed);
+        let mut phrase = phrase?;
+        let unlocked = header.unlock_recovery(&phrase);
+        Zeroize::zeroize(&mut phrase);
+        let (key, _) = unlocked?;
+
+        let bytes = fs::read(vault_mod.p.join(VAULT_N))?;
+        Ok((vault_mod.open(key, &bytes)?, key))
+    }
+
+    // Like `connect`, with enough shares of a split in place of the password.
+    pub fn unlock_shares(
+        vault_mod: &VaultMod,
+        header: &Vault,
+    ) -> Result<(Zeroizing<Vec<u8>>, [u8; 32]), DynErr> {
+        if !header.has_slot(SlotKind::Shares) {
+            return Err(Box::new(ShareErr::NotSplit));
+        }
+        let shares = SharesExec::prompt_shares()?;
+        let mut secret = shamir::combine(&shares)?;
+        let unlocked = header.unlock_shares(shares[0].set, &secret);
+        Zeroize::zeroize(&mut secret);
+        let (key, _) = unlocked?;
+
+        let bytes = fs::read(vault_mod.p.join(VAULT_N))?;
+        Ok((vault_mod.open(key, &bytes)?, key))
+    }
+
+    // Like `connect`, with the identity of `pwmn keygen` in place of the password.
+    pub fn unlock_identity(
+        vault_mod: &VaultMod,
+        header: &Vault,
+    ) -> Result<(Zeroizing<Vec<u8>>, [u8; 32]), DynErr> {
+        let identity = VaultConnection::load_identity(header)?;
+        let (key, _) = header.unlock_identity(&identity)?;
+
+        let bytes = fs::read(vault_mod.p.join(VAULT_N))?;
+        Ok((vault_mod.open(key, &bytes)?, key))
+    }
+
+    // The identity under ~/.pwmn, opened with its passphrase. Registers it wasn't
+    // granted to are turned down before the passphrase is asked for.
+    pub fn load_identity(header: &Vault) -> Result<Identity, DynErr> {
+        let bytes = identity::read()?;
+        if header.member_slot(&identity::public_of(&bytes)?).is_none() {
+            return Err(Box::new(IdentityErr::NotGranted));
+        }
+        VaultConnection::open_identity(&bytes)
+    }
+
+    pub fn open_identity(bytes: &[u8]) -> Result<Identity, DynErr> {
+        let mut passphrase = rpassword::prompt_password("Enter the identity's passphrase: ")?;
+        let opened = Identity::open(bytes, &passphrase);
+        Zeroize::zeroize(&mut passphrase);
+        Ok(opened?)
+    }
+
+    // Returns the decrypted register bytes together with the key that opened them.
+    pub fn unlock(
+        vault_mod: &mut VaultMod,
+        prompt: &str,
+        keyfile: Option<&Path>,
+    ) -> Result<(Zeroizing<Vec<u8>>, [u8; 32]), Box<dyn std::error::Error>> {
+        let password = Zeroizing::new(rpassword::prompt_password(prompt)?);
+        VaultConnection::unlock_with_password(vault_mod, &password, keyfile)
+    }
+
+    pub fn unlock_with_password(
+        vault_mod: &mut VaultMod,
+        password: &str,
+        keyfile: Option<&Path>,
+    ) -> Result<(Zeroizing<Vec<u8>>, [u8; 32]), Box<dyn std::error::Error>> {
+        let bytes = fs::read(vault_mod.pathfP.as_ref().unwrap())?;
+        VaultConnection::unlock_bytes(vault_mod, &bytes, password, keyfile)
+    }
+
+    // Opens a full vault file that isn't necessarily the current vault.bin, such as a
+    // backup, so the key comes from the key slots in its own header.
+    pub fn unlock_bytes(
+        vault_mod: &VaultMod,
+        bytes: &[u8],
+        password: &str,
+        keyfile: Option<&Path>,
+    ) -> Result<(Zeroizing<Vec<u8>>, [u8; 32]), Box<dyn std::error::Error>> {
+        let (_e_data, in_key, _) =
+            VaultConnection::open_bytes(vault_mod, bytes, password, keyfile)?;
+        Ok((_e_data, in_key))
+    }
+
+    // Like `unlock_with_password`, also returning the index of the key slot the
+    // password opened, for statements that replace that slot.
+    pub fn unlock_slot(
+        vault_mod: &VaultMod,
+        password: &str,
+        keyfile: Option<&Path>,
+    ) -> Result<(Zeroizing<Vec<u8>>, [u8; 32], usize), Box<dyn std::error::Error>> {
+        let bytes = fs::read(vault_mod.p.join(VAULT_N))?;
+        VaultConnection::open_bytes(vault_mod, &bytes, password, keyfile)
+    }
+
+    fn open_bytes(
+        vault_mod: &VaultMod,
+        bytes: &[u8],
+        password: &str,
+        keyfile: Option<&Path>,
+    ) -> Result<(Zeroizing<Vec<u8>>, [u8; 32], usize), Box<dyn std::error::Error>> {
+        let (header, _) = Vault::from_bytes(bytes)?;
+        let (in_key, slot) = header.unlock_key(password, keyfile)?;
+        let _e_data = vault_mod.open(in_key, bytes)?;
+        Ok((_e_data, in_key, slot))
+    }
+
+    // The keyfile to unlock `header` with: the one given, or asked for when none was
+    // given and no key slot opens without one.
+    pub fn keyfile_for(
+        header: &Vault,
+        given: Option<&Path>,
+    ) -> Result<Option<PathBuf>, Box<dyn std::error::Error>> {
+        match given {
+            Some(_) if !header.accepts_keyfile() => Err(Box::new(KeyfileErr::NotUsed)),
+            Some(p) => Ok(Some(p.to_path_buf())),
+            None if !header.requires_keyfile() => Ok(None),
+            None => {
+                let p = prompt_line("Enter the path of the register's keyfile: ")?;
+                if p.is_empty() {
+                    return Err(Box::new(KeyfileErr::Required));
+                }
+                Ok(Some(PathBuf::from(p)))
+            }
+        }
+    }
+
+    pub fn load_register(bytes_data: &[u8]) -> Result<Register, Box<dyn std::error::Error>> {
         let decoded: Register = {
             let config = bincode::config::standard();
-            let (value, len): (Register, usize) = bincode::decode_from_slice(&bytes_data, config)?;
+            let (value, len): (Register, usize) = bincode::decode_from_slice(bytes_data, config)?;
             value
         };
 
         Ok(decoded)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recovery_is_never_combined() {
+        let combined = |read_only, keyfile, shares, identity| {
+            let err = VaultConnection::check_options(read_only, keyfile, true, shares, identity)
+                .unwrap_err();
+            matches!(
+                err.downcast_ref::<RecoveryErr>(),
+                Some(RecoveryErr::Combined)
+            )
+        };
+        assert!(combined(true, None, false, false));
+        assert!(combined(false, Some("key.bin"), false, false));
+        assert!(combined(false, None, true, false));
+        assert!(combined(false, None, false, true));
+        assert!(VaultConnection::check_options(false, None, true, false, false).is_ok());
+        assert!(VaultConnection::check_options(true, None, false, false, false).is_ok());
+    }
+}
@@ -1,9 +1,14 @@
 use crate::encryption::enc_utl::KdfMode;
-use crate::encryption::kdf;
+use crate::encryption::kdf::{self, KdfParams};
+use crate::encryption::keyfile;
+use crate::encryption::recovery;
 use crate::error::{self, CreateErr, SessionErr};
+use crate::interpreter::ast::CreateOpts;
 use crate::session::SessionConn;
+use crate::session::lock::{DirLock, LockMode};
+use crate::storage::config::Config;
 use crate::storage::enc_auth::Auth;
-use crate::storage::init::ROOT_REG;
+use crate::storage::kit::{self, Kit};
 use crate::storage::vaultmod::VaultMod;
 use crate::storage::{self, vaultmod};
 use crate::{encryption::kdf::derive_fast_key, encryption::kdf::derive_slow_key};
@@ -13,23 +18,20 @@ use serde::{Deserialize, Serialize};
 use std::env::{self, SplitPaths};
 use std::fs::{OpenOptions, create_dir_all as mksafe_dir, remove_dir_all};
 use std::io::{Read, Seek, SeekFrom, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use storage::types::Register;
 type DynError = Box<dyn std::error::Error>;
-use crate::encryption::aead;
-use zeroize::Zeroize;
+use crate::encryption::aead::{self, CipherId, DEFAULT_CIPHER};
+use zeroize::{Zeroize, Zeroizing};
 pub struct CreateRegExec;
 use crate::storage::vault::{self, Vault};
 use crate::storage::vaultmanager::VaultManager;
 
-pub enum WriteMode {
-    Vault,
-    Auth,
-}
-
 impl CreateRegExec {
     pub fn execute(
         reg_name: &str,
+        opts: CreateOpts,
         session: &SessionConn,
     ) -> Result<(), Box<dyn std::error::Error>> {
         // Validate the input before proceeding.
@@ -38,35 +40,66 @@ impl CreateRegExec {
         // "Validate if the root vault exists. If not, propagate a VaultNotExists error."
         let vault_manager = VaultManager::load()?;
 
-        let mut child = vault_manager.create_child(reg_name)?;
-
-        let mut vault = child.allocate()?;
-
-        // TODO()! -> need to modify this function for the given situation.
-        // We create a unique folder and add it to the parent vault,
-        // which may cause other functions to fail.
-        // This would result in writing a garbage key
-        // that wastes space and prevents creating another
-        // register with the same name.
-
-        let (data_as_bytes, pwd_key) =
-            CreateRegExec::insert_encrypted_empty_data(&mut vault, reg_name)?;
-
-        let nonce = vault.load_nonce()?;
-
-        let ciphertext = aead::encrypt(pwd_key, nonce, data_as_bytes)?;
-
-        CreateRegExec::write_encrypted_data(
-            vault.pathfP.as_ref().unwrap(),
-            &ciphertext,
-            WriteMode::Vault,
+        // WITH KDF CALIBRATE wins over the configured target; with neither, the
+        // built-in parameters are used.
+        let config = Config::load(vault_manager.get_root_path())?;
+        let kdf = match opts.kdf_calibrate_ms.or(config.kdf_target_ms) {
+            Some(ms) => CreateRegExec::calibrate(ms),
+            None => KdfParams::default(),
+        };
+        // A keyfile that can't be read now would lock the register for good, so it is
+        // checked before anything is written.
+        let keyfile = opts.keyfile.as_deref().map(Path::new);
+        if let Some(p) = keyfile {
+            keyfile::hash(p)?;
+        }
+        let kit_p = opts.recovery_kit.as_deref().map(Path::new);
+        if let Some(p) = kit_p {
+            kit::ensure_new(p)?;
+        }
+        let cipher = match opts.cipher.as_deref() {
+            Some(name) => CipherId::from_name(name)?,
+            None => DEFAULT_CIPHER,
+        };
+        let phrase = opts.recovery_key.then(recovery::generate);
+        // The password prompt and the Argon2 runs behind the key slots can take a
+        // while, so they happen before the root is locked.
+        let (header, data_key, data_as_bytes) =
+            CreateRegExec::prepare_header(reg_name, &kdf, cipher, keyfile, phrase.as_deref())?;
+        let data_key = Zeroizing::new(data_key);
+
+        // Held until the register is fully written so no other process creates or drops
+        // registers under us.
+        let _root_lock = DirLock::acquire(
+            vault_manager.get_root_path(),
+            LockMode::Exclusive,
+            "The root vault",
         )?;
 
-        Auth::create_at(&vault.p)?;
-
-        let auth = Auth::load(&vault.p)?;
-
-        CreateRegExec::write_encrypted_data(&auth.file, &ciphertext, WriteMode::Auth);
+        vault_manager.remove_stale_staging()?;
+
+        // Nothing shows up under the register's own folder name until it is complete;
+        // any failure on the way only has a staging folder to clean up.
+        let mut vault = vault_manager.stage_child(reg_name)?;
+        let staged = vault
+            .allocate()
+            .and_then(|vault| vault.seal_with(header, *data_key, data_as_bytes))
+            .and_then(|_| vault_manager.commit_child(&mut vault));
+        if staged.is_err() {
+            let _ = remove_dir_all(&vault.p);
+        }
+        staged?;
+
+        if let Some(mut phrase) = phrase {
+            let kit = Kit {
+                reg_name,
+                root: vault_manager.get_root_path(),
+                phrase: &phrase,
+                created: chrono::Local::now().format("%Y-%m-%d %H:%M").to_string(),
+            };
+            CreateRegExec::show_recovery_key(&kit, kit_p);
+            Zeroize::zeroize(&mut phrase);
+        }
 
         println!(
             "\nVault Created Successfully!\nUse CONNECT '{}' to connect to your register",
@@ -75,6 +108,44 @@ impl CreateRegExec {
         Ok(())
     }
 
+    // The new register's header with all of its key slots, its data key and the
+    // encoded empty register. Sealing later draws the nonce for the first write.
+    fn prepare_header(
+        reg_name: &str,
+        kdf: &KdfParams,
+        cipher: CipherId,
+        keyfile: Option<&Path>,
+        recovery_phrase: Option<&str>,
+    ) -> Result<(Vault, [u8; 32], Vec<u8>), DynError> {
+        let mut header = Vault::generate();
+        header.cipher = cipher;
+        let (data_as_bytes, data_key) =
+            CreateRegExec::insert_encrypted_empty_data(reg_name, &mut header, kdf, keyfile)?;
+        if let Some(phrase) = recovery_phrase {
+            let slot = header.recovery_slot(phrase, &data_key)?;
+            header.slots.push(slot);
+        }
+        Ok((header, data_key, data_as_bytes))
+    }
+
+    // The register exists by now, so a kit that can't be written doesn't undo it; the
+    // key is on screen either way.
+    fn show_recovery_key(kit: &Kit, kit_p: Option<&Path>) {
+        println!("\nRecovery key, it opens the register on its own and is not shown again:\n");
+        for line in recovery::lines(kit.phrase) {
+            println!("  {}", line);
+        }
+        match kit_p.map(|p| (p, kit::write(p, &kit.render_for(p)))) {
+            Some((p, Ok(()))) => println!("\nEmergency kit written to {}", p.display()),
+            Some((p, Err(e))) => println!(
+                "\nCouldn't write the emergency kit to {}: {}\nWrite the key down instead.",
+                p.display(),
+                e
+            ),
+            None => println!("\nWrite it down and keep it somewhere safe."),
+        }
+    }
+
     pub fn pre_validation(name: &str, session: &SessionConn) -> Result<(), DynError> {
         // Validate the name's length first.
         if name.len() < 5 {
@@ -92,18 +163,25 @@ impl CreateRegExec {
         }
         Ok(())
     }
+    pub fn calibrate(target_ms: u64) -> KdfParams {
+        println!("Calibrating Argon2id for a {} ms unlock...", target_ms);
+        let kdf = kdf::calibrate(Duration::from_millis(target_ms));
+        println!("Selected {}", kdf);
+        kdf
+    }
+
+    // Returns the encoded empty register and the data key it is to be encrypted with,
+    // after adding the password's key slot for it to `header`.
     pub fn insert_encrypted_empty_data(
-        vault: &mut VaultMod,
         name: &str,
+        header: &mut Vault,
+        kdf: &KdfParams,
+        keyfile: Option<&Path>,
     ) -> Result<(Vec<u8>, [u8; 32]), Box<dyn std::error::Error>> {
-        let mut file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open(vault.pathfP.as_ref().unwrap())?;
-        // To p, because we need to move it later to fetch the private vault salt.
-        let mut password = rpassword::prompt_password(
+        // Wiped on every way out, a rejected password included.
+        let password = Zeroizing::new(rpassword::prompt_password(
             "\nA password is required to create the vault.\nPlease enter a password: ",
-        )?;
+        )?);
 
         if password.len() < 8 {
             return Err(Box::new(CreateErr::ShortLenErr {
@@ -111,11 +189,10 @@ impl CreateRegExec {
                 target_len: 8,
             }));
         }
-        let salt = vault.load_salt()?;
-        let key = derive_slow_key(&password, &salt);
-
-        // Zeroize the password from memory.
-        Zeroize::zeroize(&mut password);
+        let key = Vault::generate_data_key();
+        header
+            .slots
+            .push(header.password_slot(&password, keyfile, *kdf, &key)?);
 
         // Create an empty new record/register to use it later for CRUD operations
         let reg = Register::new(name);
@@ -125,31 +202,4 @@ impl CreateRegExec {
 
         Ok((reg_to_bytes, key))
     }
-
-    pub fn write_encrypted_data(
-        p: &PathBuf,
-        ciphertext: &Vec<u8>,
-        write_mode: WriteMode,
-    ) -> Result<(), DynError> {
-        let pos: u64;
-        match write_mode {
-            WriteMode::Vault => {
-                pos = 34;
-            }
-            WriteMode::Auth => {
-                pos = 0;
-            }
-        }
-
-        let mut r_vault = OpenOptions::new().write(true).read(true).open(p)?;
-        // [4] [2] [16] [12]
-        //  4---6---22---34
-        // Position 34 marks the beginning of the empty dataset.
-        r_vault.seek(SeekFrom::Start((pos)));
-        r_vault.write_all(&ciphertext)?;
-        // Flushing data slowly to disk but because we're during a critical moment,
-        // we need to force the flush to ensure that all data has been written.
-        r_vault.flush()?;
-        Ok(())
-    }
 }
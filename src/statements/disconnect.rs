@@ -1,4 +1,5 @@
 use crate::{
+    encryption::protected,
     error::SessionErr,
     session::{self, SessionConn},
 };
@@ -13,6 +14,7 @@ impl<'a> Disconnect<'a> {
             return Err(SessionErr::SessionNotConnected);
         }
         self.session.disconnect_from();
+        protected::rotate_session_key();
         println!("Session disconnected successfully");
         Ok(())
     }
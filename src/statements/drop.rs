@@ -1,6 +1,9 @@
+use super::connect::VaultConnection;
 use crate::interpreter::ast::DropTree;
+use crate::session::lock::{DirLock, LockMode};
 use crate::session::{SessionConn, session_conn};
 use crate::storage::enc_auth::Auth;
+use crate::storage::throttle;
 use crate::{
     encryption::kdf::derive_fast_key,
     error::{
@@ -38,14 +41,28 @@ impl Drop {
             return Err(Box::new(AnotherSessionIsRunningErr));
         }
         let mut vault_manager = vaultmanager::VaultManager::load()?;
+        let _root_lock = DirLock::acquire(
+            vault_manager.get_root_path(),
+            LockMode::Exclusive,
+            "The root vault",
+        )?;
         let (_, child_p) = vault_manager.validate_register(reg_name, false)?;
+        // A register connected anywhere else, even READ ONLY, cannot be dropped.
+        let _reg_lock = DirLock::acquire(
+            &child_p,
+            LockMode::Exclusive,
+            &format!("Register '{}'", reg_name),
+        )?;
         let mut vault = vault_manager.external_vault_load(&child_p)?;
         let auth = Auth::load(&vault.p)?;
-        auth.connect(
-            "Entre the password of the vault: ",
-            vault.salt.as_ref().unwrap(),
-            vault.nonce.as_ref().unwrap(),
-        )?;
+        let keyfile = VaultConnection::keyfile_for(&vault.load_header()?, None)?;
+        throttle::attempt(&vault.p, || {
+            auth.connect(
+                "Entre the password of the vault: ",
+                &vault,
+                keyfile.as_deref(),
+            )
+        })?;
         remove_dir_all(vault.p);
         println!(
             "Register with name '{}' hash been successfully removed",
@@ -0,0 +1,213 @@
+use super::access::AccessExec;
+use super::alter::AlterRegExec;
+use super::connect::VaultConnection;
+use crate::encryption::identity;
+use crate::encryption::kdf::KdfParams;
+use crate::error::{BundleErr, SessionErr};
+use crate::interpreter::ast::ShareTo;
+use crate::p_std::uid::Uid;
+use crate::session::SessionConn;
+use crate::storage::bundle::{self, Bundle, Lock, Shared};
+use crate::storage::types::{LogEntry, Operation, Register};
+use chrono::{Local, TimeZone};
+use std::fs;
+use std::path::Path;
+use zeroize::Zeroize;
+
+type DynError = Box<dyn std::error::Error>;
+
+const DAY_SECS: i64 = 24 * 60 * 60;
+
+pub struct EntryShareExec;
+
+impl EntryShareExec {
+    // The entry comes from the connected register as it was decrypted on CONNECT, so
+    // exporting needs no password and works READ ONLY.
+    pub fn export(
+        entry_id: &str,
+        path: &str,
+        to: ShareTo,
+        expires_days: Option<u32>,
+        session: &SessionConn,
+    ) -> Result<(), DynError> {
+        if !session.is_connected() {
+            return Err(Box::new(SessionErr::SessionNotConnected));
+        }
+        let p = Path::new(path);
+        bundle::ensure_new(p)?;
+        let reg = session.get_reg_as_immt().unwrap();
+        let entry = reg
+            .entries
+            .iter()
+            .find(|entry| entry.entry_id == entry_id)
+            .ok_or_else(|| BundleErr::EntryNotFound {
+                id: entry_id.to_string(),
+            })?;
+
+        let now = Local::now().timestamp();
+        let shared = Shared {
+            entry: entry.clone(),
+            from_register: reg.r_name.clone(),
+            exported_at: now,
+        };
+        let expires_at = expires_days.map(|days| now + days as i64 * DAY_SECS);
+        let bytes = match &to {
+            ShareTo::Member(public) => {
+                let member = identity::decode_public(public)?;
+                Bundle::seal_for(&shared, &member, expires_at)?
+            }
+            ShareTo::Passphrase => {
+                println!("Choose a passphrase for the share");
+                let mut passphrase = AlterRegExec::prompt_new_password()?;
+                let sealed = Bundle::seal_with_passphrase(
+                    &shared,
+                    &passphrase,
+                    &KdfParams::default(),
+                    expires_at,
+                );
+                passphrase.zeroize();
+                sealed?
+            }
+        };
+        bundle::write(p, &bytes)?;
+
+        println!("Exported entry '{}' to {}", entry_id, p.display());
+        if let Some(at) = expires_at {
+            println!("It can be imported until {}", date(at));
+        }
+        match to {
+            ShareTo::Member(_) => println!("Only the identity it was made for can import it"),
+            ShareTo::Passphrase => println!("Hand the passphrase over apart from the file"),
+        }
+        Ok(())
+    }
+
+    // The id comes from whoever made the share. One that isn't shaped like ours or is
+    // already taken in `reg` is replaced.
+    fn free_entry_id(id: &str, reg: &Register) -> String {
+        let mut id = id.to_string();
+        while !Register::is_entry_id(&id) || reg.entries.iter().any(|e| e.entry_id == id) {
+            id = Uid::new("Entry");
+        }
+        id
+    }
+
+    // Decrypts the share and adds its entry to the connected register. Where it came
+    // from goes into the entry's notes and the register's log.
+    pub fn import(path: &str, session: &mut SessionConn) -> Result<(), DynError> {
+        session.ensure_writable()?;
+        let p = Path::new(path);
+        let bundle = Bundle::parse(&fs::read(p)?)?;
+        // The expiry is authenticated by the open below; checking it first only spares
+        // asking for a passphrase.
+        let now = Local::now().timestamp();
+        if bundle.is_expired(now) {
+            return Err(Box::new(BundleErr::Expired {
+                at: date(bundle.expires_at.unwrap()),
+            }));
+        }
+        let shared = match bundle.lock {
+            Lock::Passphrase { .. } => {
+                let mut passphrase = rpassword::prompt_password("Enter the share's passphrase: ")?;
+                let opened = bundle.open_with_passphrase(&passphrase);
+                passphrase.zeroize();
+                opened?
+            }
+            Lock::Member { member, .. } => {
+                let bytes = identity::read()?;
+                if identity::public_of(&bytes)? != member {
+                    return Err(Box::new(BundleErr::NotForYou {
+                        public: identity::encode_public(&member),
+                    }));
+                }
+                bundle.open_with_identity(&VaultConnection::open_identity(&bytes)?)?
+            }
+        };
+
+        let (mut vault, header) = AccessExec::load(session)?;
+        let unlocked = AccessExec::unlock(&vault, &header, session)?;
+        let mut reg = VaultConnection::load_register(&unlocked.plaintext)?;
+
+        let mut entry = shared.entry;
+        entry.entry_id = EntryShareExec::free_entry_id(&entry.entry_id, &reg);
+        let provenance = format!(
+            "Imported from register '{}' on {} (exported {})",
+            shared.from_register,
+            date(now),
+            date(shared.exported_at)
+        );
+        entry.notes = Some(match entry.notes.take() {
+            Some(notes) if !notes.is_empty() => format!("{}\n{}", notes, provenance),
+            _ => provenance,
+        });
+        entry.metadata.modified_at = now;
+        let entry_id = entry.entry_id.clone();
+
+        reg.entries.push(entry);
+        reg.metadata.n_of_entries += 1;
+        reg.metadata.modified_at = now;
+        reg.log.push(LogEntry {
+            timestamp: now,
+            operation: Operation::InsertEntry,
+            entry_id: Some(entry_id.clone()),
+            status: true,
+            details: format!("imported from {}", p.display()),
+        });
+        let bytes = bincode::encode_to_vec(&reg, bincode::config::standard())?;
+        vault.seal(unlocked.data_key, bytes)?;
+        session.reload(reg);
+
+        println!(
+            "Imported entry '{}' from register '{}'",
+            entry_id, shared.from_register
+        );
+        Ok(())
+    }
+}
+
+fn date(timestamp: i64) -> String {
+    Local
+        .timestamp_opt(timestamp, 0)
+        .single()
+        .map(|at| at.format("%Y-%m-%d %H:%M").to_string())
+        .unwrap_or_else(|| timestamp.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encryption::protected::ProtectedStr;
+    use crate::storage::types::{CreatedBy, Entry, EntryMetadata};
+
+    #[test]
+    fn test_imported_ids_are_checked_and_kept_unique() {
+        let mut reg = Register::new("personal");
+        assert_eq!(
+            EntryShareExec::free_entry_id("En-0000abcd", &reg),
+            "En-0000abcd"
+        );
+
+        reg.entries.push(Entry {
+            entry_id: "En-0000abcd".to_string(),
+            used_for: vec![],
+            password: ProtectedStr::new("hunter22".to_string()),
+            notes: None,
+            username: None,
+            url: None,
+            metadata: EntryMetadata {
+                created_at: 1,
+                modified_at: 1,
+                fetched_cnt: 0,
+                password: ProtectedStr::new(String::new()),
+                strength_score: 0,
+                created_by: CreatedBy::Manual,
+            },
+            custom_field: None,
+        });
+        for id in ["En-0000abcd", "../../etc", "En-0000abcd\n", "", "En-xyz"] {
+            let free = EntryShareExec::free_entry_id(id, &reg);
+            assert!(Register::is_entry_id(&free));
+            assert_ne!(free, "En-0000abcd");
+        }
+    }
+}
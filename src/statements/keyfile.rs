@@ -0,0 +1,18 @@
+use crate::encryption::keyfile;
+use std::path::Path;
+
+type DynError = Box<dyn std::error::Error>;
+
+pub struct KeyfileExec;
+
+impl KeyfileExec {
+    pub fn execute(path: &str) -> Result<(), DynError> {
+        let p = Path::new(path);
+        keyfile::generate(p)?;
+        println!("Keyfile written to {}", p.display());
+        println!(
+            "Keep a copy somewhere safe: a register created WITH KEYFILE cannot be opened without it"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,32 @@
+use super::alter::AlterRegExec;
+use crate::encryption::identity::{self, Identity};
+use crate::encryption::kdf::KdfParams;
+use zeroize::Zeroize;
+
+type DynError = Box<dyn std::error::Error>;
+
+pub struct KeygenExec;
+
+impl KeygenExec {
+    // `pwmn keygen`: a new identity under ~/.pwmn, sealed with a passphrase. Only the
+    // public key ever leaves the machine.
+    pub fn execute() -> Result<(), DynError> {
+        identity::ensure_new()?;
+        println!("Choose a passphrase for the new identity");
+        let mut passphrase = AlterRegExec::prompt_new_password()?;
+        let identity = Identity::generate();
+        let sealed = identity.seal(&passphrase, &KdfParams::default());
+        passphrase.zeroize();
+        let p = identity::write(&sealed?)?;
+
+        println!("Identity written to {}", p.display());
+        println!(
+            "Public key: {}",
+            identity::encode_public(&identity.public())
+        );
+        println!(
+            "Hand the public key to a register's owner, GRANT ACCESS TO '<public key>' lets you in"
+        );
+        Ok(())
+    }
+}
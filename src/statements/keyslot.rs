@@ -0,0 +1,144 @@
+use super::alter::AlterRegExec;
+use super::connect::VaultConnection;
+use crate::encryption::{identity, keyfile};
+use crate::error::{KeySlotErr, SessionErr};
+use crate::interpreter::ast::KeySlotTree;
+use crate::session::SessionConn;
+use crate::storage::throttle;
+use crate::storage::vault::{MAX_KEY_SLOTS, SlotKind};
+use crate::storage::vaultmanager::VaultManager;
+use std::path::Path;
+use zeroize::Zeroize;
+
+type DynError = Box<dyn std::error::Error>;
+
+pub struct KeySlotExec;
+
+impl KeySlotExec {
+    pub fn execute(slots: KeySlotTree, session: &SessionConn) -> Result<(), DynError> {
+        match slots {
+            KeySlotTree::Show => KeySlotExec::show(session),
+            KeySlotTree::Add { keyfile } => KeySlotExec::add(keyfile.as_deref(), session),
+            KeySlotTree::Remove(index) => KeySlotExec::remove(index, session),
+        }
+    }
+
+    // Only the header is read, listing the slots needs no password.
+    pub fn show(session: &SessionConn) -> Result<(), DynError> {
+        if !session.is_connected() {
+            return Err(Box::new(SessionErr::SessionNotConnected));
+        }
+        let manager = VaultManager::load()?;
+        let header = manager
+            .external_vault_load(session.get_base_path())?
+            .load_header()?;
+
+        println!(
+            "{:<5} {:<10} {:<8} {}",
+            "SLOT", "KIND", "KEYFILE", "DETAILS"
+        );
+        for (i, slot) in header.slots.iter().enumerate() {
+            let keyfile = if header.slot_uses_keyfile(slot) {
+                "yes"
+            } else {
+                "no"
+            };
+            // Members are told apart by their public key, their KDF is always the same.
+            let details = match &slot.recipient {
+                Some(recipient) => identity::encode_public(&recipient.member),
+                None => slot.kdf.to_string(),
+            };
+            println!(
+                "{:<5} {:<10} {:<8} {}",
+                i,
+                slot.kind.to_string(),
+                keyfile,
+                details
+            );
+        }
+        println!(
+            "{} of {} key slot(s) in use, data sealed with {}",
+            header.slots.len(),
+            MAX_KEY_SLOTS,
+            header.cipher
+        );
+        Ok(())
+    }
+
+    // Any current password gives up the data key, which the new slot wraps under the
+    // new password. The new slot takes the KDF parameters of the one that opened.
+    pub fn add(keyfile: Option<&str>, session: &SessionConn) -> Result<(), DynError> {
+        session.ensure_writable()?;
+        let keyfile = keyfile.map(Path::new);
+        if let Some(p) = keyfile {
+            keyfile::hash(p)?;
+        }
+
+        let manager = VaultManager::load()?;
+        let mut vault = manager.external_vault_load(session.get_base_path())?;
+        vault.validate_f_header()?;
+        let mut header = vault.load_header()?;
+        if header.slots.len() >= MAX_KEY_SLOTS {
+            return Err(Box::new(KeySlotErr::Full { max: MAX_KEY_SLOTS }));
+        }
+
+        let (_, data_key, slot) = throttle::attempt(&vault.p, || {
+            let mut current = rpassword::prompt_password("Enter a current password: ")?;
+            let unlocked = VaultConnection::unlock_slot(&vault, &current, session.get_keyfile());
+            Zeroize::zeroize(&mut current);
+            unlocked
+        })?;
+
+        let mut password = AlterRegExec::prompt_new_password()?;
+        let kdf = header.slots[slot].kdf;
+        let new_slot = header.password_slot(&password, keyfile, kdf, &data_key);
+        Zeroize::zeroize(&mut password);
+        header.slots.push(new_slot?);
+        let index = header.slots.len() - 1;
+
+        vault.rewrap(header)?;
+        println!("Added key slot {}", index);
+        Ok(())
+    }
+
+    // The password has to open one of the slots that stay, so whoever removes a slot
+    // can still open the register afterwards. The backups lose the slot too, else its
+    // password would still open them with the same data key.
+    pub fn remove(index: usize, session: &SessionConn) -> Result<(), DynError> {
+        session.ensure_writable()?;
+
+        let manager = VaultManager::load()?;
+        let mut vault = manager.external_vault_load(session.get_base_path())?;
+        vault.validate_f_header()?;
+        let mut header = vault.load_header()?;
+        if index >= header.slots.len() {
+            return Err(Box::new(KeySlotErr::NotFound { index }));
+        }
+        // A recovery slot left on its own would force a reset on every connect.
+        if header.slots[index].kind == SlotKind::Password && header.password_slots().count() == 1 {
+            return Err(Box::new(KeySlotErr::LastSlot));
+        }
+        let removed = header.slots.remove(index);
+
+        let given = session.get_keyfile().filter(|_| header.accepts_keyfile());
+        let keyfile = VaultConnection::keyfile_for(&header, given)?;
+        throttle::attempt(&vault.p, || {
+            let mut password =
+                rpassword::prompt_password("Enter the password of a key slot that stays: ")?;
+            let unlocked = header.unlock_key(&password, keyfile.as_deref());
+            Zeroize::zeroize(&mut password);
+            Ok(unlocked?)
+        })?;
+
+        vault.rewrap(header)?;
+        println!(
+            "Removed key slot {}, the slots after it moved down by one",
+            index
+        );
+        let backups = vault.remove_slot_from_backups(&removed)?;
+        if backups > 0 {
+            println!("Also removed it from {} backup(s)", backups);
+        }
+        Ok(())
+    }
+}
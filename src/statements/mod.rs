@@ -1,5 +1,17 @@
+pub mod access;
+pub mod alter;
+pub mod autolock;
+pub mod backup;
 pub mod connect;
 pub mod create;
 pub mod disconnect;
 pub mod drop;
+pub mod entryshare;
+pub mod keyfile;
+pub mod keygen;
+pub mod keyslot;
+pub mod rekey;
+pub mod shares;
+pub mod status;
 pub mod stmt_utl;
+pub mod verify;
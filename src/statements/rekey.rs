@@ -0,0 +1,60 @@
+use super::connect::VaultConnection;
+use super::create::CreateRegExec;
+use crate::encryption::kdf::DEFAULT_KDF_TARGET_MS;
+use crate::session::SessionConn;
+use crate::storage::config::Config;
+use crate::storage::throttle;
+use crate::storage::vaultmanager::VaultManager;
+use zeroize::Zeroize;
+
+type DynError = Box<dyn std::error::Error>;
+
+pub struct RekeyExec;
+
+impl RekeyExec {
+    // Re-derives the password's slot key with Argon2 parameters calibrated on this
+    // machine. The password stays the same; the slot's salt and parameters are replaced.
+    pub fn execute(kdf_calibrate_ms: Option<u64>, session: &SessionConn) -> Result<(), DynError> {
+        session.ensure_writable()?;
+
+        let manager = VaultManager::load()?;
+        let mut vault = manager.external_vault_load(session.get_base_path())?;
+        vault.validate_f_header()?;
+
+        let keyfile = session.get_keyfile();
+        let (mut password, data_key, slot) = throttle::attempt(&vault.p, || {
+            let mut password = rpassword::prompt_password("Enter the vault's password: ")?;
+            match VaultConnection::unlock_slot(&vault, &password, keyfile) {
+                Ok((_, data_key, slot)) => Ok((password, data_key, slot)),
+                Err(e) => {
+                    Zeroize::zeroize(&mut password);
+                    Err(e)
+                }
+            }
+        })?;
+
+        let mut header = vault.load_header()?;
+        let target_ms = kdf_calibrate_ms
+            .or(Config::load(manager.get_root_path())?.kdf_target_ms)
+            .unwrap_or(DEFAULT_KDF_TARGET_MS);
+        let kdf = CreateRegExec::calibrate(target_ms);
+        if kdf.cost() <= header.slots[slot].kdf.cost() {
+            Zeroize::zeroize(&mut password);
+            println!(
+                "The register already uses {}, which is at least as strong. Nothing changed.",
+                header.slots[slot].kdf
+            );
+            return Ok(());
+        }
+
+        // Only the password's key slot is rewrapped, the register data stays as it is.
+        let keyfile = keyfile.filter(|_| header.slot_uses_keyfile(&header.slots[slot]));
+        let new_slot = header.password_slot(&password, keyfile, kdf, &data_key);
+        Zeroize::zeroize(&mut password);
+        header.slots[slot] = new_slot?;
+
+        vault.rewrap(header)?;
+        println!("Register rekeyed with {}", kdf);
+        Ok(())
+    }
+}
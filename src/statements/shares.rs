@@ -0,0 +1,167 @@
+use super::connect::VaultConnection;
+use crate::encryption::shamir::{self, Share};
+use crate::error::{KeySlotErr, ShareErr};
+use crate::session::SessionConn;
+use crate::storage::throttle;
+use crate::storage::vault::{KeySlot, MAX_KEY_SLOTS, Vault};
+use crate::storage::vaultmanager::VaultManager;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use zeroize::Zeroize;
+
+type DynError = Box<dyn std::error::Error>;
+
+pub struct SharesExec;
+
+impl SharesExec {
+    // A new key slot opens with a random secret that is handed out as shares and never
+    // stored. Every split gets its own slot, REMOVE KEYSLOT revokes all of its shares.
+    pub fn split(
+        shares: usize,
+        threshold: usize,
+        dir: Option<&str>,
+        session: &SessionConn,
+    ) -> Result<(), DynError> {
+        session.ensure_writable()?;
+        if threshold < 2 || threshold > shares || shares > shamir::MAX_SHARES {
+            return Err(Box::new(ShareErr::InvalidSplit { shares, threshold }));
+        }
+        let reg_name = session.get_connected_reg_name().unwrap().to_string();
+        let files: Option<Vec<PathBuf>> = dir.map(|dir| {
+            (1..=shares)
+                .map(|x| Path::new(dir).join(format!("{}-share-{}-of-{}.txt", reg_name, x, shares)))
+                .collect()
+        });
+        if let Some(p) = files.iter().flatten().find(|p| p.exists()) {
+            return Err(Box::new(ShareErr::FileExists {
+                path: p.display().to_string(),
+            }));
+        }
+
+        let manager = VaultManager::load()?;
+        let mut vault = manager.external_vault_load(session.get_base_path())?;
+        vault.validate_f_header()?;
+        let mut header = vault.load_header()?;
+        if header.slots.len() >= MAX_KEY_SLOTS {
+            return Err(Box::new(KeySlotErr::Full { max: MAX_KEY_SLOTS }));
+        }
+
+        let (_, data_key, _) = throttle::attempt(&vault.p, || {
+            let mut current = rpassword::prompt_password("Enter a current password: ")?;
+            let unlocked = VaultConnection::unlock_slot(&vault, &current, session.get_keyfile());
+            Zeroize::zeroize(&mut current);
+            unlocked
+        })?;
+
+        let mut secret: [u8; 32] = rand::random();
+        let made = SharesExec::make_split(&header, &secret, &data_key, shares, threshold);
+        secret.zeroize();
+        let (slot, split) = made?;
+        header.slots.push(slot);
+        let index = header.slots.len() - 1;
+
+        // The files go first: should the header write fail they are only stray shares of
+        // a slot that doesn't exist.
+        if let Some(files) = &files {
+            let written = SharesExec::write_files(files, &split, &reg_name);
+            if written.is_err() {
+                files.iter().for_each(|p| {
+                    let _ = fs::remove_file(p);
+                });
+            }
+            written?;
+        }
+        vault.rewrap(header)?;
+
+        println!(
+            "Added key slot {}, any {} of these {} shares open the register:",
+            index, threshold, shares
+        );
+        match &files {
+            Some(files) => files.iter().for_each(|p| println!("  {}", p.display())),
+            None => split
+                .iter()
+                .for_each(|share| println!("  {}", share.encode())),
+        }
+        println!("REMOVE KEYSLOT {} revokes all of them", index);
+        Ok(())
+    }
+
+    fn make_split(
+        header: &Vault,
+        secret: &[u8; 32],
+        data_key: &[u8; 32],
+        shares: usize,
+        threshold: usize,
+    ) -> Result<(KeySlot, Vec<Share>), DynError> {
+        let slot = header.shares_slot(secret, data_key)?;
+        let split = shamir::split(secret, shares, threshold, Vault::share_set(&slot))?;
+        Ok((slot, split))
+    }
+
+    fn write_files(files: &[PathBuf], split: &[Share], reg_name: &str) -> Result<(), DynError> {
+        for (p, share) in files.iter().zip(split) {
+            let mut file = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(p)?;
+            writeln!(
+                file,
+                "# pwmn key share {} of {} for register '{}', any {} of them open it.\n\
+                 # Enter the line below, or the path of this file, at CONNECT {} WITH SHARES;\n{}",
+                share.x,
+                split.len(),
+                reg_name,
+                share.threshold,
+                reg_name,
+                share.encode()
+            )?;
+            file.sync_all()?;
+        }
+        Ok(())
+    }
+
+    // Asks for shares until the threshold of the first one is reached. Each answer is
+    // either a share or the path of a share file.
+    pub fn prompt_shares() -> Result<Vec<Share>, DynError> {
+        let mut shares: Vec<Share> = vec![];
+        loop {
+            let prompt = match shares.first() {
+                None => "Enter a share or the path of a share file: ".to_string(),
+                Some(first) => format!("Enter share {} of {}: ", shares.len() + 1, first.threshold),
+            };
+            let mut typed = rpassword::prompt_password(prompt)?;
+            let share = SharesExec::read_share(typed.trim());
+            Zeroize::zeroize(&mut typed);
+            shares.push(share?);
+            if shares.len() >= shares[0].threshold as usize {
+                return Ok(shares);
+            }
+        }
+    }
+
+    fn read_share(typed: &str) -> Result<Share, ShareErr> {
+        let p = Path::new(typed);
+        if !p.is_file() {
+            return Share::decode(typed);
+        }
+        let mut contents = fs::read_to_string(p).map_err(|e| ShareErr::Malformed {
+            reason: format!("couldn't read '{}': {}", p.display(), e),
+        })?;
+        let share = contents
+            .lines()
+            .map(str::trim)
+            .find(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(Share::decode)
+            .unwrap_or_else(|| {
+                Err(ShareErr::Malformed {
+                    reason: format!("no share in '{}'", p.display()),
+                })
+            });
+        contents.zeroize();
+        share
+    }
+}
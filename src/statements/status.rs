@@ -0,0 +1,47 @@
+use crate::session::SessionConn;
+use crate::session::harden;
+use crate::session::lock::LockMode;
+
+pub struct StatusExec;
+
+impl StatusExec {
+    pub fn execute(session: &SessionConn) {
+        println!("Root: {}", session.get_root_path().display());
+        if let Some(hardening) = harden::applied() {
+            for measure in hardening.measures() {
+                println!("{}", measure);
+            }
+        }
+        match session.get_autolock() {
+            Some(after) => println!("Autolock: after {} idle minute(s)", after.as_secs() / 60),
+            None => println!("Autolock: off"),
+        }
+        let Some(name) = session.get_connected_reg_name() else {
+            println!("Not connected to any register");
+            return;
+        };
+        println!("Connected to '{}'", name);
+        if session.is_locked() {
+            println!("Locked, the next statement on it unlocks it again");
+        }
+
+        match session.get_lock() {
+            Some(lock) => {
+                let access = match lock.mode() {
+                    LockMode::Shared => "READ ONLY, shared lock",
+                    LockMode::Exclusive => "read-write, exclusive lock",
+                };
+                println!("Access: {}", access);
+                println!(
+                    "Lock: {} (held by PID {})",
+                    lock.path().display(),
+                    std::process::id()
+                );
+            }
+            None => println!("Lock: none"),
+        }
+        if let Some(keyfile) = session.get_keyfile() {
+            println!("Keyfile: {}", keyfile.display());
+        }
+    }
+}
@@ -0,0 +1,365 @@
+use super::connect::VaultConnection;
+use super::stmt_utl::confirm;
+use crate::encryption::identity::IDENTITY_N;
+use crate::error::{DecryptionErr, SessionErr};
+use crate::interpreter::ast::VerifyTree;
+use crate::session::SessionConn;
+use crate::session::lock::{DirLock, LOCK_N, LockMode};
+use crate::storage::backup;
+use crate::storage::config::CONFIG_N;
+use crate::storage::enc_auth::AUTH;
+use crate::storage::init;
+use crate::storage::rootmeta::{ROOT_META_N, RootMeta};
+use crate::storage::throttle::{self, EXPECTED_DIR, THROTTLE_KEY_N};
+use crate::storage::types::Register;
+use crate::storage::vault::{VAULT_N, Vault};
+use crate::storage::vaultmanager::{STAGING_PREFIX, VaultManager};
+use crate::storage::vaultmod::VaultMod;
+use std::fs;
+use std::path::{Path, PathBuf};
+use zeroize::Zeroize;
+
+type DynError = Box<dyn std::error::Error>;
+
+// One of the two copies of a register, vault.bin or auth.pwmn, as far as it could be read.
+struct VaultCopy {
+    name: &'static str,
+    bytes: Option<Vec<u8>>,
+    header: Option<Vault>,
+    register: Option<Register>,
+}
+
+impl VaultCopy {
+    fn read(dir: &Path, name: &'static str) -> Self {
+        let bytes = fs::read(dir.join(name)).ok();
+        let header = bytes
+            .as_ref()
+            .and_then(|b| Vault::from_bytes(b).ok())
+            .map(|(header, _)| header);
+        Self {
+            name,
+            bytes,
+            header,
+            register: None,
+        }
+    }
+
+    fn is_good(&self) -> bool {
+        self.register.is_some()
+    }
+}
+
+pub struct VerifyExec;
+
+impl VerifyExec {
+    pub fn execute(verify: VerifyTree, session: &SessionConn) -> Result<(), DynError> {
+        match verify {
+            VerifyTree::Register(name) => VerifyExec::verify_register(&name, session),
+            VerifyTree::All => VerifyExec::verify_all(),
+        }
+    }
+
+    // Checks both copies of a register: header, decryption, agreement between the two,
+    // and the consistency of the decoded register. When only one copy is sound the
+    // other can be rewritten from it.
+    pub fn verify_register(reg_name: &str, session: &SessionConn) -> Result<(), DynError> {
+        let manager = VaultManager::load()?;
+        let (_, child_p) = manager.validate_register(reg_name, false)?;
+
+        // A session connected to this register already holds its lock.
+        let (writable, _lock) = match session.get_connected_reg_name() {
+            Some(current) if current == reg_name => (!session.is_read_only(), None),
+            Some(_) => return Err(Box::new(SessionErr::AnotherSessionIsRunningErr)),
+            None => {
+                let lock = DirLock::acquire(
+                    &child_p,
+                    LockMode::Exclusive,
+                    &format!("Register '{}'", reg_name),
+                )?;
+                (true, Some(lock))
+            }
+        };
+
+        let mut vault = manager.vault_at(&child_p);
+        let mut copies = [
+            VaultCopy::read(&child_p, VAULT_N),
+            VaultCopy::read(&child_p, AUTH),
+        ];
+        let mut problems = 0;
+
+        println!("Verifying register '{}'", reg_name);
+        for copy in &copies {
+            match (&copy.bytes, &copy.header) {
+                (None, _) => {
+                    problems += 1;
+                    println!("  [fail] {}: missing or unreadable", copy.name);
+                }
+                (Some(_), None) => {
+                    problems += 1;
+                    println!("  [fail] {}: invalid or unknown header", copy.name);
+                }
+                (Some(_), Some(header)) => {
+                    let outdated = if header.is_outdated() {
+                        ", upgraded on the next CONNECT"
+                    } else {
+                        ""
+                    };
+                    println!(
+                        "  [ok]   {}: header v{}, {}, {} key slot(s){}",
+                        copy.name,
+                        header.version,
+                        header.cipher,
+                        header.slots.len(),
+                        outdated
+                    );
+                }
+            }
+        }
+
+        if copies.iter().all(|c| c.header.is_none()) {
+            VerifyExec::no_good_copy(&child_p, reg_name)?;
+            return Ok(());
+        }
+
+        // Without a lock of our own the session is connected here and knows the keyfile.
+        let header = copies.iter().find_map(|c| c.header.as_ref()).unwrap();
+        let given = match _lock.is_none() && header.accepts_keyfile() {
+            true => session.get_keyfile(),
+            false => None,
+        };
+        let keyfile = VaultConnection::keyfile_for(header, given)?;
+
+        // A wrong password here is a guess like any other.
+        problems += throttle::attempt(&child_p, || {
+            let mut password = rpassword::prompt_password("Enter the vault's password: ")?;
+            let decrypted =
+                VerifyExec::decrypt_copies(&vault, &mut copies, &password, keyfile.as_deref());
+            Zeroize::zeroize(&mut password);
+            decrypted
+        })?;
+
+        match (&copies[0].bytes, &copies[1].bytes) {
+            (Some(a), Some(b)) if a == b => println!("  [ok]   both copies are identical"),
+            (Some(_), Some(_)) => {
+                problems += 1;
+                println!("  [fail] {} and {} differ", VAULT_N, AUTH);
+            }
+            _ => {}
+        }
+
+        for copy in &copies {
+            let Some(reg) = &copy.register else { continue };
+            let found = reg.validate();
+            if found.is_empty() {
+                println!(
+                    "  [ok]   {}: register is consistent ({} entries)",
+                    copy.name,
+                    reg.entries.len()
+                );
+            }
+            for problem in &found {
+                println!("  [warn] {}: {}", copy.name, problem);
+            }
+        }
+
+        if problems == 0 {
+            println!("Register '{}' is healthy", reg_name);
+            return Ok(());
+        }
+        println!("{} problem(s) found", problems);
+        VerifyExec::repair(&mut vault, &copies, writable, reg_name)
+    }
+
+    // Returns the number of copies that failed. A password that opens neither copy is
+    // reported as a wrong password rather than as damage.
+    fn decrypt_copies(
+        vault: &VaultMod,
+        copies: &mut [VaultCopy; 2],
+        password: &str,
+        keyfile: Option<&Path>,
+    ) -> Result<usize, DynError> {
+        let mut failed = 0;
+        let mut wrong_password = 0;
+        let mut attempted = 0;
+        // Both copies normally share their key slots, the key is derived once for them.
+        let mut derived: Option<(Vault, Option<[u8; 32]>)> = None;
+
+        for copy in copies.iter_mut() {
+            let (Some(bytes), Some(header)) = (&copy.bytes, &copy.header) else {
+                continue;
+            };
+            attempted += 1;
+            let key = match &derived {
+                Some((h, key)) if h.flags == header.flags && h.slots == header.slots => *key,
+                _ => {
+                    let key = match header.unlock_key(password, keyfile) {
+                        Ok((key, _)) => Some(key),
+                        Err(e) if e.downcast_ref::<DecryptionErr>().is_some() => None,
+                        Err(e) => return Err(e),
+                    };
+                    derived = Some((header.clone(), key));
+                    key
+                }
+            };
+
+            let opened = match key {
+                Some(key) => vault
+                    .open(key, bytes)
+                    .and_then(|plaintext| VaultConnection::load_register(&plaintext)),
+                None => Err(Box::new(DecryptionErr::DecryptionErr) as DynError),
+            };
+            match opened {
+                Ok(reg) => {
+                    println!("  [ok]   {}: decrypts and decodes", copy.name);
+                    copy.register = Some(reg);
+                }
+                Err(e) => {
+                    if e.downcast_ref::<DecryptionErr>().is_some() {
+                        wrong_password += 1;
+                    }
+                    failed += 1;
+                    println!("  [fail] {}: {}", copy.name, e);
+                }
+            }
+        }
+
+        if attempted > 0 && wrong_password == attempted {
+            return Err(Box::new(DecryptionErr::DecryptionErr));
+        }
+        Ok(failed)
+    }
+
+    fn repair(
+        vault: &mut VaultMod,
+        copies: &[VaultCopy; 2],
+        writable: bool,
+        reg_name: &str,
+    ) -> Result<(), DynError> {
+        let [vault_copy, auth_copy] = copies;
+        let (source, target) = match (vault_copy.is_good(), auth_copy.is_good()) {
+            (true, _) => (vault_copy, auth_copy),
+            (false, true) => (auth_copy, vault_copy),
+            (false, false) => return VerifyExec::no_good_copy(&vault.p, reg_name),
+        };
+        if target.bytes == source.bytes {
+            // Nothing to copy, the problems are inside the register itself.
+            return Ok(());
+        }
+        if !writable {
+            println!("The register is connected READ ONLY, reconnect without it to repair");
+            return Ok(());
+        }
+        if !confirm(&format!("Rewrite {} from {}?", target.name, source.name))? {
+            println!("Left unchanged");
+            return Ok(());
+        }
+
+        if source.name == VAULT_N {
+            vault.sync_auth()?;
+        } else {
+            // Replacing vault.bin backs up the damaged file first.
+            vault.replace(source.bytes.as_ref().unwrap())?;
+        }
+        println!("Repaired {} from {}", target.name, source.name);
+        Ok(())
+    }
+
+    fn no_good_copy(reg_dir: &Path, reg_name: &str) -> Result<(), DynError> {
+        let backups = backup::list(reg_dir)?;
+        match backups.first() {
+            Some(newest) => println!(
+                "Neither copy can be opened. The newest of {} backup(s) can be restored with RESTORE REGISTER {} FROM BACKUP '{}'",
+                backups.len(),
+                reg_name,
+                newest.timestamp
+            ),
+            None => println!("Neither copy can be opened and there are no backups to restore"),
+        }
+        Ok(())
+    }
+
+    // Register names are only stored hashed, so this checks what can be checked
+    // without a password and reports folders that no longer hold a usable register.
+    pub fn verify_all() -> Result<(), DynError> {
+        // Loading would refuse a root that lost rvault.bin, it is restored first.
+        let root = init::root_path()?;
+        if init::is_initialized(&root)?
+            && RootMeta::load(&root)?.is_none()
+            && VaultManager::restore_root_meta(&root)?.is_some()
+        {
+            println!("Restored {} from the registers' copies", ROOT_META_N);
+        }
+        let manager = VaultManager::load()?;
+        let root = manager.get_root_path();
+        let _lock = DirLock::acquire(root, LockMode::Shared, "The root vault")?;
+
+        let mut entries: Vec<PathBuf> = fs::read_dir(root)?
+            .filter_map(|e| e.ok().map(|e| e.path()))
+            .collect();
+        entries.sort();
+
+        let (mut registers, mut orphaned, mut warnings) = (0, 0, 0);
+        for p in &entries {
+            let name = p
+                .file_name()
+                .map(|n| n.to_string_lossy().to_string())
+                .unwrap_or_default();
+            if !p.is_dir() {
+                if ![LOCK_N, ROOT_META_N, CONFIG_N, IDENTITY_N, THROTTLE_KEY_N]
+                    .contains(&name.as_str())
+                {
+                    warnings += 1;
+                    println!("  [warn] unexpected file {}", name);
+                }
+                continue;
+            }
+            if name.starts_with(STAGING_PREFIX) {
+                warnings += 1;
+                println!(
+                    "  [warn] {}: left by an interrupted CREATE, removed by the next CREATE",
+                    name
+                );
+                continue;
+            }
+            // The root is also the home folder's, which keeps the throttle markers.
+            if name == EXPECTED_DIR {
+                continue;
+            }
+            if !VaultManager::is_register_id(&name) {
+                warnings += 1;
+                println!("  [warn] unknown folder {}", name);
+                continue;
+            }
+
+            registers += 1;
+            let short = &name[..13];
+            let vault_copy = VaultCopy::read(p, VAULT_N);
+            let auth_copy = VaultCopy::read(p, AUTH);
+            match (&vault_copy.header, &auth_copy.header) {
+                (None, None) => {
+                    orphaned += 1;
+                    println!(
+                        "  [fail] {}: orphaned, no readable vault or auth copy",
+                        short
+                    );
+                }
+                (Some(header), _) if vault_copy.bytes == auth_copy.bytes => {
+                    println!("  [ok]   {}: v{}, copies identical", short, header.version);
+                }
+                _ => {
+                    warnings += 1;
+                    println!(
+                        "  [warn] {}: copies differ, run VERIFY REGISTER <name> to repair",
+                        short
+                    );
+                }
+            }
+        }
+
+        println!(
+            "{} register(s), {} orphaned, {} warning(s)",
+            registers, orphaned, warnings
+        );
+        Ok(())
+    }
+}
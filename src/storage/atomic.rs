@@ -0,0 +1,88 @@
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::Duration;
+
+const TMP_SUFFIX: &str = ".tmp";
+// Younger temporary files may still be written by another process, a READ ONLY
+// session records failed unlocks too and recovery runs before any register lock is
+// taken. No write takes anywhere near this long.
+const STALE_AFTER: Duration = Duration::from_secs(60);
+
+// Replaces `p` with `bytes` without ever exposing a partial file: the data goes to a
+// temporary file in the same directory, is flushed to disk, renamed over the original,
+// and the directory entry itself is flushed afterwards. After a crash `p` holds either
+// the old or the new content, at worst with a stray temporary file next to it.
+pub fn write_atomic(p: &Path, bytes: &[u8]) -> io::Result<()> {
+    let dir = p.parent().unwrap_or(Path::new("."));
+    let mut tmp_name = OsString::from(".");
+    tmp_name.push(p.file_name().unwrap_or_default());
+    tmp_name.push(format!(".{:016x}{}", rand::random::<u64>(), TMP_SUFFIX));
+    let tmp = dir.join(tmp_name);
+
+    let written = (|| {
+        let mut file = OpenOptions::new().write(true).create_new(true).open(&tmp)?;
+        file.write_all(bytes)?;
+        file.sync_all()?;
+        fs::rename(&tmp, p)
+    })();
+    if written.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    written?;
+    sync_dir(dir)
+}
+
+// Like `write_atomic`, for a file that must not exist yet: the temporary file is linked
+// into place instead of renamed over it, and an existing `p` fails with AlreadyExists.
+pub fn write_new(p: &Path, bytes: &[u8]) -> io::Result<()> {
+    let dir = p.parent().unwrap_or(Path::new("."));
+    let mut tmp_name = OsString::from(".");
+    tmp_name.push(p.file_name().unwrap_or_default());
+    tmp_name.push(format!(".{:016x}{}", rand::random::<u64>(), TMP_SUFFIX));
+    let tmp = dir.join(tmp_name);
+
+    let written = (|| {
+        let mut file = OpenOptions::new().write(true).create_new(true).open(&tmp)?;
+        file.write_all(bytes)?;
+        file.sync_all()?;
+        fs::hard_link(&tmp, p)
+    })();
+    let _ = fs::remove_file(&tmp);
+    written?;
+    sync_dir(dir)
+}
+
+// Makes renames and newly created entries in `dir` durable.
+pub fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+// Temporary files only survive an interrupted write; they are never the newest
+// complete copy of anything and can be removed once they are old enough.
+pub fn remove_stale(dir: &Path) -> io::Result<usize> {
+    let mut removed = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.starts_with('.')
+            && name.ends_with(TMP_SUFFIX)
+            && entry.file_type()?.is_file()
+            && is_stale(&entry.metadata()?)
+        {
+            fs::remove_file(entry.path())?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+// A modification time in the future counts as fresh, the file goes once the clock
+// has caught up with it.
+fn is_stale(metadata: &fs::Metadata) -> bool {
+    match metadata.modified() {
+        Ok(modified) => modified.elapsed().is_ok_and(|age| age >= STALE_AFTER),
+        Err(_) => true,
+    }
+}
@@ -0,0 +1,387 @@
+use super::atomic;
+use super::config::Config;
+use crate::error::BackupErr;
+use chrono::{Datelike, NaiveDate, NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fmt::Display;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const BACKUP_DIR: &str = "backups";
+pub const RETENTION_N: &str = "retention";
+// Backups still bound to an earlier folder of the register, one folder per folder id.
+// RESTORE binds them to the current folder, see `VaultMod::rebind`.
+pub const STALE_DIR: &str = "stale";
+
+// Backups are named after the UTC time they were taken, e.g. vault-20261019T101530.123Z.bin;
+// the part between the prefix and the extension is what RESTORE expects. A second
+// backup within the same millisecond gets a counter, vault-20261019T101530.123Z-1.bin.
+const TS_FORMAT: &str = "%Y%m%dT%H%M%S%.3fZ";
+const PREFIX: &str = "vault-";
+const EXT: &str = ".bin";
+
+type DynError = Box<dyn std::error::Error>;
+
+// How many backups survive a prune. A backup is kept when any of the three rules
+// selects it: it is among the `last` newest, or it is the newest backup of one of the
+// `daily` most recent days, or of one of the `weekly` most recent ISO weeks. The newest
+// backup is always kept, even when all three are 0, it is the one just taken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionPolicy {
+    pub last: usize,
+    pub daily: usize,
+    pub weekly: usize,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        Self {
+            last: 10,
+            daily: 7,
+            weekly: 4,
+        }
+    }
+}
+
+impl Display for RetentionPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "last {}, daily {}, weekly {}",
+            self.last, self.daily, self.weekly
+        )
+    }
+}
+
+impl RetentionPolicy {
+    // Stored next to the backups as `last=N` lines. Without that file the register
+    // follows the [backup] defaults of the root's config.toml; rules missing from the
+    // file do too.
+    pub fn load(reg_dir: &Path) -> Result<Self, DynError> {
+        let defaults = match reg_dir.parent() {
+            Some(root) => Config::load(root)?.backup,
+            None => Self::default(),
+        };
+        let p = reg_dir.join(BACKUP_DIR).join(RETENTION_N);
+        let content = match fs::read_to_string(&p) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(defaults),
+            Err(e) => return Err(Box::new(e)),
+        };
+
+        let mut policy = defaults;
+        for line in content.lines().filter(|l| !l.trim().is_empty()) {
+            let (key, value) = line
+                .split_once('=')
+                .ok_or(BackupErr::InvalidRetention(line.to_string()))?;
+            let value: usize = value
+                .trim()
+                .parse()
+                .map_err(|_| BackupErr::InvalidRetention(line.to_string()))?;
+            match key.trim() {
+                "last" => policy.last = value,
+                "daily" => policy.daily = value,
+                "weekly" => policy.weekly = value,
+                _ => return Err(Box::new(BackupErr::InvalidRetention(line.to_string()))),
+            }
+        }
+        Ok(policy)
+    }
+
+    pub fn save(&self, reg_dir: &Path) -> Result<(), DynError> {
+        let dir = reg_dir.join(BACKUP_DIR);
+        fs::create_dir_all(&dir)?;
+        let content = format!(
+            "last={}\ndaily={}\nweekly={}\n",
+            self.last, self.daily, self.weekly
+        );
+        atomic::write_atomic(&dir.join(RETENTION_N), content.as_bytes())?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Backup {
+    pub timestamp: String,
+    pub taken: NaiveDateTime,
+    // The counter of backups taken within the same millisecond, 0 for the first.
+    pub seq: u32,
+    pub path: PathBuf,
+    pub size: u64,
+    // The folder id a set-aside backup is bound to, None for the register's own.
+    pub bound_to: Option<String>,
+}
+
+// Copies the current vault.bin of `reg_dir` into its backups folder and prunes the
+// folder with the register's retention policy.
+pub fn snapshot(reg_dir: &Path, vault_bytes: &[u8]) -> Result<PathBuf, DynError> {
+    let dir = reg_dir.join(BACKUP_DIR);
+    fs::create_dir_all(&dir)?;
+    let timestamp = Utc::now().format(TS_FORMAT).to_string();
+    // An existing backup is never replaced, the name takes the next free counter.
+    let mut seq = 0;
+    let p = loop {
+        let p = match seq {
+            0 => dir.join(format!("{PREFIX}{timestamp}{EXT}")),
+            n => dir.join(format!("{PREFIX}{timestamp}-{n}{EXT}")),
+        };
+        match atomic::write_new(&p, vault_bytes) {
+            Ok(()) => break p,
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => seq += 1,
+            Err(e) => return Err(Box::new(e)),
+        }
+    };
+    prune(reg_dir, &RetentionPolicy::load(reg_dir)?)?;
+    Ok(p)
+}
+
+// Newest first. Files that don't follow the naming scheme are left alone.
+pub fn list(reg_dir: &Path) -> Result<Vec<Backup>, DynError> {
+    list_in(&reg_dir.join(BACKUP_DIR), None)
+}
+
+// The backups set aside in backups/stale/, newest first. A prune never sees them.
+pub fn list_stale(reg_dir: &Path) -> Result<Vec<Backup>, DynError> {
+    let dir = reg_dir.join(BACKUP_DIR).join(STALE_DIR);
+    if !dir.try_exists()? {
+        return Ok(Vec::new());
+    }
+    let mut backups = Vec::new();
+    for entry in fs::read_dir(&dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            let id = entry.file_name().to_string_lossy().to_string();
+            backups.extend(list_in(&entry.path(), Some(&id))?);
+        }
+    }
+    backups.sort_by(|a, b| (b.taken, b.seq).cmp(&(a.taken, a.seq)));
+    Ok(backups)
+}
+
+fn list_in(dir: &Path, bound_to: Option<&str>) -> Result<Vec<Backup>, DynError> {
+    if !dir.try_exists()? {
+        return Ok(Vec::new());
+    }
+
+    let mut backups = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        let Some(timestamp) = name.strip_prefix(PREFIX).and_then(|n| n.strip_suffix(EXT)) else {
+            continue;
+        };
+        let Some((taken, seq)) = parse_timestamp(timestamp) else {
+            continue;
+        };
+        backups.push(Backup {
+            timestamp: timestamp.to_string(),
+            taken,
+            seq,
+            path: entry.path(),
+            size: entry.metadata()?.len(),
+            bound_to: bound_to.map(String::from),
+        });
+    }
+    backups.sort_by(|a, b| (b.taken, b.seq).cmp(&(a.taken, a.seq)));
+    Ok(backups)
+}
+
+// The time and counter of a backup name, see TS_FORMAT.
+fn parse_timestamp(timestamp: &str) -> Option<(NaiveDateTime, u32)> {
+    let (time, seq) = match timestamp.split_once('-') {
+        Some((time, seq)) => (time, seq.parse().ok().filter(|&n| n > 0)?),
+        None => (timestamp, 0),
+    };
+    Some((NaiveDateTime::parse_from_str(time, TS_FORMAT).ok()?, seq))
+}
+
+// Moves a backup that is bound to the folder id `bound_to` into backups/stale/, where
+// neither `list` nor a prune finds it.
+pub fn set_aside(reg_dir: &Path, backup: &Backup, bound_to: &str) -> Result<PathBuf, DynError> {
+    let dir = reg_dir.join(BACKUP_DIR).join(STALE_DIR).join(bound_to);
+    fs::create_dir_all(&dir)?;
+    let p = dir.join(backup.path.file_name().unwrap_or_default());
+    fs::rename(&backup.path, &p)?;
+    atomic::sync_dir(&dir)?;
+    Ok(p)
+}
+
+// The register's own backups first, then the ones set aside.
+pub fn find(reg_dir: &Path, timestamp: &str) -> Result<Backup, DynError> {
+    list(reg_dir)?
+        .into_iter()
+        .chain(list_stale(reg_dir)?)
+        .find(|b| b.timestamp == timestamp)
+        .ok_or_else(|| {
+            Box::new(BackupErr::NotFound {
+                timestamp: timestamp.to_string(),
+            }) as DynError
+        })
+}
+
+// Removes every backup the policy doesn't select and returns the ones that remain.
+pub fn prune(reg_dir: &Path, policy: &RetentionPolicy) -> Result<Vec<Backup>, DynError> {
+    let backups = list(reg_dir)?;
+    let keep = select(&backups, policy);
+
+    let mut kept = Vec::new();
+    for (i, backup) in backups.into_iter().enumerate() {
+        if keep.contains(&i) {
+            kept.push(backup);
+        } else {
+            fs::remove_file(&backup.path)?;
+        }
+    }
+    Ok(kept)
+}
+
+// `backups` is sorted newest first, so the first backup seen for a day or week is the
+// newest one of that period.
+fn select(backups: &[Backup], policy: &RetentionPolicy) -> HashSet<usize> {
+    let mut keep: HashSet<usize> = (0..backups.len().min(policy.last.max(1))).collect();
+
+    let mut days: Vec<NaiveDate> = Vec::new();
+    let mut weeks: Vec<(i32, u32)> = Vec::new();
+    for (i, backup) in backups.iter().enumerate() {
+        let day = backup.taken.date();
+        if !days.contains(&day) && days.len() < policy.daily {
+            days.push(day);
+            keep.insert(i);
+        }
+        let week = (day.iso_week().year(), day.iso_week().week());
+        if !weeks.contains(&week) && weeks.len() < policy.weekly {
+            weeks.push(week);
+            keep.insert(i);
+        }
+    }
+    keep
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backup_at(ts: &str) -> Backup {
+        Backup {
+            timestamp: ts.to_string(),
+            taken: NaiveDateTime::parse_from_str(ts, TS_FORMAT).unwrap(),
+            seq: 0,
+            path: PathBuf::new(),
+            size: 0,
+            bound_to: None,
+        }
+    }
+
+    #[test]
+    fn test_retention_keeps_last_daily_and_weekly() {
+        // Newest first: three saves on Oct 21, one on Oct 20, one on Oct 12 and one on Oct 1.
+        let backups: Vec<Backup> = [
+            "20261021T120000.000Z",
+            "20261021T110000.000Z",
+            "20261021T100000.000Z",
+            "20261020T100000.000Z",
+            "20261012T100000.000Z",
+            "20261001T100000.000Z",
+        ]
+        .iter()
+        .map(|ts| backup_at(ts))
+        .collect();
+
+        let policy = RetentionPolicy {
+            last: 2,
+            daily: 2,
+            weekly: 0,
+        };
+        let mut kept: Vec<usize> = select(&backups, &policy).into_iter().collect();
+        kept.sort();
+        assert_eq!(kept, vec![0, 1, 3]);
+
+        // Oct 21 and Oct 20 share an ISO week, Oct 12 and Oct 1 each have their own.
+        let policy = RetentionPolicy {
+            last: 0,
+            daily: 0,
+            weekly: 3,
+        };
+        let mut kept: Vec<usize> = select(&backups, &policy).into_iter().collect();
+        kept.sort();
+        assert_eq!(kept, vec![0, 4, 5]);
+
+        // Nothing selected by the rules, the backup just taken stays.
+        let policy = RetentionPolicy {
+            last: 0,
+            daily: 0,
+            weekly: 0,
+        };
+        let kept: Vec<usize> = select(&backups, &policy).into_iter().collect();
+        assert_eq!(kept, vec![0]);
+        assert!(select(&[], &policy).is_empty());
+    }
+
+    #[test]
+    fn test_snapshot_survives_a_zero_policy() {
+        let dir = std::env::temp_dir().join(format!("pwmn-backup-{}", uuid::Uuid::new_v4()));
+        RetentionPolicy {
+            last: 0,
+            daily: 0,
+            weekly: 0,
+        }
+        .save(&dir)
+        .unwrap();
+
+        let first = snapshot(&dir, b"first").unwrap();
+        assert_eq!(fs::read(&first).unwrap(), b"first");
+        let second = snapshot(&dir, b"second").unwrap();
+        assert!(!first.exists());
+        let backups = list(&dir).unwrap();
+        assert_eq!(backups.len(), 1);
+        assert_eq!(backups[0].path, second);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_backups_in_the_same_millisecond_are_all_kept() {
+        let dir = std::env::temp_dir().join(format!("pwmn-backup-{}", uuid::Uuid::new_v4()));
+        let taken: Vec<PathBuf> = (0..5)
+            .map(|i| snapshot(&dir, format!("save {}", i).as_bytes()).unwrap())
+            .collect();
+
+        // Newest first, each with its own content.
+        let backups = list(&dir).unwrap();
+        assert_eq!(backups.len(), 5);
+        for (i, b) in backups.iter().rev().enumerate() {
+            assert_eq!(b.path, taken[i]);
+            assert_eq!(fs::read(&b.path).unwrap(), format!("save {}", i).as_bytes());
+            assert_eq!(find(&dir, &b.timestamp).unwrap().path, b.path);
+        }
+
+        let at = "20261019T101530.123Z";
+        assert_eq!(parse_timestamp(at).unwrap().1, 0);
+        assert_eq!(parse_timestamp(&format!("{at}-2")).unwrap().1, 2);
+        assert!(parse_timestamp(&format!("{at}-0")).is_none());
+        assert!(parse_timestamp(&format!("{at}-x")).is_none());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_retention_file_roundtrip() {
+        let dir = std::env::temp_dir().join(format!("pwmn-backup-{}", uuid::Uuid::new_v4()));
+        assert_eq!(
+            RetentionPolicy::load(&dir).unwrap(),
+            RetentionPolicy::default()
+        );
+
+        let policy = RetentionPolicy {
+            last: 3,
+            daily: 0,
+            weekly: 12,
+        };
+        policy.save(&dir).unwrap();
+        assert_eq!(RetentionPolicy::load(&dir).unwrap(), policy);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
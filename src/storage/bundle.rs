@@ -0,0 +1,309 @@
+use crate::encryption::aead;
+use crate::encryption::identity::{self, Identity};
+use crate::encryption::kdf::{KdfParams, derive_slow_key};
+use crate::error::BundleErr;
+use crate::storage::types::Entry;
+use bincode::{Decode, Encode};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use zeroize::Zeroize;
+
+type DynError = Box<dyn std::error::Error>;
+
+// A single entry handed to someone outside the register, see EXPORT ENTRY:
+//
+//   [magic 4][version 2][lock 1][expires 8][lock fields][nonce 12][sealed entry]
+//
+// A passphrase lock carries [kdf 12][salt 16], a member lock [member 32][ephemeral 32].
+// Everything in front of the sealed entry is authenticated with it, so the expiry
+// can't be pushed back without breaking the file. An expiry of 0 never expires.
+const MAGIC: [u8; 4] = *b"PWSH";
+const VERSION: u16 = 1;
+const PREFIX_LEN: usize = 4 + 2 + 1 + 8;
+const LOCK_PASSPHRASE: u8 = 1;
+const LOCK_MEMBER: u8 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lock {
+    Passphrase {
+        kdf: KdfParams,
+        salt: [u8; 16],
+    },
+    Member {
+        member: [u8; 32],
+        ephemeral: [u8; 32],
+    },
+}
+
+// What the file carries: the entry and where it came from.
+#[derive(Debug, Encode, Decode)]
+pub struct Shared {
+    pub entry: Entry,
+    pub from_register: String,
+    pub exported_at: i64,
+}
+
+pub struct Bundle {
+    pub lock: Lock,
+    pub expires_at: Option<i64>,
+    header: Vec<u8>,
+    nonce: [u8; 12],
+    sealed: Vec<u8>,
+}
+
+impl Bundle {
+    pub fn seal_with_passphrase(
+        shared: &Shared,
+        passphrase: &str,
+        kdf: &KdfParams,
+        expires_at: Option<i64>,
+    ) -> Result<Vec<u8>, DynError> {
+        let salt: [u8; 16] = rand::random();
+        let key = derive_slow_key(passphrase, &salt, kdf);
+        let lock = Lock::Passphrase { kdf: *kdf, salt };
+        Bundle::seal(shared, lock, *key, expires_at)
+    }
+
+    // Only the holder of the identity behind `member` opens it.
+    pub fn seal_for(
+        shared: &Shared,
+        member: &[u8; 32],
+        expires_at: Option<i64>,
+    ) -> Result<Vec<u8>, DynError> {
+        let (ephemeral, key) = identity::agree_with(member)?;
+        let lock = Lock::Member {
+            member: *member,
+            ephemeral,
+        };
+        Bundle::seal(shared, lock, key, expires_at)
+    }
+
+    fn seal(
+        shared: &Shared,
+        lock: Lock,
+        mut key: [u8; 32],
+        expires_at: Option<i64>,
+    ) -> Result<Vec<u8>, DynError> {
+        let mut bytes = header_bytes(&lock, expires_at);
+        let nonce: [u8; 12] = rand::random();
+        bytes.extend_from_slice(&nonce);
+        let plaintext = bincode::encode_to_vec(shared, bincode::config::standard())?;
+        let sealed = aead::encrypt(key, nonce, plaintext, &bytes[..bytes.len() - 12]);
+        key.zeroize();
+        bytes.extend_from_slice(&sealed?);
+        Ok(bytes)
+    }
+
+    // Reads the parts in the clear, nothing is authenticated before one of the `open_*`.
+    pub fn parse(bytes: &[u8]) -> Result<Bundle, BundleErr> {
+        if bytes.len() < PREFIX_LEN || bytes[..4] != MAGIC || bytes[4..6] != VERSION.to_le_bytes() {
+            return Err(BundleErr::Malformed);
+        }
+        let expires = i64::from_le_bytes(bytes[7..15].try_into().unwrap());
+        let (lock, fields_len) = match bytes[6] {
+            LOCK_PASSPHRASE if bytes.len() >= PREFIX_LEN + 28 => {
+                let field = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+                let kdf = KdfParams {
+                    m_cost: field(PREFIX_LEN),
+                    t_cost: field(PREFIX_LEN + 4),
+                    p_cost: field(PREFIX_LEN + 8),
+                };
+                if !kdf.is_valid() {
+                    return Err(BundleErr::Malformed);
+                }
+                let salt = bytes[PREFIX_LEN + 12..PREFIX_LEN + 28].try_into().unwrap();
+                (Lock::Passphrase { kdf, salt }, 28)
+            }
+            LOCK_MEMBER if bytes.len() >= PREFIX_LEN + 64 => (
+                Lock::Member {
+                    member: bytes[PREFIX_LEN..PREFIX_LEN + 32].try_into().unwrap(),
+                    ephemeral: bytes[PREFIX_LEN + 32..PREFIX_LEN + 64].try_into().unwrap(),
+                },
+                64,
+            ),
+            _ => return Err(BundleErr::Malformed),
+        };
+        let header_len = PREFIX_LEN + fields_len;
+        if bytes.len() < header_len + 12 + 16 {
+            return Err(BundleErr::Malformed);
+        }
+        Ok(Bundle {
+            lock,
+            expires_at: (expires != 0).then_some(expires),
+            header: bytes[..header_len].to_vec(),
+            nonce: bytes[header_len..header_len + 12].try_into().unwrap(),
+            sealed: bytes[header_len + 12..].to_vec(),
+        })
+    }
+
+    pub fn is_expired(&self, now: i64) -> bool {
+        self.expires_at.is_some_and(|at| now >= at)
+    }
+
+    pub fn open_with_passphrase(&self, passphrase: &str) -> Result<Shared, BundleErr> {
+        let Lock::Passphrase { kdf, salt } = self.lock else {
+            return Err(BundleErr::Malformed);
+        };
+        let key = derive_slow_key(passphrase, &salt, &kdf);
+        self.open(*key).ok_or(BundleErr::WrongPassphrase)
+    }
+
+    pub fn open_with_identity(&self, identity: &Identity) -> Result<Shared, BundleErr> {
+        let Lock::Member { member, ephemeral } = self.lock else {
+            return Err(BundleErr::Malformed);
+        };
+        if member != identity.public() {
+            return Err(BundleErr::NotForYou {
+                public: identity::encode_public(&member),
+            });
+        }
+        let key = identity
+            .agree(&ephemeral)
+            .map_err(|_| BundleErr::Malformed)?;
+        self.open(key).ok_or(BundleErr::Tampered)
+    }
+
+    fn open(&self, mut key: [u8; 32]) -> Option<Shared> {
+        let opened = aead::decrypt(key, self.nonce, self.sealed.clone(), &self.header);
+        key.zeroize();
+        let plaintext = opened.ok()?;
+        bincode::decode_from_slice(&plaintext, bincode::config::standard())
+            .ok()
+            .map(|(shared, _)| shared)
+    }
+}
+
+fn header_bytes(lock: &Lock, expires_at: Option<i64>) -> Vec<u8> {
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend_from_slice(&VERSION.to_le_bytes());
+    match lock {
+        Lock::Passphrase { .. } => bytes.push(LOCK_PASSPHRASE),
+        Lock::Member { .. } => bytes.push(LOCK_MEMBER),
+    }
+    bytes.extend_from_slice(&expires_at.unwrap_or(0).to_le_bytes());
+    match lock {
+        Lock::Passphrase { kdf, salt } => {
+            for cost in [kdf.m_cost, kdf.t_cost, kdf.p_cost] {
+                bytes.extend_from_slice(&cost.to_le_bytes());
+            }
+            bytes.extend_from_slice(salt);
+        }
+        Lock::Member { member, ephemeral } => {
+            bytes.extend_from_slice(member);
+            bytes.extend_from_slice(ephemeral);
+        }
+    }
+    bytes
+}
+
+// Fails before anything is asked for when the file already exists.
+pub fn ensure_new(p: &Path) -> Result<(), BundleErr> {
+    if p.exists() {
+        return Err(BundleErr::FileExists {
+            path: p.display().to_string(),
+        });
+    }
+    Ok(())
+}
+
+pub fn write(p: &Path, bytes: &[u8]) -> Result<(), DynError> {
+    ensure_new(p)?;
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(p)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encryption::protected::ProtectedStr;
+    use crate::storage::types::{CreatedBy, EntryMetadata};
+    use secrecy::ExposeSecret;
+
+    const TEST_KDF: KdfParams = KdfParams {
+        m_cost: 64,
+        t_cost: 1,
+        p_cost: 1,
+    };
+
+    fn shared() -> Shared {
+        Shared {
+            entry: Entry {
+                entry_id: "En-0000abcd".to_string(),
+                used_for: vec!["ci".to_string()],
+                password: ProtectedStr::new("hunter22".to_string()),
+                notes: None,
+                username: Some("deploy".to_string()),
+                url: None,
+                metadata: EntryMetadata {
+                    created_at: 1,
+                    modified_at: 2,
+                    fetched_cnt: 0,
+                    password: ProtectedStr::new(String::new()),
+                    strength_score: 0,
+                    created_by: CreatedBy::Manual,
+                },
+                custom_field: None,
+            },
+            from_register: "personal".to_string(),
+            exported_at: 3,
+        }
+    }
+
+    #[test]
+    fn test_passphrase_bundle_roundtrip() {
+        let bytes =
+            Bundle::seal_with_passphrase(&shared(), "passphrase", &TEST_KDF, Some(100)).unwrap();
+        let bundle = Bundle::parse(&bytes).unwrap();
+        assert_eq!(bundle.expires_at, Some(100));
+        assert!(!bundle.is_expired(99));
+        assert!(bundle.is_expired(100));
+
+        let opened = bundle.open_with_passphrase("passphrase").unwrap();
+        assert_eq!(opened.entry.password.reveal().expose_secret(), "hunter22");
+        assert_eq!(opened.from_register, "personal");
+        assert!(matches!(
+            bundle.open_with_passphrase("wrong"),
+            Err(BundleErr::WrongPassphrase)
+        ));
+
+        // Pushing the expiry back breaks the authentication.
+        let mut extended = bytes.clone();
+        extended[7..15].copy_from_slice(&0i64.to_le_bytes());
+        let extended = Bundle::parse(&extended).unwrap();
+        assert_eq!(extended.expires_at, None);
+        assert!(extended.open_with_passphrase("passphrase").is_err());
+        assert!(Bundle::parse(&bytes[..20]).is_err());
+    }
+
+    #[test]
+    fn test_member_bundle_opens_for_its_member_only() {
+        let member = Identity::generate();
+        let bytes = Bundle::seal_for(&shared(), &member.public(), None).unwrap();
+        let bundle = Bundle::parse(&bytes).unwrap();
+        assert_eq!(bundle.expires_at, None);
+        assert_eq!(
+            bundle.open_with_identity(&member).unwrap().entry.entry_id,
+            "En-0000abcd"
+        );
+        assert!(matches!(
+            bundle.open_with_identity(&Identity::generate()),
+            Err(BundleErr::NotForYou { .. })
+        ));
+
+        let mut altered = bytes.clone();
+        let last = altered.len() - 1;
+        altered[last] ^= 0x01;
+        assert!(matches!(
+            Bundle::parse(&altered).unwrap().open_with_identity(&member),
+            Err(BundleErr::Tampered)
+        ));
+    }
+}
@@ -0,0 +1,92 @@
+use super::atomic;
+use super::backup::RetentionPolicy;
+use crate::error::ConfigErr;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+pub const CONFIG_N: &str = "config.toml";
+
+// Written by INIT; every key is optional and falls back to the value shown.
+const TEMPLATE: &str = r#"# pwmn configuration. Every setting is optional.
+
+# Unlock time in milliseconds that CREATE and REKEY calibrate Argon2id for.
+# Unset, CREATE uses the built-in parameters and REKEY targets 1000 ms.
+# kdf_target_ms = 1000
+
+# Lock a connected register after this many idle minutes, 0 never locks.
+autolock_minutes = 0
+
+# Backup retention for registers without their own SET BACKUP RETENTION.
+[backup]
+last = 10
+daily = 7
+weekly = 4
+"#;
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub kdf_target_ms: Option<u64>,
+    pub autolock_minutes: u64,
+    pub backup: RetentionPolicy,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            kdf_target_ms: None,
+            autolock_minutes: 0,
+            backup: RetentionPolicy::default(),
+        }
+    }
+}
+
+impl Config {
+    // A root without config.toml, e.g. one created before it existed, uses the defaults.
+    pub fn load(root: &Path) -> Result<Self, ConfigErr> {
+        let p = root.join(CONFIG_N);
+        let invalid = |reason: String| ConfigErr::Invalid {
+            path: p.display().to_string(),
+            reason,
+        };
+        let content = match fs::read_to_string(&p) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(invalid(e.to_string())),
+        };
+        let config: Config = toml::from_str(&content).map_err(|e| invalid(e.to_string()))?;
+        if config.kdf_target_ms == Some(0) {
+            return Err(invalid("kdf_target_ms must be greater than 0".to_string()));
+        }
+        Ok(config)
+    }
+
+    pub fn write_template(root: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        atomic::write_atomic(&root.join(CONFIG_N), TEMPLATE.as_bytes())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_template_matches_defaults() {
+        let parsed: Config = toml::from_str(TEMPLATE).unwrap();
+        assert_eq!(parsed, Config::default());
+    }
+
+    #[test]
+    fn test_partial_and_invalid_configs() {
+        let parsed: Config = toml::from_str("autolock_minutes = 5\n[backup]\nlast = 3\n").unwrap();
+        assert_eq!(parsed.autolock_minutes, 5);
+        assert_eq!(parsed.backup.last, 3);
+        assert_eq!(parsed.backup.daily, RetentionPolicy::default().daily);
+        assert_eq!(parsed.kdf_target_ms, None);
+
+        assert!(toml::from_str::<Config>("autolock_minutes = \"soon\"").is_err());
+        assert!(toml::from_str::<Config>("clipboard_timeout = 5").is_err());
+    }
+}
@@ -1,11 +1,17 @@
 use crate::{
-    encryption::{
-        aead::decrypt,
-        kdf::{derive_fast_key, derive_slow_key},
-    },
+    encryption::{aead::decrypt, kdf::derive_fast_key},
     error::{AuthErr, FileReqErr},
+    storage::{
+        vault::{MAGIC, Vault},
+        vaultmod::VaultMod,
+    },
 };
-use std::{fs::OpenOptions, io::Read, path::PathBuf};
+use std::{
+    fs::OpenOptions,
+    io::Read,
+    path::{Path, PathBuf},
+};
+use zeroize::Zeroizing;
 
 pub const AUTH: &str = "auth.pwmn";
 pub struct Auth {
@@ -34,18 +40,29 @@ impl Auth {
         Ok(Self { file })
     }
 
+    // The auth file mirrors vault.bin, header included. Files written before that
+    // only hold the ciphertext and still rely on the salt and nonce of the vault header,
+    // which has to be loaded. A file with a header that doesn't read is an error, not
+    // one of those.
     pub fn connect(
         &self,
         prompt: &str,
-        salt: &[u8; 16],
-        nonce: &[u8; 12],
+        vault: &VaultMod,
+        keyfile: Option<&Path>,
     ) -> Result<(), Box<dyn std::error::Error>> {
         let mut file = OpenOptions::new().write(true).read(true).open(&self.file)?;
         let mut buffer: Vec<u8> = Vec::new();
         file.read_to_end(&mut buffer)?;
-        let password = rpassword::prompt_password(prompt)?;
-        let key = derive_slow_key(&password, salt);
-        decrypt(key, *nonce, buffer)?;
+        let password = Zeroizing::new(rpassword::prompt_password(prompt)?);
+        if buffer.starts_with(&MAGIC) {
+            let (header, _) = Vault::from_bytes(&buffer)?;
+            let key = Zeroizing::new(header.unlock_key(&password, keyfile)?.0);
+            vault.open(*key, &buffer)?;
+        } else {
+            let header = vault.header.as_ref().ok_or(AuthErr::HeaderNotLoaded)?;
+            let key = Zeroizing::new(header.unlock_key(&password, keyfile)?.0);
+            header.cipher.open(&key, &header.nonce, &buffer, &[])?;
+        }
         Ok(())
     }
 }
@@ -1,27 +1,110 @@
+use super::config::Config;
+use super::rootmeta::RootMeta;
+use super::throttle::{EXPECTED_DIR, THROTTLE_KEY_N};
+use crate::encryption::identity::IDENTITY_N;
 use crate::error::{self, InitErr};
 
 use std::{
     env,
     fs::{self, File, create_dir_all as mksafe_dir},
     path::{self, Path, PathBuf},
+    sync::OnceLock,
 };
 
 // Private root vault to handle all other child files.
 pub const ROOT_REG: &str = ".pwmn"; // Parent Folder Name, handled by INIT
 
-pub fn init() -> Result<(), Box<dyn std::error::Error>> {
+// Environment variable naming the root folder itself, e.g. a vault on a mounted volume.
+pub const ROOT_ENV: &str = "PWMN_HOME";
+
+// Set once from `--root <path>`; takes precedence over PWMN_HOME and ~/.pwmn.
+static ROOT_OVERRIDE: OnceLock<PathBuf> = OnceLock::new();
+
+pub fn set_root_override(p: PathBuf) -> Result<(), Box<dyn std::error::Error>> {
+    let p = path::absolute(p)?;
+    ROOT_OVERRIDE
+        .set(p)
+        .map_err(|_| "The root folder can only be chosen once")?;
+    Ok(())
+}
+
+// The root folder every register lives in: --root, then PWMN_HOME, then ~/.pwmn.
+pub fn root_path() -> Result<PathBuf, Box<dyn std::error::Error>> {
+    if let Some(p) = ROOT_OVERRIDE.get() {
+        return Ok(p.clone());
+    }
+    if let Some(p) = env::var_os(ROOT_ENV).filter(|p| !p.is_empty()) {
+        return Ok(path::absolute(PathBuf::from(p))?);
+    }
     let home = dirs_next::home_dir().ok_or(error::HomeDirErr::InvalidHomeDir)?;
+    Ok(home.join(ROOT_REG))
+}
+
+// A root folder holding nothing but the identity of `pwmn keygen` and the throttle
+// key and markers hasn't been initialized yet, those share ~/.pwmn with the default
+// root whichever root is in use.
+pub fn is_initialized(root: &Path) -> std::io::Result<bool> {
+    if !root.try_exists()? {
+        return Ok(false);
+    }
+    for entry in fs::read_dir(root)? {
+        let name = entry?.file_name();
+        if ![IDENTITY_N, THROTTLE_KEY_N, EXPECTED_DIR].contains(&name.to_str().unwrap_or_default())
+        {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+pub fn init() -> Result<(), Box<dyn std::error::Error>> {
+    init_at(&root_path()?)
+}
 
-    let root_folder = home.join(ROOT_REG);
-    if root_folder.try_exists()? {
+fn init_at(root_folder: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    if is_initialized(root_folder)? {
         return Err(Box::new(InitErr::RootVaultAlreadyExists));
     }
-    mksafe_dir(&root_folder)?;
+    mksafe_dir(root_folder)?;
+    RootMeta::generate().save(root_folder)?;
+    Config::write_template(root_folder)?;
     let s_msg = format!(
-        "Initialized an empty  repository in {}/{}",
-        env::var("HOME").unwrap(),
-        ROOT_REG
+        "Initialized an empty  repository in {}",
+        root_folder.display()
     );
     println!("{s_msg}");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::DecryptionErr;
+    use crate::storage::throttle;
+
+    #[test]
+    fn test_throttle_files_dont_initialize_the_default_root() {
+        let base = env::temp_dir().join(format!("pwmn-test-{}", uuid::Uuid::new_v4()));
+        // ~/.pwmn holds the throttle key and markers of a register in another root.
+        let home_root = base.join(ROOT_REG);
+        let other_reg = base.join("other").join("reg");
+        mksafe_dir(&other_reg).unwrap();
+        let failed = throttle::attempt_in(
+            &home_root,
+            &other_reg,
+            || -> Result<(), Box<dyn std::error::Error>> {
+                Err(Box::new(DecryptionErr::DecryptionErr))
+            },
+        );
+        assert!(failed.is_err());
+        assert!(home_root.join(THROTTLE_KEY_N).exists());
+        assert!(home_root.join(EXPECTED_DIR).exists());
+
+        assert!(!is_initialized(&home_root).unwrap());
+        init_at(&home_root).unwrap();
+        assert!(is_initialized(&home_root).unwrap());
+        assert!(init_at(&home_root).is_err());
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
@@ -0,0 +1,149 @@
+use crate::encryption::recovery;
+use crate::error::RecoveryErr;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+type DynError = Box<dyn std::error::Error>;
+
+// What goes on an emergency kit, the sheet to print and keep with the recovery key.
+pub struct Kit<'a> {
+    pub reg_name: &'a str,
+    pub root: &'a Path,
+    pub phrase: &'a str,
+    pub created: String,
+}
+
+impl Kit<'_> {
+    // A path ending in .html or .htm gets a page to print from a browser, anything
+    // else plain text.
+    pub fn render_for(&self, p: &Path) -> String {
+        match p.extension().and_then(|e| e.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("html") || ext.eq_ignore_ascii_case("htm") => {
+                self.html()
+            }
+            _ => self.text(),
+        }
+    }
+
+    pub fn text(&self) -> String {
+        let mut out = String::new();
+        out.push_str("PWMN EMERGENCY KIT\n==================\n\n");
+        out.push_str(&format!("Register:  {}\n", self.reg_name));
+        out.push_str(&format!("Root:      {}\n", self.root.display()));
+        out.push_str(&format!("Created:   {}\n\n", self.created));
+        out.push_str("Recovery key:\n\n");
+        for line in recovery::lines(self.phrase) {
+            out.push_str(&format!("  {}\n", line));
+        }
+        out.push_str(&format!("\n{}\n", self.instructions()));
+        out
+    }
+
+    pub fn html(&self) -> String {
+        let rows: String = recovery::lines(self.phrase)
+            .iter()
+            .map(|line| format!("      <tr><td>{}</td></tr>\n", escape(line)))
+            .collect();
+        format!(
+            "<!DOCTYPE html>
+<html>
+  <head>
+    <meta charset=\"utf-8\">
+    <title>pwmn emergency kit: {name}</title>
+    <style>
+      body {{ font-family: sans-serif; max-width: 40em; margin: 2em auto; }}
+      td {{ font-family: monospace; font-size: 1.2em; white-space: pre; padding: 0.2em 0; }}
+    </style>
+  </head>
+  <body>
+    <h1>pwmn emergency kit</h1>
+    <p>Register: <b>{name}</b><br>Root: <code>{root}</code><br>Created: {created}</p>
+    <h2>Recovery key</h2>
+    <table>
+{rows}    </table>
+    <p>{instructions}</p>
+  </body>
+</html>
+",
+            name = escape(self.reg_name),
+            root = escape(&self.root.display().to_string()),
+            created = escape(&self.created),
+            rows = rows,
+            instructions = escape(&self.instructions()),
+        )
+    }
+
+    fn instructions(&self) -> String {
+        format!(
+            "The recovery key opens the register without its password or keyfile. \
+             Keep this sheet offline and out of sight. To use it, run \
+             CONNECT {} WITH RECOVERY KEY; and set a new password when asked.",
+            self.reg_name
+        )
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// Fails before anything is created when the kit file already exists, so CREATE can
+// check the path up front.
+pub fn ensure_new(p: &Path) -> Result<(), RecoveryErr> {
+    if p.exists() {
+        return Err(RecoveryErr::KitExists {
+            path: p.display().to_string(),
+        });
+    }
+    Ok(())
+}
+
+// Readable by its owner only, it holds a key to the register.
+pub fn write(p: &Path, contents: &str) -> Result<(), DynError> {
+    ensure_new(p)?;
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(p)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kit_holds_the_key_in_both_formats() {
+        let phrase = recovery::generate();
+        let kit = Kit {
+            reg_name: "personal",
+            root: Path::new("/home/<me>/.pwmn"),
+            phrase: &phrase,
+            created: "2026-01-01 10:00".to_string(),
+        };
+        let first_word = phrase.split(' ').next().unwrap();
+
+        let text = kit.render_for(Path::new("kit.txt"));
+        assert!(text.contains("Register:  personal"));
+        assert!(text.contains("/home/<me>/.pwmn"));
+        assert!(text.contains(&format!(" 1. {}", first_word)));
+
+        let html = kit.render_for(Path::new("kit.HTML"));
+        assert!(html.starts_with("<!DOCTYPE html>"));
+        assert!(html.contains("/home/&lt;me&gt;/.pwmn"));
+        assert!(html.contains(&format!(" 1. {}", first_word)));
+
+        let p = std::env::temp_dir().join(format!("pwmn-kit-{}.txt", uuid::Uuid::new_v4()));
+        write(&p, &text).unwrap();
+        assert!(write(&p, &text).is_err());
+        std::fs::remove_file(&p).unwrap();
+    }
+}
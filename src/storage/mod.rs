@@ -1,5 +1,12 @@
+pub mod atomic;
+pub mod backup;
+pub mod bundle;
+pub mod config;
 pub mod enc_auth;
 pub mod init;
+pub mod kit;
+pub mod rootmeta;
+pub mod throttle;
 pub mod types;
 pub mod vault;
 pub mod vaultmanager;
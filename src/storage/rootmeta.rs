@@ -0,0 +1,92 @@
+use super::atomic;
+use crate::error::VaultValidationErr;
+use std::fs;
+use std::path::Path;
+
+// Public metadata of the root vault, stored unencrypted next to the register folders.
+pub const ROOT_META_N: &str = "rvault.bin";
+
+const ROOT_MAGIC: [u8; 4] = *b"PWMR";
+const ROOT_META_VERSION: u16 = 1;
+
+// Layout v1: magic (4) | version u16 LE | name salt (16).
+#[derive(Debug, Clone)]
+pub struct RootMeta {
+    pub version: u16,
+    // Salt of the register folder names. Random per installation so the folder of a
+    // given register name can't be precomputed from the source.
+    pub name_salt: [u8; 16],
+}
+
+impl RootMeta {
+    pub fn generate() -> Self {
+        Self {
+            version: ROOT_META_VERSION,
+            name_salt: rand::random(),
+        }
+    }
+
+    // The metadata of a root whose salt is already known. Register folders keep a copy
+    // of it, see `VaultManager::save_root_meta_copy`.
+    pub fn with_salt(name_salt: [u8; 16]) -> Self {
+        Self {
+            version: ROOT_META_VERSION,
+            name_salt,
+        }
+    }
+
+    // `None` for roots created before the metadata file existed.
+    pub fn load(root: &Path) -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        let bytes = match fs::read(root.join(ROOT_META_N)) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(Box::new(e)),
+        };
+        Ok(Some(Self::from_bytes(&bytes)?))
+    }
+
+    pub fn save(&self, root: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        atomic::write_atomic(&root.join(ROOT_META_N), &self.to_bytes())?;
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buffer = ROOT_MAGIC.to_vec();
+        buffer.extend_from_slice(&ROOT_META_VERSION.to_le_bytes());
+        buffer.extend_from_slice(&self.name_salt);
+        buffer
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, VaultValidationErr> {
+        if bytes.len() < 22 || bytes[..4] != ROOT_MAGIC {
+            return Err(VaultValidationErr::InvalidRootMeta);
+        }
+        let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+        if version != ROOT_META_VERSION {
+            return Err(VaultValidationErr::InvalidRootMeta);
+        }
+        Ok(Self {
+            version,
+            name_salt: bytes[6..22].try_into().unwrap(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_root_meta_roundtrip_and_rejects_garbage() {
+        let meta = RootMeta::generate();
+        let parsed = RootMeta::from_bytes(&meta.to_bytes()).unwrap();
+        assert_eq!(parsed.name_salt, meta.name_salt);
+        assert_ne!(RootMeta::generate().name_salt, meta.name_salt);
+
+        let mut bytes = meta.to_bytes();
+        assert!(RootMeta::from_bytes(&bytes[..21]).is_err());
+        bytes[4] = 9;
+        assert!(RootMeta::from_bytes(&bytes).is_err());
+        assert!(RootMeta::from_bytes(b"PWMN\x01\x00 not a root meta file").is_err());
+    }
+}
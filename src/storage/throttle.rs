@@ -0,0 +1,396 @@
+use super::atomic;
+use super::init::ROOT_REG;
+use crate::error::{DecryptionErr, HomeDirErr, RecoveryErr, ShareErr, ThrottleErr};
+use chrono::{Local, TimeZone, Utc};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+type DynErr = Box<dyn std::error::Error>;
+
+// Failed unlocks of a register since the last successful one, next to its vault as
+// throttle-<key id>.pwmn, so users sharing a root each keep their own record.
+const RECORD_PREFIX: &str = "throttle-";
+const RECORD_EXT: &str = ".pwmn";
+// The key the records are authenticated with. It is personal and lives under the
+// home folder like the identity, even when --root or PWMN_HOME point elsewhere.
+pub const THROTTLE_KEY_N: &str = "throttle.key";
+// Next to the key, one empty marker for every register with failures on record. A
+// record that is gone while its marker is still there was deleted, not cleared.
+// Whoever can write the root but not the home folder can't forge, edit or remove a
+// record unnoticed; whoever can write the home folder as well can.
+pub const EXPECTED_DIR: &str = "throttled";
+
+const MAGIC: [u8; 4] = *b"PWMT";
+const VERSION: u16 = 1;
+const FLAG_ALTERED: u8 = 0x01;
+// Layout v1: magic (4) | version u16 LE | flags (1) | failures u32 LE |
+// last failure i64 LE, unix seconds | HMAC-SHA256 of everything before it (32).
+const BODY_LEN: usize = 19;
+const FILE_LEN: usize = BODY_LEN + 32;
+
+// Failures that don't hold off the next attempt; from then on the wait doubles.
+const FREE_ATTEMPTS: u32 = 3;
+const MAX_WAIT_SECS: i64 = 15 * 60;
+
+#[derive(Debug)]
+pub struct Throttle {
+    file: PathBuf,
+    // The marker in EXPECTED_DIR.
+    expected: PathBuf,
+    key: [u8; 32],
+    pub failures: u32,
+    // Unix seconds.
+    pub last_failure: i64,
+    // The record didn't verify; it was replaced by one that waits the longest.
+    pub altered: bool,
+}
+
+impl Throttle {
+    // `key_dir` holds the key and the markers, see `key_dir`.
+    pub fn load(key_dir: &Path, reg_dir: &Path) -> Result<Self, DynErr> {
+        let key = load_key(key_dir)?;
+        let key_id = hex::encode(&Sha256::digest(key)[..8]);
+        // The folder a register is found in names its marker.
+        let reg_dir = fs::canonicalize(reg_dir).unwrap_or_else(|_| reg_dir.to_path_buf());
+        let marker = hex::encode(&Sha256::digest(reg_dir.as_os_str().as_encoded_bytes())[..16]);
+        let mut throttle = Throttle {
+            file: reg_dir.join(format!("{}{}{}", RECORD_PREFIX, key_id, RECORD_EXT)),
+            expected: key_dir.join(EXPECTED_DIR).join(marker),
+            key,
+            failures: 0,
+            last_failure: 0,
+            altered: false,
+        };
+        let intact = match fs::read(&throttle.file) {
+            Ok(bytes) => throttle.read(&bytes),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => !throttle.expected.try_exists()?,
+            Err(e) => return Err(Box::new(e)),
+        };
+        if !intact {
+            // Saved right away, so a restart doesn't skip the wait.
+            throttle.failures = FREE_ATTEMPTS + 10;
+            throttle.last_failure = Utc::now().timestamp();
+            throttle.altered = true;
+            throttle.save()?;
+        }
+        Ok(throttle)
+    }
+
+    // Seconds until the next attempt is allowed, 0 when it is allowed now.
+    pub fn wait_secs(&self) -> i64 {
+        let wait = match self.failures.checked_sub(FREE_ATTEMPTS) {
+            Some(doublings) => (1i64 << doublings.min(20)).min(MAX_WAIT_SECS),
+            None => 0,
+        };
+        // A clock set back doesn't stretch the wait.
+        (self.last_failure + wait - Utc::now().timestamp()).clamp(0, wait)
+    }
+
+    pub fn check(&self) -> Result<(), ThrottleErr> {
+        match self.wait_secs() {
+            0 => Ok(()),
+            secs if self.altered => Err(ThrottleErr::Altered { secs }),
+            secs => Err(ThrottleErr::Backoff {
+                failures: self.failures,
+                secs,
+            }),
+        }
+    }
+
+    pub fn record_failure(&mut self) -> io::Result<()> {
+        self.failures = self.failures.saturating_add(1);
+        self.last_failure = Utc::now().timestamp();
+        self.save()
+    }
+
+    // Starts over, returning what happened since the last successful unlock.
+    pub fn record_success(&mut self) -> io::Result<Option<String>> {
+        if self.failures == 0 && !self.altered {
+            return Ok(None);
+        }
+        let report = if self.altered {
+            "the record of failed unlock attempts was altered".to_string()
+        } else {
+            format!(
+                "{} failed unlock attempt(s) since the last successful one, the last on {}",
+                self.failures,
+                Local
+                    .timestamp_opt(self.last_failure, 0)
+                    .single()
+                    .map(|at| at.format("%Y-%m-%d %H:%M").to_string())
+                    .unwrap_or_else(|| "an unknown date".to_string())
+            )
+        };
+        self.failures = 0;
+        self.altered = false;
+        // The marker goes first, a crash in between leaves a record the next success
+        // clears rather than a missing one.
+        for p in [&self.expected, &self.file] {
+            match fs::remove_file(p) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        Ok(Some(report))
+    }
+
+    // The marker is written first, so there is never a record without one.
+    fn save(&self) -> io::Result<()> {
+        fs::create_dir_all(self.expected.parent().unwrap())?;
+        if !self.expected.try_exists()? {
+            atomic::write_atomic(&self.expected, &[])?;
+        }
+
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        bytes.push(if self.altered { FLAG_ALTERED } else { 0 });
+        bytes.extend_from_slice(&self.failures.to_le_bytes());
+        bytes.extend_from_slice(&self.last_failure.to_le_bytes());
+        let tag = self.mac().chain_update(&bytes).finalize().into_bytes();
+        bytes.extend_from_slice(&tag);
+        atomic::write_atomic(&self.file, &bytes)
+    }
+
+    // False for anything that isn't a record this key wrote.
+    fn read(&mut self, bytes: &[u8]) -> bool {
+        if bytes.len() != FILE_LEN || bytes[..4] != MAGIC || bytes[4..6] != VERSION.to_le_bytes() {
+            return false;
+        }
+        let (body, tag) = bytes.split_at(BODY_LEN);
+        if self.mac().chain_update(body).verify_slice(tag).is_err() {
+            return false;
+        }
+        self.altered = body[6] & FLAG_ALTERED != 0;
+        self.failures = u32::from_le_bytes(body[7..11].try_into().unwrap());
+        self.last_failure = i64::from_le_bytes(body[11..19].try_into().unwrap());
+        true
+    }
+
+    fn mac(&self) -> Hmac<Sha256> {
+        Hmac::<Sha256>::new_from_slice(&self.key).unwrap()
+    }
+}
+
+// Where the key and the markers are kept.
+pub fn key_dir() -> Result<PathBuf, HomeDirErr> {
+    let home = dirs_next::home_dir().ok_or(HomeDirErr::InvalidHomeDir)?;
+    Ok(home.join(ROOT_REG))
+}
+
+// Runs one unlock of the register in `reg_dir`: turned down while the register waits
+// out earlier failures, recorded when the secret was wrong, and reported on success.
+pub fn attempt<T>(reg_dir: &Path, unlock: impl FnOnce() -> Result<T, DynErr>) -> Result<T, DynErr> {
+    attempt_in(&key_dir()?, reg_dir, unlock)
+}
+
+pub(crate) fn attempt_in<T>(
+    key_dir: &Path,
+    reg_dir: &Path,
+    unlock: impl FnOnce() -> Result<T, DynErr>,
+) -> Result<T, DynErr> {
+    let mut throttle = Throttle::load(key_dir, reg_dir)?;
+    throttle.check()?;
+    match unlock() {
+        Ok(unlocked) => {
+            if let Some(report) = throttle.record_success()? {
+                println!("Warning: {}", report);
+            }
+            Ok(unlocked)
+        }
+        Err(e) => {
+            if is_wrong_secret(e.as_ref()) {
+                // The wrong secret is the error that matters here.
+                if let Err(io) = throttle.record_failure() {
+                    println!("Couldn't record the failed attempt: {}", io);
+                }
+            }
+            Err(e)
+        }
+    }
+}
+
+// Guesses: a wrong password or keyfile, recovery key or set of shares. Typos in the
+// input, a missing keyfile or identity are not.
+fn is_wrong_secret(e: &(dyn std::error::Error + 'static)) -> bool {
+    e.is::<DecryptionErr>()
+        || matches!(e.downcast_ref(), Some(RecoveryErr::WrongKey))
+        || matches!(e.downcast_ref(), Some(ShareErr::NotFound))
+}
+
+fn load_key(key_dir: &Path) -> Result<[u8; 32], DynErr> {
+    let p = key_dir.join(THROTTLE_KEY_N);
+    let bytes = match fs::read(&p) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return create_key(&p),
+        Err(e) => return Err(Box::new(e)),
+    };
+    bytes.try_into().map_err(|_| {
+        Box::new(ThrottleErr::BadKey {
+            path: p.display().to_string(),
+        }) as DynErr
+    })
+}
+
+// Two processes may get here at once. The key is written in full under a temporary
+// name and linked into place, the first link wins and the other process reads it.
+fn create_key(p: &Path) -> Result<[u8; 32], DynErr> {
+    let key: [u8; 32] = rand::random();
+    let dir = p.parent().unwrap();
+    fs::create_dir_all(dir)?;
+    let tmp = dir.join(format!(
+        ".{}.{:016x}.tmp",
+        THROTTLE_KEY_N,
+        rand::random::<u64>()
+    ));
+    let written = (|| {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&tmp)?;
+        file.write_all(&key)?;
+        file.sync_all()?;
+        fs::hard_link(&tmp, p)
+    })();
+    let _ = fs::remove_file(&tmp);
+    match written {
+        Ok(()) => {
+            atomic::sync_dir(dir)?;
+            Ok(key)
+        }
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => load_key(dir),
+        Err(e) => Err(Box::new(e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn temp_root() -> PathBuf {
+        let p = env::temp_dir().join(format!("pwmn-test-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(p.join("reg")).unwrap();
+        p
+    }
+
+    fn wrong_password() -> Result<(), DynErr> {
+        Err(Box::new(DecryptionErr::DecryptionErr))
+    }
+
+    #[test]
+    fn test_failures_back_off_and_are_reported_once() {
+        let root = temp_root();
+        let reg = root.join("reg");
+        for _ in 0..FREE_ATTEMPTS {
+            assert!(attempt_in(&root, &reg, wrong_password).is_err());
+        }
+        let throttle = Throttle::load(&root, &reg).unwrap();
+        assert_eq!(throttle.failures, FREE_ATTEMPTS);
+        assert!(throttle.file.exists() && throttle.expected.exists());
+        assert!(matches!(
+            throttle.check(),
+            Err(ThrottleErr::Backoff { secs: 1, .. })
+        ));
+        // Not even asked while waiting, so not counted either.
+        let held_off = attempt_in(&root, &reg, || -> Result<(), DynErr> { unreachable!() });
+        assert!(held_off.unwrap_err().is::<ThrottleErr>());
+
+        // Other errors aren't guesses.
+        let mut throttle = Throttle::load(&root, &reg).unwrap();
+        throttle.last_failure -= 60;
+        throttle.save().unwrap();
+        let typo = attempt_in(&root, &reg, || -> Result<(), DynErr> {
+            Err(Box::new(RecoveryErr::NotSet))
+        });
+        assert!(typo.is_err());
+        assert_eq!(Throttle::load(&root, &reg).unwrap().failures, FREE_ATTEMPTS);
+
+        attempt_in(&root, &reg, || Ok(())).unwrap();
+        let mut throttle = Throttle::load(&root, &reg).unwrap();
+        assert_eq!(throttle.failures, 0);
+        assert!(!throttle.file.exists() && !throttle.expected.exists());
+        assert_eq!(throttle.record_success().unwrap(), None);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_wait_doubles_up_to_the_cap() {
+        let root = temp_root();
+        let mut throttle = Throttle::load(&root, &root.join("reg")).unwrap();
+        throttle.last_failure = Utc::now().timestamp();
+        let waits: Vec<i64> = (0..8)
+            .map(|failures| {
+                throttle.failures = failures;
+                throttle.wait_secs()
+            })
+            .collect();
+        assert_eq!(waits, [0, 0, 0, 1, 2, 4, 8, 16]);
+        throttle.failures = u32::MAX;
+        assert_eq!(throttle.wait_secs(), MAX_WAIT_SECS);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_an_edited_record_is_detected() {
+        let root = temp_root();
+        let reg = root.join("reg");
+        for _ in 0..5 {
+            let _ = attempt_in(&root, &reg, wrong_password);
+            let mut throttle = Throttle::load(&root, &reg).unwrap();
+            throttle.last_failure -= 60;
+            throttle.save().unwrap();
+        }
+
+        // Back to zero failures, without the key.
+        let record = Throttle::load(&root, &reg).unwrap().file;
+        let mut bytes = fs::read(&record).unwrap();
+        bytes[7..11].copy_from_slice(&0u32.to_le_bytes());
+        fs::write(&record, &bytes).unwrap();
+
+        let throttle = Throttle::load(&root, &reg).unwrap();
+        assert!(throttle.altered);
+        assert!(matches!(throttle.check(), Err(ThrottleErr::Altered { .. })));
+        // It stays that way after a restart.
+        assert!(Throttle::load(&root, &reg).unwrap().altered);
+
+        // A record made under another key doesn't verify either.
+        let other = temp_root();
+        let other_record = Throttle::load(&other, &reg).unwrap().file;
+        fs::copy(&record, &other_record).unwrap();
+        assert!(Throttle::load(&other, &reg).unwrap().altered);
+
+        fs::remove_dir_all(&root).unwrap();
+        fs::remove_dir_all(&other).unwrap();
+    }
+
+    #[test]
+    fn test_a_deleted_record_is_detected() {
+        let root = temp_root();
+        let reg = root.join("reg");
+        let _ = attempt_in(&root, &reg, wrong_password);
+        let throttle = Throttle::load(&root, &reg).unwrap();
+        assert_eq!(throttle.failures, 1);
+        fs::remove_file(&throttle.file).unwrap();
+
+        let throttle = Throttle::load(&root, &reg).unwrap();
+        assert!(throttle.altered);
+        assert!(throttle.file.exists());
+        assert!(matches!(throttle.check(), Err(ThrottleErr::Altered { .. })));
+
+        // Users sharing the root each keep their own record.
+        let other = temp_root();
+        let theirs = Throttle::load(&other, &reg).unwrap();
+        assert_ne!(theirs.file, throttle.file);
+        assert!(!theirs.altered && theirs.failures == 0);
+
+        fs::remove_dir_all(&root).unwrap();
+        fs::remove_dir_all(&other).unwrap();
+    }
+}
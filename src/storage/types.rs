@@ -1,3 +1,4 @@
+use crate::encryption::protected::ProtectedStr;
 use crate::error::CreateErr;
 use crate::p_std::uid::Uid;
 use crate::storage::init::ROOT_REG;
@@ -30,11 +31,11 @@ pub struct Register {
     pub log: Vec<LogEntry>,
 }
 
-#[derive(Debug, Deserialize, Serialize, Encode, Decode)]
+#[derive(Debug, Clone, Deserialize, Serialize, Encode, Decode)]
 pub struct Entry {
     pub entry_id: String,
     pub used_for: Vec<String>,
-    pub password: String,
+    pub password: ProtectedStr,
     pub notes: Option<String>,
     pub username: Option<String>,
     pub url: Option<String>,
@@ -42,7 +43,7 @@ pub struct Entry {
     pub custom_field: Option<HashMap<String, CustomValue>>,
 }
 
-#[derive(Debug, Deserialize, Serialize, Encode, Decode)]
+#[derive(Debug, Clone, Deserialize, Serialize, Encode, Decode)]
 pub enum CustomValue {
     Text(String),
     Number(i32),
@@ -74,17 +75,17 @@ pub struct RegMetadata {
     pub n_of_entries: u32,
 }
 
-#[derive(Debug, Deserialize, Serialize, Encode, Decode)]
+#[derive(Debug, Clone, Deserialize, Serialize, Encode, Decode)]
 pub struct EntryMetadata {
     pub created_at: i64,
     pub modified_at: i64,
     pub fetched_cnt: u32,
-    pub password: String,
+    pub password: ProtectedStr,
     pub strength_score: u8,
     pub created_by: CreatedBy,
 }
 
-#[derive(Debug, Deserialize, Serialize, Encode, Decode)]
+#[derive(Debug, Clone, Deserialize, Serialize, Encode, Decode)]
 pub enum CreatedBy {
     Manual,
     Generated,
@@ -105,4 +106,125 @@ impl Register {
             log: Vec::new(),
         }
     }
+
+    // Consistency problems in a decoded register, empty when it is sound.
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.metadata.n_of_entries as usize != self.entries.len() {
+            problems.push(format!(
+                "metadata counts {} entries but the register holds {}",
+                self.metadata.n_of_entries,
+                self.entries.len()
+            ));
+        }
+        if self.metadata.created_at > self.metadata.modified_at {
+            problems.push("register was modified before it was created".to_string());
+        }
+
+        let mut ids = HashMap::new();
+        for entry in &self.entries {
+            if ids.insert(entry.entry_id.as_str(), ()).is_some() {
+                problems.push(format!(
+                    "entry id '{}' is used more than once",
+                    entry.entry_id
+                ));
+            }
+            if !Register::is_entry_id(&entry.entry_id) {
+                problems.push(format!("entry id '{}' is malformed", entry.entry_id));
+            }
+            if entry.metadata.created_at > entry.metadata.modified_at {
+                problems.push(format!(
+                    "entry '{}' was modified before it was created",
+                    entry.entry_id
+                ));
+            }
+        }
+
+        if let Some(pos) = self
+            .log
+            .windows(2)
+            .position(|w| w[0].timestamp > w[1].timestamp)
+        {
+            problems.push(format!("log goes back in time at record {}", pos + 1));
+        }
+        problems
+    }
+
+    // As `Uid::new("Entry")` makes them: En- and 8 hex digits of a UUID.
+    pub fn is_entry_id(id: &str) -> bool {
+        id.strip_prefix("En-")
+            .is_some_and(|hex| hex.len() == 8 && hex.bytes().all(|b| b.is_ascii_hexdigit()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(password: &str) -> Entry {
+        Entry {
+            entry_id: "En-0000abcd".to_string(),
+            used_for: vec![],
+            password: ProtectedStr::new(password.to_string()),
+            notes: None,
+            username: None,
+            url: None,
+            metadata: EntryMetadata {
+                created_at: 1,
+                modified_at: 1,
+                fetched_cnt: 0,
+                password: ProtectedStr::new(String::new()),
+                strength_score: 0,
+                created_by: CreatedBy::Manual,
+            },
+            custom_field: None,
+        }
+    }
+
+    #[test]
+    fn test_debug_output_leaves_passwords_out() {
+        let mut reg = Register::new("personal");
+        reg.entries.push(entry("hunter22"));
+        let debug = format!("{:?}", reg);
+        assert!(debug.contains("En-0000abcd"));
+        assert!(!debug.contains("hunter22"));
+    }
+
+    #[test]
+    fn test_validate_reports_inconsistent_registers() {
+        let mut reg = Register::new("personal");
+        reg.entries.push(entry("hunter22"));
+        reg.metadata.n_of_entries = 1;
+        assert!(reg.validate().is_empty());
+
+        // The same id twice, and a count that no longer matches.
+        reg.entries.push(entry("hunter23"));
+        let problems = reg.validate();
+        assert_eq!(problems.len(), 2, "{:?}", problems);
+        assert!(problems.iter().any(|p| p.contains("used more than once")));
+        assert!(problems.iter().any(|p| p.contains("counts 1 entries")));
+
+        for bad in [
+            "",
+            "En-",
+            "En-0000abc",
+            "En-0000abcde",
+            "En-0000abcg",
+            "Re-0000abcd",
+        ] {
+            let mut reg = Register::new("personal");
+            let mut entry = entry("hunter22");
+            entry.entry_id = bad.to_string();
+            reg.entries.push(entry);
+            reg.metadata.n_of_entries = 1;
+            assert_eq!(
+                reg.validate(),
+                vec![format!("entry id '{}' is malformed", bad)]
+            );
+        }
+
+        let mut reg = Register::new("personal");
+        reg.metadata.modified_at = reg.metadata.created_at - 1;
+        assert_eq!(reg.validate().len(), 1);
+    }
 }
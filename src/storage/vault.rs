@@ -1,11 +1,23 @@
+use crate::encryption::aead::{self, CipherId, DEFAULT_CIPHER};
+use crate::encryption::identity::{self, Identity};
+use crate::encryption::kdf::{KdfParams, derive_slow_key};
+use crate::encryption::keyfile;
 use crate::error::CreateErr;
+use crate::error::DecryptionErr;
+use crate::error::EncryptionErr;
 use crate::error::HomeDirErr;
+use crate::error::IdentityErr;
+use crate::error::KeyfileErr;
+use crate::error::RecoveryErr;
+use crate::error::ShareErr;
+use crate::error::VaultValidationErr;
+use crate::storage::atomic;
 use crate::storage::init::ROOT_REG;
 use argon2::password_hash::rand_core::{CryptoRng, OsRng, RngCore};
-use chacha20poly1305::{AeadCore, ChaCha20Poly1305};
 use hex;
 use rand::random;
 use rpassword;
+use sha2::{Digest, Sha256};
 use std::io::{Read, Seek, SeekFrom, Write};
 use std::ptr::hash;
 use std::{
@@ -13,50 +25,1006 @@ use std::{
     fs::{self, File, OpenOptions, create_dir_all},
     path::{Path, PathBuf},
 };
+use zeroize::{Zeroize, Zeroizing};
 
 pub const VAULT_N: &str = "vault.bin";
+pub const MAGIC: [u8; 4] = *b"PWMN";
+
+// Header layouts, this file is the only place that knows about them:
+//
+//   v1  [magic 4][version 2][salt 16][nonce 12]                     34 bytes
+//   v2  v1 + [key check 16], header and folder bound as AEAD data    50 bytes
+//   v3  [magic 4][version 2][header len 2] then length-prefixed
+//       fields [tag 1][len 2][value], see the TAG_* constants.
+//   v4  v3 framing; the data is encrypted with a random data key that is stored
+//       wrapped in one or more key slots, each a TAG_KEY_SLOT field holding its own
+//       SLOT_* fields. The salt, KDF and key check move into the slots. A
+//       TAG_HEADER_CHECK field closes the header, see `header_check`.
+//
+// v1 to v3 are only ever read; every write produces the current version.
+pub const VAULT_VERSION: u16 = 4;
+const V1_LEN: usize = 34;
+const V2_LEN: usize = 50;
+const V3_PREFIX_LEN: usize = 8;
+
+const TAG_FLAGS: u8 = 0x01;
+const TAG_CIPHER: u8 = 0x02;
+const TAG_KDF: u8 = 0x03;
+const TAG_SALT: u8 = 0x04;
+const TAG_NONCE: u8 = 0x05;
+const TAG_KEY_CHECK: u8 = 0x06;
+const TAG_KEY_SLOT: u8 = 0x07;
+const TAG_HEADER_CHECK: u8 = 0x08;
+const HEADER_CHECK_LEN: usize = 16;
+
+const SLOT_KIND: u8 = 0x01;
+const SLOT_KDF: u8 = 0x02;
+const SLOT_SALT: u8 = 0x03;
+const SLOT_NONCE: u8 = 0x04;
+const SLOT_WRAPPED: u8 = 0x05;
+const SLOT_FLAGS: u8 = 0x06;
+const SLOT_MEMBER: u8 = 0x07;
+const SLOT_EPHEMERAL: u8 = 0x08;
+
+// The slot key also depends on a keyfile.
+pub const SLOT_FLAG_KEYFILE: u32 = 0x0001;
+const KNOWN_SLOT_FLAGS: u32 = SLOT_FLAG_KEYFILE;
+
+// Keeps the header well below its 64 KiB limit.
+pub const MAX_KEY_SLOTS: usize = 8;
+
+// Recovery keys and shared secrets already have 256 bits of entropy, stretching them
+// buys nothing. The light Argon2 run only keeps their slots laid out like a password
+// slot.
+pub const RANDOM_SECRET_KDF: KdfParams = KdfParams {
+    m_cost: 16 * 1024,
+    t_cost: 1,
+    p_cost: 1,
+};
+
+const BAD: VaultValidationErr = VaultValidationErr::MismatchedFileHeader;
+
+// Every key slot also depends on a keyfile, see `encryption::keyfile`. Registers
+// created WITH KEYFILE before slots had flags of their own; upgrading from v3 moves
+// it into the slots as SLOT_FLAG_KEYFILE.
+pub const FLAG_KEYFILE: u32 = 0x0001;
+// Unknown bits are rejected rather than ignored.
+pub const KNOWN_FLAGS: u32 = FLAG_KEYFILE;
 
 type DynamicError = Box<dyn std::error::Error>;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlotKind {
+    Password = 1,
+    // Opens with the register's recovery key, see `encryption::recovery`.
+    Recovery = 2,
+    // Opens with a secret rebuilt from shares, see `encryption::shamir`. The first
+    // bytes of its salt identify the split.
+    Shares = 3,
+    // Opens with a member's X25519 identity, see `encryption::identity`.
+    Recipient = 4,
+}
+
+impl SlotKind {
+    pub fn from_u8(id: u8) -> Option<Self> {
+        match id {
+            1 => Some(SlotKind::Password),
+            2 => Some(SlotKind::Recovery),
+            3 => Some(SlotKind::Shares),
+            4 => Some(SlotKind::Recipient),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for SlotKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SlotKind::Password => write!(f, "password"),
+            SlotKind::Recovery => write!(f, "recovery"),
+            SlotKind::Shares => write!(f, "shares"),
+            SlotKind::Recipient => write!(f, "member"),
+        }
+    }
+}
+
+// The public keys of a recipient slot: the member's own, and the one-off key its
+// slot key was agreed with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecipientKeys {
+    pub member: [u8; 32],
+    pub ephemeral: [u8; 32],
+}
+
+// One way to obtain the data key: a key derived from the slot's own salt and KDF
+// unwraps it. Legacy headers are read as a single slot with nothing wrapped, its
+// derived key encrypts the data directly.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeySlot {
+    pub kind: SlotKind,
+    pub flags: u32,
+    pub kdf: KdfParams,
+    pub salt: [u8; 16],
+    pub nonce: [u8; 12],
+    pub wrapped: Vec<u8>,
+    // Recipient slots only.
+    pub recipient: Option<RecipientKeys>,
+}
+
+impl KeySlot {
+    fn legacy(kdf: KdfParams, salt: [u8; 16]) -> Self {
+        Self {
+            kind: SlotKind::Password,
+            flags: 0,
+            kdf,
+            salt,
+            nonce: [0u8; 12],
+            wrapped: vec![],
+            recipient: None,
+        }
+    }
+
+    pub fn is_legacy(&self) -> bool {
+        self.wrapped.is_empty()
+    }
+
+    // Seals `data_key` under `slot_key` into a copy of the slot with a fresh nonce.
+    pub fn wrap(
+        mut slot: KeySlot,
+        slot_key: &[u8; 32],
+        data_key: &[u8; 32],
+    ) -> Result<KeySlot, EncryptionErr> {
+        slot.nonce = rand::random();
+        slot.wrapped = aead::encrypt(
+            *slot_key,
+            slot.nonce,
+            data_key.to_vec(),
+            &slot.bound_bytes(),
+        )?;
+        Ok(slot)
+    }
+
+    // Argon2id over the password, bound to the keyfile when the register uses one.
+    fn derive(&self, password: &str, keyfile_hash: Option<&[u8; 32]>) -> Zeroizing<[u8; 32]> {
+        let pwd_key = derive_slow_key(password, &self.salt, &self.kdf);
+        match keyfile_hash {
+            Some(hash) => Zeroizing::new(keyfile::combine(*pwd_key, hash)),
+            None => pwd_key,
+        }
+    }
+
+    // The decrypted buffer is wiped, only the returned copy of the key is left.
+    fn unwrap_key(&self, slot_key: &[u8; 32]) -> Option<[u8; 32]> {
+        let data_key = Zeroizing::new(
+            aead::decrypt(
+                *slot_key,
+                self.nonce,
+                self.wrapped.clone(),
+                &self.bound_bytes(),
+            )
+            .ok()?,
+        );
+        data_key.as_slice().try_into().ok()
+    }
+
+    // Every field but the wrapped key, authenticated along with it.
+    fn bound_bytes(&self) -> Vec<u8> {
+        let mut fields: Vec<u8> = vec![];
+        push_field(&mut fields, SLOT_KIND, &[self.kind as u8]);
+        // Left out when zero, slots written before the field existed stay byte-identical.
+        if self.flags != 0 {
+            push_field(&mut fields, SLOT_FLAGS, &self.flags.to_le_bytes());
+        }
+        push_field(&mut fields, SLOT_KDF, &kdf_bytes(&self.kdf));
+        push_field(&mut fields, SLOT_SALT, &self.salt);
+        push_field(&mut fields, SLOT_NONCE, &self.nonce);
+        if let Some(recipient) = &self.recipient {
+            push_field(&mut fields, SLOT_MEMBER, &recipient.member);
+            push_field(&mut fields, SLOT_EPHEMERAL, &recipient.ephemeral);
+        }
+        fields
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut fields = self.bound_bytes();
+        push_field(&mut fields, SLOT_WRAPPED, &self.wrapped);
+        fields
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, VaultValidationErr> {
+        let mut kind = None;
+        let mut flags = 0;
+        let mut kdf = None;
+        let mut salt = None;
+        let mut nonce = None;
+        let mut wrapped = None;
+        let mut member = None;
+        let mut ephemeral = None;
+        for (tag, value) in read_fields(bytes)? {
+            match tag {
+                SLOT_KIND => kind = Some(SlotKind::from_u8(fixed::<1>(value)?[0]).ok_or(BAD)?),
+                SLOT_FLAGS => flags = u32::from_le_bytes(fixed(value)?),
+                SLOT_KDF => kdf = Some(parse_kdf(value)?),
+                SLOT_SALT => salt = Some(fixed(value)?),
+                SLOT_NONCE => nonce = Some(fixed(value)?),
+                SLOT_WRAPPED => wrapped = Some(fixed::<48>(value)?.to_vec()),
+                SLOT_MEMBER => member = Some(fixed(value)?),
+                SLOT_EPHEMERAL => ephemeral = Some(fixed(value)?),
+                _ => return Err(BAD),
+            }
+        }
+        if flags & !KNOWN_SLOT_FLAGS != 0 {
+            return Err(BAD);
+        }
+        let kind = kind.ok_or(BAD)?;
+        let recipient = match (kind, member, ephemeral) {
+            (SlotKind::Recipient, Some(member), Some(ephemeral)) => {
+                Some(RecipientKeys { member, ephemeral })
+            }
+            (SlotKind::Recipient, _, _) | (_, Some(_), _) | (_, _, Some(_)) => return Err(BAD),
+            _ => None,
+        };
+        Ok(Self {
+            kind,
+            flags,
+            kdf: kdf.ok_or(BAD)?,
+            salt: salt.ok_or(BAD)?,
+            nonce: nonce.ok_or(BAD)?,
+            wrapped: wrapped.ok_or(BAD)?,
+            recipient,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Vault {
     pub magic: [u8; 4],
     pub version: u16,
-    pub salt: [u8; 16],
-    pub nonce: [u8; 12],
+    pub flags: u32,
+    pub cipher: CipherId,
+    // Nonce of the data encryption, as long as `cipher` takes.
+    pub nonce: Vec<u8>,
+    // v2 and v3 only, tells a wrong password apart from a tampered file. From v4 on
+    // the key slots do that.
+    pub key_check: [u8; 16],
+    pub slots: Vec<KeySlot>,
 }
 impl Vault {
     pub fn allocate(p: &PathBuf) -> Result<PathBuf, DynamicError> {
-        let nonce_array = ChaCha20Poly1305::generate_nonce(&mut OsRng);
-        let mut f = Self {
-            magic: [0x50, 0x57, 0x4D, 0x4E],
-            version: 1,
-            salt: rand::random(),
-            nonce: [0u8; 12],
-        };
-        f.nonce = nonce_array.into();
+        let mut f = Self::generate();
         let path = f.allocate_header(&p)?;
         Ok(path)
     }
 
+    // A fresh header with its own random salt and nonce, used both when a register
+    // is allocated and whenever its key material has to be replaced.
+    pub fn generate() -> Self {
+        Self {
+            magic: MAGIC,
+            version: VAULT_VERSION,
+            flags: 0,
+            cipher: DEFAULT_CIPHER,
+            nonce: DEFAULT_CIPHER.generate_nonce(),
+            key_check: [0u8; 16],
+            slots: vec![],
+        }
+    }
+
+    // A random key for the register data, it never changes with the password.
+    pub fn generate_data_key() -> [u8; 32] {
+        let mut key = [0u8; 32];
+        OsRng.fill_bytes(&mut key);
+        key
+    }
+
+    // Lets a wrong password be told apart from a header or ciphertext that fails
+    // authentication. It is only as cheap to test as the Argon2 key behind it.
+    pub fn key_check(key: &[u8; 32]) -> [u8; 16] {
+        let digest = Sha256::new()
+            .chain_update(b"pwmn-key-check")
+            .chain_update(key)
+            .finalize();
+        let mut check = [0u8; 16];
+        check.copy_from_slice(&digest[..16]);
+        check
+    }
+
+    // Only password slots ever take a keyfile, whatever the header says.
+    pub fn slot_uses_keyfile(&self, slot: &KeySlot) -> bool {
+        slot.kind == SlotKind::Password
+            && (self.flags & FLAG_KEYFILE != 0 || slot.flags & SLOT_FLAG_KEYFILE != 0)
+    }
+
+    pub fn password_slots(&self) -> impl Iterator<Item = (usize, &KeySlot)> {
+        self.slots
+            .iter()
+            .enumerate()
+            .filter(|(_, slot)| slot.kind == SlotKind::Password)
+    }
+
+    // Some password slot opens with a keyfile, so one may be given.
+    pub fn accepts_keyfile(&self) -> bool {
+        self.password_slots()
+            .any(|(_, slot)| self.slot_uses_keyfile(slot))
+    }
+
+    // No password slot opens without a keyfile, so one has to be given.
+    pub fn requires_keyfile(&self) -> bool {
+        self.accepts_keyfile()
+            && self
+                .password_slots()
+                .all(|(_, slot)| self.slot_uses_keyfile(slot))
+    }
+
+    // The key that decrypts the data, together with the index of the slot that gave
+    // it up. The password is tried against every password slot it can open, those
+    // that need a keyfile only when one is given; each costs an Argon2 run. A password
+    // that opens none of them is a wrong password. A legacy slot's key can only be
+    // checked by `open`.
+    pub fn unlock_key(
+        &self,
+        password: &str,
+        keyfile: Option<&Path>,
+    ) -> Result<([u8; 32], usize), DynamicError> {
+        // The keyfile is read first, a missing one shouldn't cost an Argon2 run.
+        let keyfile_hash = match keyfile {
+            Some(p) if self.accepts_keyfile() => Some(keyfile::hash(p)?),
+            _ => None,
+        };
+        let mut tried = false;
+        for (i, slot) in self.password_slots() {
+            let hash = match (self.slot_uses_keyfile(slot), &keyfile_hash) {
+                (true, None) => continue,
+                (true, Some(hash)) => Some(hash),
+                (false, _) => None,
+            };
+            tried = true;
+            let slot_key = slot.derive(password, hash);
+            if slot.is_legacy() {
+                return Ok((*slot_key, i));
+            }
+            if let Some(data_key) = slot.unwrap_key(&slot_key) {
+                return Ok((data_key, i));
+            }
+        }
+        if !tried && self.accepts_keyfile() {
+            return Err(Box::new(KeyfileErr::Required));
+        }
+        Err(Box::new(DecryptionErr::DecryptionErr))
+    }
+
+    // A new password slot for `data_key`, with its own salt. Given a keyfile, the slot
+    // only opens with both.
+    pub fn password_slot(
+        &self,
+        password: &str,
+        keyfile: Option<&Path>,
+        kdf: KdfParams,
+        data_key: &[u8; 32],
+    ) -> Result<KeySlot, DynamicError> {
+        let mut slot = KeySlot {
+            kind: SlotKind::Password,
+            flags: 0,
+            kdf,
+            salt: rand::random(),
+            nonce: rand::random(),
+            wrapped: vec![],
+            recipient: None,
+        };
+        if keyfile.is_some() {
+            slot.flags |= SLOT_FLAG_KEYFILE;
+        }
+        let keyfile_hash = match (self.slot_uses_keyfile(&slot), keyfile) {
+            (true, Some(p)) => Some(keyfile::hash(p)?),
+            (true, None) => return Err(Box::new(KeyfileErr::Required)),
+            (false, _) => None,
+        };
+        let slot_key = slot.derive(password, keyfile_hash.as_ref());
+        Ok(KeySlot::wrap(slot, &slot_key, data_key)?)
+    }
+
+    // A recovery slot for `data_key`, `phrase` as given by `recovery::normalize`.
+    pub fn recovery_slot(
+        &self,
+        phrase: &str,
+        data_key: &[u8; 32],
+    ) -> Result<KeySlot, EncryptionErr> {
+        Vault::secret_slot(SlotKind::Recovery, phrase, None, data_key)
+    }
+
+    // A shares slot for `data_key`, opened by `secret` once `shamir::combine` rebuilt
+    // it. `share_set` tells which split its shares belong to.
+    pub fn shares_slot(
+        &self,
+        secret: &[u8; 32],
+        data_key: &[u8; 32],
+    ) -> Result<KeySlot, EncryptionErr> {
+        Vault::secret_slot(SlotKind::Shares, &hex::encode(secret), None, data_key)
+    }
+
+    pub fn share_set(slot: &KeySlot) -> [u8; 4] {
+        fixed(&slot.salt[..4]).unwrap()
+    }
+
+    // A slot for `data_key` that `member` opens with their identity. Wrapping only
+    // takes their public key, so members can be added, and re-added after the data
+    // key changes, without them.
+    pub fn recipient_slot(
+        &self,
+        member: &[u8; 32],
+        data_key: &[u8; 32],
+    ) -> Result<KeySlot, DynamicError> {
+        let (ephemeral, mut shared) = identity::agree_with(member)?;
+        let recipient = RecipientKeys {
+            member: *member,
+            ephemeral,
+        };
+        let slot = Vault::secret_slot(
+            SlotKind::Recipient,
+            &hex::encode(shared),
+            Some(recipient),
+            data_key,
+        );
+        shared.zeroize();
+        Ok(slot?)
+    }
+
+    pub fn member_slot(&self, member: &[u8; 32]) -> Option<usize> {
+        self.slots
+            .iter()
+            .position(|slot| slot.recipient.is_some_and(|r| &r.member == member))
+    }
+
+    fn secret_slot(
+        kind: SlotKind,
+        secret: &str,
+        recipient: Option<RecipientKeys>,
+        data_key: &[u8; 32],
+    ) -> Result<KeySlot, EncryptionErr> {
+        let slot = KeySlot {
+            kind,
+            flags: 0,
+            kdf: RANDOM_SECRET_KDF,
+            salt: rand::random(),
+            nonce: rand::random(),
+            wrapped: vec![],
+            recipient,
+        };
+        let slot_key = slot.derive(secret, None);
+        KeySlot::wrap(slot, &slot_key, data_key)
+    }
+
+    pub fn has_slot(&self, kind: SlotKind) -> bool {
+        self.slots.iter().any(|slot| slot.kind == kind)
+    }
+
+    // Like `unlock_key`, for the recovery slots.
+    pub fn unlock_recovery(&self, phrase: &str) -> Result<([u8; 32], usize), RecoveryErr> {
+        if !self.has_slot(SlotKind::Recovery) {
+            return Err(RecoveryErr::NotSet);
+        }
+        self.slots
+            .iter()
+            .enumerate()
+            .filter(|(_, slot)| slot.kind == SlotKind::Recovery)
+            .find_map(|(i, slot)| Some((slot.unwrap_key(&slot.derive(phrase, None))?, i)))
+            .ok_or(RecoveryErr::WrongKey)
+    }
+
+    // The shares slot of split `set`, given the secret its shares rebuilt.
+    pub fn unlock_shares(
+        &self,
+        set: [u8; 4],
+        secret: &[u8],
+    ) -> Result<([u8; 32], usize), ShareErr> {
+        let (i, slot) = self
+            .slots
+            .iter()
+            .enumerate()
+            .find(|(_, slot)| slot.kind == SlotKind::Shares && Vault::share_set(slot) == set)
+            .ok_or(ShareErr::NotFound)?;
+        let data_key = slot
+            .unwrap_key(&slot.derive(&hex::encode(secret), None))
+            .ok_or(ShareErr::NotFound)?;
+        Ok((data_key, i))
+    }
+
+    // The recipient slot granted to `identity`.
+    pub fn unlock_identity(&self, identity: &Identity) -> Result<([u8; 32], usize), IdentityErr> {
+        let i = self
+            .member_slot(&identity.public())
+            .ok_or(IdentityErr::NotGranted)?;
+        let slot = &self.slots[i];
+        let mut shared = identity.agree(&slot.recipient.unwrap().ephemeral)?;
+        let data_key = slot.unwrap_key(&slot.derive(&hex::encode(shared), None));
+        shared.zeroize();
+        Ok((data_key.ok_or(IdentityErr::NotGranted)?, i))
+    }
+
+    // Brings a v1 to v3 header's single slot to the current layout: the key it
+    // derives wraps `data_key` from now on, and the header-wide keyfile requirement
+    // moves into the slot. The data has to be encrypted again under `data_key`.
+    pub fn upgrade_slots(
+        &mut self,
+        legacy_key: [u8; 32],
+        data_key: &[u8; 32],
+    ) -> Result<(), EncryptionErr> {
+        let keyfile = self.flags & FLAG_KEYFILE != 0;
+        self.flags &= !FLAG_KEYFILE;
+        self.slots = std::mem::take(&mut self.slots)
+            .into_iter()
+            .map(|mut slot| {
+                if keyfile {
+                    slot.flags |= SLOT_FLAG_KEYFILE;
+                }
+                KeySlot::wrap(slot, &legacy_key, data_key)
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(())
+    }
+
+    pub fn is_outdated(&self) -> bool {
+        self.version < VAULT_VERSION
+    }
+
+    // Always serializes the current layout, which is how older headers get upgraded.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.serialize(true)
+    }
+
+    // The header as bound to the data: the key slots are left out so they can be
+    // replaced without re-encrypting the register. Each slot authenticates itself.
+    pub fn bound_bytes(&self) -> Vec<u8> {
+        self.serialize(false)
+    }
+
+    fn serialize(&self, with_slots: bool) -> Vec<u8> {
+        let mut fields: Vec<u8> = vec![];
+        push_field(&mut fields, TAG_FLAGS, &self.flags.to_le_bytes());
+        push_field(&mut fields, TAG_CIPHER, &[self.cipher as u8]);
+        push_field(&mut fields, TAG_NONCE, &self.nonce);
+        if with_slots {
+            for slot in &self.slots {
+                push_field(&mut fields, TAG_KEY_SLOT, &slot.to_bytes());
+            }
+        }
+
+        let check_len = if with_slots { 3 + HEADER_CHECK_LEN } else { 0 };
+        let header_len = (V3_PREFIX_LEN + fields.len() + check_len) as u16;
+        let mut buffer: Vec<u8> = vec![];
+        buffer.extend_from_slice(&MAGIC); // magic 4 bytes
+        buffer.extend_from_slice(&VAULT_VERSION.to_le_bytes()); // version 2 bytes
+        buffer.extend_from_slice(&header_len.to_le_bytes()); // header length 2 bytes
+        buffer.extend_from_slice(&fields);
+        if with_slots {
+            let check = Vault::header_check(&buffer);
+            push_field(&mut buffer, TAG_HEADER_CHECK, &check);
+        }
+        buffer
+    }
+
+    // Checked before any key is derived, so a salt, KDF or wrapped key that was
+    // damaged or edited is reported as such instead of as a wrong password. It is a
+    // plain digest: whoever recomputes it only gets a register that won't open, the
+    // slots and the data stay authenticated by their keys.
+    fn header_check(header: &[u8]) -> [u8; HEADER_CHECK_LEN] {
+        let digest = Sha256::new()
+            .chain_update(b"pwmn-header-check")
+            .chain_update(header)
+            .finalize();
+        let mut check = [0u8; HEADER_CHECK_LEN];
+        check.copy_from_slice(&digest[..HEADER_CHECK_LEN]);
+        check
+    }
+
+    // Reads a header back from the start of a vault file, returning it together
+    // with the offset at which the ciphertext starts.
+    pub fn from_bytes(bytes: &[u8]) -> Result<(Self, usize), VaultValidationErr> {
+        if bytes.len() < 6 || bytes[0..4] != MAGIC {
+            return Err(VaultValidationErr::MismatchedFileHeader);
+        }
+        match u16::from_le_bytes([bytes[4], bytes[5]]) {
+            1 => Vault::from_fixed(bytes, 1, V1_LEN),
+            2 => Vault::from_fixed(bytes, 2, V2_LEN),
+            3 | 4 => Vault::from_fields(bytes),
+            _ => Err(VaultValidationErr::MismatchedFileHeader),
+        }
+    }
+
+    fn from_fixed(
+        bytes: &[u8],
+        version: u16,
+        header_len: usize,
+    ) -> Result<(Self, usize), VaultValidationErr> {
+        if bytes.len() < header_len {
+            return Err(VaultValidationErr::MismatchedFileHeader);
+        }
+        let mut header = Self::generate();
+        header.version = version;
+        header.cipher = CipherId::ChaCha20Poly1305;
+        header.nonce = bytes[22..34].to_vec();
+        header.key_check = [0u8; 16];
+        if version == 2 {
+            header.key_check.copy_from_slice(&bytes[34..50]);
+        }
+        header.slots = vec![KeySlot::legacy(KdfParams::default(), fixed(&bytes[6..22])?)];
+        Ok((header, header_len))
+    }
+
+    fn from_fields(bytes: &[u8]) -> Result<(Self, usize), VaultValidationErr> {
+        if bytes.len() < V3_PREFIX_LEN {
+            return Err(BAD);
+        }
+        let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+        let header_len = u16::from_le_bytes([bytes[6], bytes[7]]) as usize;
+        if header_len < V3_PREFIX_LEN || bytes.len() < header_len {
+            return Err(BAD);
+        }
+
+        let mut flags = None;
+        let mut cipher = None;
+        let mut kdf = None;
+        let mut salt = None;
+        let mut nonce = None;
+        let mut key_check = None;
+        let mut slots = vec![];
+        let mut checked = false;
+
+        let fields = read_fields(&bytes[V3_PREFIX_LEN..header_len])?;
+        let last = fields.len().saturating_sub(1);
+        for (i, (tag, value)) in fields.into_iter().enumerate() {
+            match (tag, version) {
+                // Always the last field, over everything before it.
+                (TAG_HEADER_CHECK, 4) if i == last => {
+                    let covered = header_len - 3 - HEADER_CHECK_LEN;
+                    if value != Vault::header_check(&bytes[..covered]) {
+                        return Err(VaultValidationErr::TamperedHeader);
+                    }
+                    checked = true;
+                }
+                (TAG_FLAGS, _) => flags = Some(u32::from_le_bytes(fixed(value)?)),
+                (TAG_CIPHER, _) => {
+                    cipher = Some(CipherId::from_u8(fixed::<1>(value)?[0]).ok_or(BAD)?)
+                }
+                (TAG_NONCE, _) => nonce = Some(value.to_vec()),
+                (TAG_KDF, 3) => kdf = Some(parse_kdf(value)?),
+                (TAG_SALT, 3) => salt = Some(fixed(value)?),
+                (TAG_KEY_CHECK, 3) => key_check = Some(fixed(value)?),
+                (TAG_KEY_SLOT, 4) => slots.push(KeySlot::from_bytes(value)?),
+                _ => return Err(BAD),
+            }
+        }
+
+        // A v4 header without its check had it cut off.
+        if version == 4 && !checked {
+            return Err(VaultValidationErr::TamperedHeader);
+        }
+        let flags = flags.unwrap_or(0);
+        if flags & !KNOWN_FLAGS != 0 {
+            return Err(BAD);
+        }
+        if version == 3 {
+            slots.push(KeySlot::legacy(kdf.ok_or(BAD)?, salt.ok_or(BAD)?));
+            key_check.ok_or(BAD)?;
+        }
+        let cipher = cipher.ok_or(BAD)?;
+        let nonce = nonce.ok_or(BAD)?;
+        if nonce.len() != cipher.nonce_len() {
+            return Err(BAD);
+        }
+        let header = Self {
+            magic: MAGIC,
+            version,
+            flags,
+            cipher,
+            nonce,
+            key_check: key_check.unwrap_or([0u8; 16]),
+            slots,
+        };
+        Ok((header, header_len))
+    }
+
     pub fn allocate_header(&mut self, p: &PathBuf) -> Result<PathBuf, DynamicError> {
         let root_file = PathBuf::from(p).join(VAULT_N);
-        let mut file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(&root_file)?;
-        let mut buffer: Vec<u8> = vec![];
-        buffer.extend_from_slice(&self.magic); // magic 4 bytes 
-        buffer.extend_from_slice(&self.version.to_le_bytes()); // version 2 bytes
-        buffer.extend_from_slice(&self.salt); // salt 16 bytes needs later to derive reg hash
-        buffer.extend_from_slice(&self.nonce); // 12 to_le_bytes
-
-        /// number of registers 2 bytes, we need this to iterate over N number of registers  to
-        /// match the given input against the stored registers, since the registers would be
-        /// represented as HASH not as STRING, its okay to be public not encrypted;
-        file.write_all(&buffer); // 34 bytes [4][2][16][12]
-        //
+        atomic::write_atomic(&root_file, &self.to_bytes())?;
         Ok(root_file)
     }
 }
+
+// Splits a run of [tag 1][len 2][value] fields, all of which must fit exactly.
+fn read_fields(bytes: &[u8]) -> Result<Vec<(u8, &[u8])>, VaultValidationErr> {
+    let mut fields = vec![];
+    let mut pos = 0;
+    while pos < bytes.len() {
+        if pos + 3 > bytes.len() {
+            return Err(BAD);
+        }
+        let tag = bytes[pos];
+        let len = u16::from_le_bytes([bytes[pos + 1], bytes[pos + 2]]) as usize;
+        let start = pos + 3;
+        if start + len > bytes.len() {
+            return Err(BAD);
+        }
+        fields.push((tag, &bytes[start..start + len]));
+        pos = start + len;
+    }
+    Ok(fields)
+}
+
+fn kdf_bytes(kdf: &KdfParams) -> Vec<u8> {
+    let mut bytes = vec![1u8]; // 1 = Argon2id
+    bytes.extend_from_slice(&kdf.m_cost.to_le_bytes());
+    bytes.extend_from_slice(&kdf.t_cost.to_le_bytes());
+    bytes.extend_from_slice(&kdf.p_cost.to_le_bytes());
+    bytes
+}
+
+fn push_field(buffer: &mut Vec<u8>, tag: u8, value: &[u8]) {
+    buffer.push(tag);
+    buffer.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    buffer.extend_from_slice(value);
+}
+
+fn fixed<const N: usize>(value: &[u8]) -> Result<[u8; N], VaultValidationErr> {
+    value
+        .try_into()
+        .map_err(|_| VaultValidationErr::MismatchedFileHeader)
+}
+
+fn parse_kdf(value: &[u8]) -> Result<KdfParams, VaultValidationErr> {
+    let value: [u8; 13] = fixed(value)?;
+    // Argon2id is the only algorithm so far.
+    if value[0] != 1 {
+        return Err(VaultValidationErr::MismatchedFileHeader);
+    }
+    let kdf = KdfParams {
+        m_cost: u32::from_le_bytes(fixed(&value[1..5])?),
+        t_cost: u32::from_le_bytes(fixed(&value[5..9])?),
+        p_cost: u32::from_le_bytes(fixed(&value[9..13])?),
+    };
+    if !kdf.is_valid() {
+        return Err(VaultValidationErr::MismatchedFileHeader);
+    }
+    Ok(kdf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Cheap enough for tests, the real parameters take a second per derivation.
+    const TEST_KDF: KdfParams = KdfParams {
+        m_cost: 64,
+        t_cost: 1,
+        p_cost: 1,
+    };
+
+    #[test]
+    fn test_current_header_roundtrip() {
+        let mut header = Vault::generate();
+        let data_key = Vault::generate_data_key();
+        let slot = header
+            .password_slot("password1", None, TEST_KDF, &data_key)
+            .unwrap();
+        header.slots.push(slot);
+        let mut bytes = header.to_bytes();
+        let header_len = bytes.len();
+        bytes.extend_from_slice(b"ciphertext");
+
+        let (parsed, offset) = Vault::from_bytes(&bytes).unwrap();
+        assert_eq!(offset, header_len);
+        assert_eq!(parsed.version, VAULT_VERSION);
+        assert_eq!(parsed.nonce, header.nonce);
+        assert_eq!(parsed.slots, header.slots);
+        assert_eq!(parsed.cipher, DEFAULT_CIPHER);
+        assert_eq!(parsed.nonce.len(), 24);
+        assert_eq!(parsed.bound_bytes(), header.bound_bytes());
+        assert_eq!(&bytes[offset..], b"ciphertext");
+    }
+
+    #[test]
+    fn test_password_slot_unwraps_the_data_key() {
+        let mut header = Vault::generate();
+        let data_key = Vault::generate_data_key();
+        let slot = header
+            .password_slot("password1", None, TEST_KDF, &data_key)
+            .unwrap();
+        header.slots.push(slot);
+
+        let (key, index) = header.unlock_key("password1", None).unwrap();
+        assert_eq!((key, index), (data_key, 0));
+        let err = header.unlock_key("password2", None).unwrap_err();
+        assert!(err.downcast_ref::<DecryptionErr>().is_some());
+
+        // A slot whose metadata was altered no longer unwraps.
+        header.slots[0].salt[0] ^= 0x01;
+        assert!(header.unlock_key("password1", None).is_err());
+    }
+
+    #[test]
+    fn test_each_slot_opens_with_its_own_password_or_keyfile() {
+        let keyfile = std::env::temp_dir().join(format!("pwmn-slot-{}", uuid::Uuid::new_v4()));
+        keyfile::generate(&keyfile).unwrap();
+
+        let mut header = Vault::generate();
+        let data_key = Vault::generate_data_key();
+        for (password, keyfile) in [("password1", None), ("password2", Some(keyfile.as_path()))] {
+            let slot = header
+                .password_slot(password, keyfile, TEST_KDF, &data_key)
+                .unwrap();
+            header.slots.push(slot);
+        }
+        let bytes = header.to_bytes();
+        let (header, _) = Vault::from_bytes(&bytes).unwrap();
+
+        assert!(header.accepts_keyfile());
+        assert!(!header.requires_keyfile());
+        assert_eq!(header.unlock_key("password1", None).unwrap(), (data_key, 0));
+        assert_eq!(
+            header.unlock_key("password2", Some(&keyfile)).unwrap(),
+            (data_key, 1)
+        );
+        // The keyfile slot is skipped without the keyfile.
+        assert!(header.unlock_key("password2", None).is_err());
+
+        // Only a keyfile slot left: the keyfile is required.
+        let mut only_keyfile = header.clone();
+        only_keyfile.slots.remove(0);
+        assert!(only_keyfile.requires_keyfile());
+        let err = only_keyfile.unlock_key("password2", None).unwrap_err();
+        assert!(err.downcast_ref::<KeyfileErr>().is_some());
+
+        fs::remove_file(&keyfile).unwrap();
+    }
+
+    #[test]
+    fn test_recovery_slot_opens_without_password_or_keyfile() {
+        let mut header = Vault::generate();
+        // Set by registers created WITH KEYFILE before slots had flags.
+        header.flags = FLAG_KEYFILE;
+        let data_key = Vault::generate_data_key();
+        assert!(matches!(
+            header.unlock_recovery("anything"),
+            Err(RecoveryErr::NotSet)
+        ));
+
+        let phrase = crate::encryption::recovery::generate();
+        let slot = header.recovery_slot(&phrase, &data_key).unwrap();
+        header.slots.push(slot);
+        let (header, _) = Vault::from_bytes(&header.to_bytes()).unwrap();
+
+        assert_eq!(header.unlock_recovery(&phrase).unwrap(), (data_key, 0));
+        let other = crate::encryption::recovery::generate();
+        assert!(matches!(
+            header.unlock_recovery(&other),
+            Err(RecoveryErr::WrongKey)
+        ));
+        // Not a password slot: the phrase doesn't open it as a password.
+        assert!(!header.accepts_keyfile());
+        assert!(header.unlock_key(&phrase, None).is_err());
+    }
+
+    #[test]
+    fn test_shares_slot_opens_with_the_rebuilt_secret() {
+        use crate::encryption::shamir;
+
+        let mut header = Vault::generate();
+        let data_key = Vault::generate_data_key();
+        let secret: [u8; 32] = rand::random();
+        let slot = header.shares_slot(&secret, &data_key).unwrap();
+        let set = Vault::share_set(&slot);
+        header.slots.push(slot);
+        let (header, _) = Vault::from_bytes(&header.to_bytes()).unwrap();
+
+        let shares = shamir::split(&secret, 5, 3, set).unwrap();
+        let rebuilt = shamir::combine(&shares[2..]).unwrap();
+        assert_eq!(header.unlock_shares(set, &rebuilt).unwrap(), (data_key, 0));
+
+        // Shares of a split whose slot was removed, or of another register.
+        let other: [u8; 32] = rand::random();
+        assert!(matches!(
+            header.unlock_shares(set, &other),
+            Err(ShareErr::NotFound)
+        ));
+        assert!(matches!(
+            header.unlock_shares([0; 4], &rebuilt),
+            Err(ShareErr::NotFound)
+        ));
+    }
+
+    #[test]
+    fn test_recipient_slot_opens_with_the_member_identity() {
+        let mut header = Vault::generate();
+        let data_key = Vault::generate_data_key();
+        let member = Identity::generate();
+        let slot = header.recipient_slot(&member.public(), &data_key).unwrap();
+        header.slots.push(slot);
+        let (header, _) = Vault::from_bytes(&header.to_bytes()).unwrap();
+
+        assert_eq!(header.member_slot(&member.public()), Some(0));
+        assert_eq!(header.unlock_identity(&member).unwrap(), (data_key, 0));
+        assert!(matches!(
+            header.unlock_identity(&Identity::generate()),
+            Err(IdentityErr::NotGranted)
+        ));
+
+        // The member key is bound into the slot, swapping it breaks the wrap.
+        let mut swapped = header.clone();
+        swapped.slots[0].recipient.as_mut().unwrap().ephemeral[0] ^= 0x01;
+        assert!(swapped.unlock_identity(&member).is_err());
+    }
+
+    #[test]
+    fn test_v1_header_is_read_with_legacy_defaults() {
+        let mut bytes = b"PWMN".to_vec();
+        bytes.extend_from_slice(&1u16.to_le_bytes());
+        bytes.extend_from_slice(&[1u8; 16]);
+        bytes.extend_from_slice(&[2u8; 12]);
+        bytes.extend_from_slice(b"ciphertext");
+
+        let (parsed, offset) = Vault::from_bytes(&bytes).unwrap();
+        assert_eq!(offset, 34);
+        assert!(parsed.is_outdated());
+        assert_eq!(parsed.cipher, CipherId::ChaCha20Poly1305);
+        assert_eq!(parsed.nonce, [2u8; 12]);
+        assert_eq!(parsed.slots.len(), 1);
+        assert!(parsed.slots[0].is_legacy());
+        assert_eq!(parsed.slots[0].salt, [1u8; 16]);
+        assert_eq!(parsed.slots[0].kdf, KdfParams::default());
+    }
+
+    #[test]
+    fn test_header_check_covers_the_key_slots() {
+        let mut header = Vault::generate();
+        let data_key = Vault::generate_data_key();
+        let slot = header
+            .password_slot("password1", None, TEST_KDF, &data_key)
+            .unwrap();
+        header.slots.push(slot);
+        let bytes = header.to_bytes();
+
+        let salt_at = bytes
+            .windows(16)
+            .position(|w| w == header.slots[0].salt)
+            .unwrap();
+        let mut salted = bytes.clone();
+        salted[salt_at] ^= 0x01;
+        assert!(matches!(
+            Vault::from_bytes(&salted),
+            Err(VaultValidationErr::TamperedHeader)
+        ));
+        let mut checked = bytes.clone();
+        *checked.last_mut().unwrap() ^= 0x01;
+        assert!(matches!(
+            Vault::from_bytes(&checked),
+            Err(VaultValidationErr::TamperedHeader)
+        ));
+
+        // Nor can the check be left out.
+        let mut unchecked = bytes[..bytes.len() - 3 - HEADER_CHECK_LEN].to_vec();
+        let header_len = unchecked.len() as u16;
+        unchecked[6..8].copy_from_slice(&header_len.to_le_bytes());
+        assert!(matches!(
+            Vault::from_bytes(&unchecked),
+            Err(VaultValidationErr::TamperedHeader)
+        ));
+    }
+
+    #[test]
+    fn test_malformed_headers_are_rejected() {
+        let bytes = Vault::generate().to_bytes();
+
+        // Truncated inside the field list.
+        assert!(Vault::from_bytes(&bytes[..bytes.len() - 4]).is_err());
+
+        // Unknown tag.
+        let mut unknown = bytes.clone();
+        unknown[8] = 0x7f;
+        assert!(Vault::from_bytes(&unknown).is_err());
+
+        // Undefined flag bit.
+        let mut flagged = bytes.clone();
+        flagged[11] = 0x80;
+        assert!(Vault::from_bytes(&flagged).is_err());
+    }
+}
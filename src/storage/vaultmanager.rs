@@ -1,52 +1,176 @@
+use super::atomic;
+use super::backup;
+use super::enc_auth::AUTH;
+use super::rootmeta::{ROOT_META_N, RootMeta};
 use super::vault::VAULT_N;
-use super::{init::ROOT_REG, vault::Vault};
+use super::{init, vault::Vault};
 use crate::encryption::kdf::derive_fast_key;
-use crate::error::{self, ConnectionErr, CreateErr, DropErr, FileReqErr};
+use crate::error::{self, ConnectionErr, CreateErr, DropErr, FileReqErr, VaultValidationErr};
+use crate::session::lock::{DirLock, LOCK_N, LockMode};
 use crate::storage::vaultmod::VaultMod;
 use hex;
-use std::fs::create_dir_all as mksafe_dir;
+use std::fs::{self, create_dir_all as mksafe_dir};
 use std::path::PathBuf;
 
+// Name salt of roots created before rvault.bin existed. Registers hashed with it are
+// still found, and are moved to the root's own salt on their next read-write CONNECT.
 pub const SALT: [u8; 16] = [
     188, 209, 128, 213, 229, 38, 112, 152, 37, 246, 56, 123, 185, 210, 43, 26,
 ];
 
+pub const STAGING_PREFIX: &str = ".staging-";
+
 pub struct VaultManager {
     p: PathBuf,
+    name_salt: [u8; 16],
 }
 
 impl VaultManager {
     pub fn load() -> Result<Self, Box<dyn std::error::Error>> {
-        let home = dirs_next::home_dir().ok_or(error::HomeDirErr::InvalidHomeDir)?;
-        let root_folder = home.join(ROOT_REG);
-        if !(root_folder.try_exists()?) {
+        let root_folder = init::root_path()?;
+        if !init::is_initialized(&root_folder)? {
             return Err(Box::new(CreateErr::VaultNotExists));
         }
 
-        Ok(Self { p: root_folder })
+        let name_salt = match RootMeta::load(&root_folder)? {
+            Some(meta) => meta.name_salt,
+            None => VaultManager::adopt_root_meta(&root_folder)?,
+        };
+        Ok(Self {
+            p: root_folder,
+            name_salt,
+        })
+    }
+
+    // Gives a root from before rvault.bin its own salt. This needs the root to itself;
+    // when another process holds it the legacy salt is used until the next run. A root
+    // whose registers keep a copy of rvault.bin lost it instead, and a new salt would
+    // hide them, so that is left to VERIFY ALL.
+    fn adopt_root_meta(root: &PathBuf) -> Result<[u8; 16], Box<dyn std::error::Error>> {
+        if !VaultManager::root_meta_copies(root)?.is_empty() {
+            return Err(Box::new(VaultValidationErr::MissingRootMeta));
+        }
+        let Ok(_lock) = DirLock::acquire(root, LockMode::Exclusive, "The root vault") else {
+            return Ok(SALT);
+        };
+        if let Some(meta) = RootMeta::load(root)? {
+            return Ok(meta.name_salt);
+        }
+        let meta = RootMeta::generate();
+        meta.save(root)?;
+        println!(
+            "Generated a per-installation salt for register names in {}",
+            ROOT_META_N
+        );
+        Ok(meta.name_salt)
+    }
+
+    // The copies of rvault.bin found in register folders, `None` for a damaged one.
+    // Only registers named with a salt of the root's own keep one.
+    fn root_meta_copies(
+        root: &PathBuf,
+    ) -> Result<Vec<Option<RootMeta>>, Box<dyn std::error::Error>> {
+        let mut copies = vec![];
+        for entry in fs::read_dir(root)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            if !VaultManager::is_register_id(&name) || !entry.file_type()?.is_dir() {
+                continue;
+            }
+            if entry.path().join(ROOT_META_N).try_exists()? {
+                copies.push(RootMeta::load(&entry.path()).ok().flatten());
+            }
+        }
+        Ok(copies)
+    }
+
+    // Writes rvault.bin back from the copies its registers keep, when they all agree.
+    // `None` when there is no copy to restore from.
+    pub fn restore_root_meta(
+        root: &PathBuf,
+    ) -> Result<Option<RootMeta>, Box<dyn std::error::Error>> {
+        let _lock = DirLock::acquire(root, LockMode::Exclusive, "The root vault")?;
+        if let Some(meta) = RootMeta::load(root)? {
+            return Ok(Some(meta));
+        }
+        let copies = VaultManager::root_meta_copies(root)?;
+        let Some(Some(first)) = copies.first() else {
+            return match copies.is_empty() {
+                true => Ok(None),
+                false => Err(Box::new(VaultValidationErr::RootMetaCopiesDiffer)),
+            };
+        };
+        let agree = copies
+            .iter()
+            .all(|c| c.as_ref().is_some_and(|c| c.name_salt == first.name_salt));
+        if !agree {
+            return Err(Box::new(VaultValidationErr::RootMetaCopiesDiffer));
+        }
+        first.save(root)?;
+        Ok(Some(first.clone()))
+    }
+
+    // Keeps a copy of rvault.bin in a register folder, so a root that loses it still
+    // knows the salt its registers are named with.
+    pub fn save_root_meta_copy(&self, reg_dir: &PathBuf) -> Result<(), Box<dyn std::error::Error>> {
+        if self.name_salt == SALT {
+            return Ok(());
+        }
+        let meta = RootMeta::with_salt(self.name_salt);
+        match RootMeta::load(reg_dir) {
+            Ok(Some(copy)) if copy.name_salt == meta.name_salt => Ok(()),
+            _ => meta.save(reg_dir),
+        }
     }
+
     pub fn get_root_path(&self) -> &PathBuf {
         &self.p
     }
 
+    pub fn register_id(&self, reg_name: &str) -> String {
+        format!(
+            ".{}",
+            hex::encode(derive_fast_key(reg_name, &self.name_salt))
+        )
+    }
+
+    fn legacy_register_id(reg_name: &str) -> String {
+        format!(".{}", hex::encode(derive_fast_key(reg_name, &SALT)))
+    }
+
+    // Whether the folder a register was found in is still named with the legacy salt.
+    pub fn is_legacy(&self, reg_name: &str, child: &PathBuf) -> bool {
+        let id = self.register_id(reg_name);
+        child.file_name().is_some_and(|n| n.to_string_lossy() != id)
+    }
+
+    // Returns the folder id and path of a register. New registers always get the
+    // root's own salt; existing ones are looked up under it first and under the
+    // legacy salt second.
     pub fn validate_register(
         &self,
         reg_name: &str,
         to_create: bool,
     ) -> Result<(String, PathBuf), Box<dyn std::error::Error>> {
-        let child = format!(".{}", hex::encode(derive_fast_key(reg_name, &SALT)));
+        let child = self.register_id(reg_name);
         let path = PathBuf::from(&self.p).join(&child);
-        if path
+        let legacy = VaultManager::legacy_register_id(reg_name);
+        let legacy_path = PathBuf::from(&self.p).join(&legacy);
+
+        let exists = path
             .try_exists()
-            .map_err(|E| FileReqErr::UnexpectedIOError)?
-            && to_create
-        {
+            .map_err(|E| FileReqErr::UnexpectedIOError)?;
+        let legacy_exists = legacy != child
+            && legacy_path
+                .try_exists()
+                .map_err(|E| FileReqErr::UnexpectedIOError)?;
+
+        if to_create && (exists || legacy_exists) {
             return Err(Box::new(CreateErr::RegisterAlreadyExists));
-        } else if !path
-            .try_exists()
-            .map_err(|E| FileReqErr::UnexpectedIOError)?
-            && !to_create
-        {
+        } else if !to_create && !exists {
+            if legacy_exists {
+                return Ok((legacy, legacy_path));
+            }
             return Err(Box::new(DropErr::VaultNotExists {
                 vault: reg_name.to_string(),
             }));
@@ -54,31 +178,389 @@ impl VaultManager {
         Ok((child, path))
     }
 
-    pub fn create_child(&self, reg_name: &str) -> Result<VaultMod, Box<dyn std::error::Error>> {
+    // Moves a register found under the legacy salt to its folder under the root's salt.
+    // The folder id is part of the associated data, so the register is resealed for
+    // the new id in a staging folder that is renamed into place once complete. Backups
+    // that open with the same key are resealed too, older ones are set aside under the
+    // old id, RESTORE binds one to the new folder once its password is given. Backups
+    // from before the register's format upgrade open with `legacy_key` and are brought
+    // under the data key on the way. Returns the lock of the new folder, which
+    // replaces the caller's lock.
+    pub fn rehash(
+        &self,
+        reg_name: &str,
+        vault: &mut VaultMod,
+        key: [u8; 32],
+        legacy_key: Option<[u8; 32]>,
+        plaintext: &[u8],
+    ) -> Result<DirLock, Box<dyn std::error::Error>> {
+        let _root_lock = DirLock::acquire(&self.p, LockMode::Exclusive, "The root vault")?;
+        let header = vault.load_header()?;
+
+        let mut moved = self.stage(self.register_id(reg_name))?;
+        let staged = (|| {
+            let mut lock = DirLock::acquire(&moved.p, LockMode::Exclusive, "The register")?;
+            VaultManager::copy_folder(&vault.p, &moved.p)?;
+            moved.pathfP = Some(moved.p.join(VAULT_N));
+            moved.seal_with(header.clone(), key, plaintext.to_vec())?;
+            let stale = VaultManager::rebind_backups(vault, &moved, key, legacy_key)?;
+            if stale > 0 {
+                println!(
+                    "Set aside {} backup(s) made under an earlier password in {}, RESTORE FROM BACKUP still restores them with that password",
+                    stale,
+                    moved
+                        .p
+                        .join(backup::BACKUP_DIR)
+                        .join(backup::STALE_DIR)
+                        .display()
+                );
+            }
+            self.commit_child(&mut moved)?;
+            lock.moved_to(&moved.p);
+            Ok::<_, Box<dyn std::error::Error>>(lock)
+        })();
+        let lock = match staged {
+            Ok(lock) => lock,
+            Err(e) => {
+                let _ = fs::remove_dir_all(&moved.p);
+                return Err(e);
+            }
+        };
+
+        // The new folder is complete and found first, the old one is only a leftover.
+        fs::remove_dir_all(&vault.p)?;
+        atomic::sync_dir(&self.p)?;
+        *vault = moved;
+        Ok(lock)
+    }
+
+    // Everything but the two vault copies, which are resealed, and the lock file.
+    fn copy_folder(from: &PathBuf, to: &PathBuf) -> Result<(), Box<dyn std::error::Error>> {
+        for entry in fs::read_dir(from)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name == VAULT_N || name == AUTH || name == LOCK_N {
+                continue;
+            }
+            if entry.file_type()?.is_dir() {
+                mksafe_dir(to.join(&name))?;
+                VaultManager::copy_folder(&entry.path(), &to.join(&name))?;
+            } else {
+                fs::copy(entry.path(), to.join(&name))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn rebind_backups(
+        old: &VaultMod,
+        moved: &VaultMod,
+        key: [u8; 32],
+        legacy_key: Option<[u8; 32]>,
+    ) -> Result<usize, Box<dyn std::error::Error>> {
+        let mut stale = 0;
+        for b in backup::list(&moved.p)? {
+            let bytes = fs::read(&b.path)?;
+            let (mut b_header, _) = Vault::from_bytes(&bytes)?;
+            let plaintext = match (b_header.is_outdated(), legacy_key) {
+                (false, _) => old.open(key, &bytes).ok(),
+                (true, Some(legacy_key)) => match old.open(legacy_key, &bytes) {
+                    // The same upgrade as `VaultMod::migrate`: the legacy key wraps the
+                    // data key, so the backup still opens with its own password.
+                    Ok(plaintext) => {
+                        b_header.upgrade_slots(legacy_key, &key)?;
+                        Some(plaintext)
+                    }
+                    Err(_) => None,
+                },
+                (true, None) => None,
+            };
+            // Without the key it was made under, a backup can't be resealed for the
+            // new id. It is moved out of the way rather than removed, its password may
+            // still be known.
+            let Some(plaintext) = plaintext else {
+                backup::set_aside(&moved.p, &b, &old.id)?;
+                stale += 1;
+                continue;
+            };
+            b_header.nonce = b_header.cipher.generate_nonce();
+            let ciphertext = moved.encrypt_with(&mut b_header, key, plaintext.to_vec())?;
+            let mut rebound = b_header.to_bytes();
+            rebound.extend_from_slice(&ciphertext);
+            atomic::write_atomic(&b.path, &rebound)?;
+        }
+        Ok(stale)
+    }
+
+    // Allocates a new register in a `.staging-<uuid>` folder of the root. The vault
+    // already carries its final id, which is bound into the ciphertext, and only gets
+    // its final folder name from `commit_child` once it has been fully written.
+    pub fn stage_child(&self, reg_name: &str) -> Result<VaultMod, Box<dyn std::error::Error>> {
         let (f_hex, _) = self.validate_register(reg_name, true)?;
-        let target_folder = PathBuf::from(&self.p).join(&f_hex);
-        mksafe_dir(&target_folder)
-            .map_err(|e| "Something Went Wrong while creating the register")?;
+        self.stage(f_hex)
+    }
+
+    fn stage(&self, f_hex: String) -> Result<VaultMod, Box<dyn std::error::Error>> {
+        let staging = self
+            .p
+            .join(format!("{}{}", STAGING_PREFIX, uuid::Uuid::new_v4()));
+        mksafe_dir(&staging).map_err(|e| "Something Went Wrong while creating the register")?;
         Ok(VaultMod {
-            p: target_folder,
+            p: staging,
+            id: f_hex,
             pathfP: None,
-            salt: None,
-            nonce: None,
+            header: None,
         })
     }
 
+    pub fn commit_child(&self, vault: &mut VaultMod) -> Result<(), Box<dyn std::error::Error>> {
+        let target = self.p.join(&vault.id);
+        if target.try_exists()? {
+            return Err(Box::new(CreateErr::RegisterAlreadyExists));
+        }
+        self.save_root_meta_copy(&vault.p)?;
+        fs::rename(&vault.p, &target)?;
+        atomic::sync_dir(&self.p)?;
+        vault.pathfP = Some(target.join(VAULT_N));
+        vault.p = target;
+        Ok(())
+    }
+
+    // Staging folders left behind by a CREATE that was killed before it could clean up.
+    // Callers hold the root lock exclusively, so none of them belongs to a running CREATE.
+    pub fn remove_stale_staging(&self) -> Result<usize, Box<dyn std::error::Error>> {
+        let mut removed = 0;
+        for entry in fs::read_dir(&self.p)? {
+            let entry = entry?;
+            if entry
+                .file_name()
+                .to_string_lossy()
+                .starts_with(STAGING_PREFIX)
+                && entry.file_type()?.is_dir()
+            {
+                fs::remove_dir_all(entry.path())?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
     pub fn external_vault_load(
         &self,
         child: &PathBuf,
     ) -> Result<VaultMod, Box<dyn std::error::Error>> {
-        let mut vault = VaultMod {
+        let mut vault = self.vault_at(child);
+        if vault.recover()? {
+            println!(
+                "Restored {} from its auth copy after an interrupted write",
+                VAULT_N
+            );
+        }
+        vault.load_header()?;
+        Ok(vault)
+    }
+
+    // The register folder as it is on disk, without the recovery and header checks of
+    // `external_vault_load`. Used to inspect a register that may be damaged.
+    pub fn vault_at(&self, child: &PathBuf) -> VaultMod {
+        let id = child
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+        VaultMod {
             p: child.clone(),
+            id,
             pathfP: Some(child.clone().join(VAULT_N)),
-            salt: None,
-            nonce: None,
-        };
-        vault.load_salt()?;
-        vault.load_nonce()?;
-        Ok(vault)
+            header: None,
+        }
+    }
+
+    // Whether a folder name has the shape of a register id: a dot followed by the
+    // hex encoded name hash.
+    pub fn is_register_id(name: &str) -> bool {
+        name.strip_prefix('.')
+            .is_some_and(|hash| hash.len() == 64 && hash.bytes().all(|b| b.is_ascii_hexdigit()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_manager() -> VaultManager {
+        let p = std::env::temp_dir().join(format!("pwmn-root-{}", uuid::Uuid::new_v4()));
+        mksafe_dir(&p).unwrap();
+        VaultManager {
+            p,
+            name_salt: RootMeta::generate().name_salt,
+        }
+    }
+
+    #[test]
+    fn test_register_ids_are_told_apart_from_other_folders() {
+        let manager = temp_manager();
+        let id = manager.register_id("personal");
+        assert!(VaultManager::is_register_id(&id));
+        assert!(VaultManager::is_register_id(
+            &VaultManager::legacy_register_id("personal")
+        ));
+
+        let staging = format!("{}{}", STAGING_PREFIX, &id[1..]);
+        for other in [
+            staging.as_str(),
+            &id[1..],
+            &id[..64],
+            &format!("{}0", id),
+            &format!(".{}", "g".repeat(64)),
+            "backups",
+            ".",
+            "",
+        ] {
+            assert!(!VaultManager::is_register_id(other), "{}", other);
+        }
+
+        fs::remove_dir_all(&manager.p).unwrap();
+    }
+
+    #[test]
+    fn test_staged_register_only_appears_once_committed() {
+        let manager = temp_manager();
+        let mut vault = manager.stage_child("personal").unwrap();
+        let id = manager.register_id("personal");
+        assert_eq!(vault.id, id);
+        assert_eq!(vault.p.parent(), Some(manager.p.as_path()));
+        assert!(vault.p.is_dir());
+        assert!(!manager.p.join(&id).exists());
+
+        fs::write(vault.p.join(VAULT_N), b"header").unwrap();
+        manager.commit_child(&mut vault).unwrap();
+        assert_eq!(vault.p, manager.p.join(&id));
+        assert_eq!(vault.pathfP, Some(manager.p.join(&id).join(VAULT_N)));
+        assert_eq!(
+            fs::read(manager.p.join(&id).join(VAULT_N)).unwrap(),
+            b"header"
+        );
+
+        // The name is taken now, and a second staging can't be committed over it.
+        assert!(manager.stage_child("personal").is_err());
+        let mut second = manager.stage(id.clone()).unwrap();
+        let err = manager.commit_child(&mut second).unwrap_err();
+        assert!(err.downcast_ref::<CreateErr>().is_some());
+        assert!(second.p.is_dir());
+        assert_eq!(
+            fs::read(manager.p.join(&id).join(VAULT_N)).unwrap(),
+            b"header"
+        );
+
+        fs::remove_dir_all(&manager.p).unwrap();
+    }
+
+    #[test]
+    fn test_only_staging_folders_are_removed_as_stale() {
+        let manager = temp_manager();
+        let staged = manager.stage_child("personal").unwrap();
+        let register = manager.p.join(manager.register_id("work"));
+        mksafe_dir(&register).unwrap();
+        mksafe_dir(manager.p.join("backups")).unwrap();
+        // A file carrying the prefix isn't a staging folder.
+        let file = manager.p.join(format!("{}note", STAGING_PREFIX));
+        fs::write(&file, b"").unwrap();
+
+        assert_eq!(manager.remove_stale_staging().unwrap(), 1);
+        assert!(!staged.p.exists());
+        assert!(register.is_dir());
+        assert!(manager.p.join("backups").is_dir());
+        assert!(file.is_file());
+        assert_eq!(manager.remove_stale_staging().unwrap(), 0);
+
+        fs::remove_dir_all(&manager.p).unwrap();
+    }
+
+    #[test]
+    fn test_lost_root_meta_is_restored_not_regenerated() {
+        let manager = temp_manager();
+        // A root without registers, as left by an old INIT, just gets a salt.
+        VaultManager::adopt_root_meta(&manager.p).unwrap();
+        fs::remove_file(manager.p.join(ROOT_META_N)).unwrap();
+
+        let mut vault = manager.stage_child("personal").unwrap();
+        manager.commit_child(&mut vault).unwrap();
+        let err = VaultManager::adopt_root_meta(&manager.p).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<VaultValidationErr>(),
+            Some(VaultValidationErr::MissingRootMeta)
+        ));
+        assert!(!manager.p.join(ROOT_META_N).exists());
+
+        let restored = VaultManager::restore_root_meta(&manager.p)
+            .unwrap()
+            .unwrap();
+        assert_eq!(restored.name_salt, manager.name_salt);
+        let loaded = RootMeta::load(&manager.p).unwrap().unwrap();
+        assert_eq!(loaded.name_salt, manager.name_salt);
+
+        // Copies that disagree leave the choice to the user.
+        fs::remove_file(manager.p.join(ROOT_META_N)).unwrap();
+        let other = manager.p.join(manager.register_id("work"));
+        mksafe_dir(&other).unwrap();
+        RootMeta::generate().save(&other).unwrap();
+        let err = VaultManager::restore_root_meta(&manager.p).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<VaultValidationErr>(),
+            Some(VaultValidationErr::RootMetaCopiesDiffer)
+        ));
+        assert!(!manager.p.join(ROOT_META_N).exists());
+
+        fs::remove_dir_all(&manager.p).unwrap();
+    }
+
+    #[test]
+    fn test_rehash_sets_aside_backups_it_cannot_reseal() {
+        let manager = temp_manager();
+        let legacy_p = manager.p.join(VaultManager::legacy_register_id("personal"));
+        mksafe_dir(&legacy_p).unwrap();
+        let mut vault = manager.vault_at(&legacy_p);
+        vault.allocate().unwrap();
+
+        // Two backups under an earlier key, one under the current key.
+        let (old_key, key) = (Vault::generate_data_key(), Vault::generate_data_key());
+        for (k, plaintext) in [(old_key, "first"), (old_key, "second"), (key, "third")] {
+            let header = vault.load_header().unwrap();
+            vault.seal_with(header, k, plaintext.into()).unwrap();
+        }
+        let header = vault.load_header().unwrap();
+        vault.seal_with(header, key, b"fourth".to_vec()).unwrap();
+        assert_eq!(backup::list(&legacy_p).unwrap().len(), 3);
+
+        let _lock = manager
+            .rehash("personal", &mut vault, key, None, b"fourth")
+            .unwrap();
+        assert_eq!(vault.p, manager.p.join(manager.register_id("personal")));
+        assert!(!legacy_p.exists());
+        assert!(RootMeta::load(&vault.p).unwrap().is_some());
+
+        let kept = backup::list(&vault.p).unwrap();
+        assert_eq!(kept.len(), 1);
+        assert_eq!(
+            *vault.open(key, &fs::read(&kept[0].path).unwrap()).unwrap(),
+            b"third"
+        );
+
+        // The other two still open under the old folder id, and RESTORE binds them to
+        // the new one.
+        let stale = backup::list_stale(&vault.p).unwrap();
+        assert_eq!(stale.len(), 2);
+        let old_id = legacy_p.file_name().unwrap().to_str().unwrap();
+        assert!(stale.iter().all(|b| b.bound_to.as_deref() == Some(old_id)));
+        let found = backup::find(&vault.p, &stale[0].timestamp).unwrap();
+        assert_eq!(found.path, stale[0].path);
+        let bytes = fs::read(&found.path).unwrap();
+        assert!(vault.open(old_key, &bytes).is_err());
+        let plaintext = vault.bound_to(old_id).open(old_key, &bytes).unwrap();
+        assert_eq!(*plaintext, b"second");
+        let rebound = vault.rebind(&bytes, old_key, &plaintext).unwrap();
+        assert_eq!(*vault.open(old_key, &rebound).unwrap(), b"second");
+
+        fs::remove_dir_all(&manager.p).unwrap();
     }
 }
@@ -1,14 +1,18 @@
+use crate::encryption::aead::{self, CipherId, DEFAULT_CIPHER};
 use crate::encryption::enc_utl::KdfMode;
-use crate::error::VaultValidationErr;
+use crate::error::{DecryptionErr, VaultValidationErr};
 use anyhow::ensure;
 use rand::rngs::adapter::ReseedingRng;
 // use crate::encryption::kdf;
 use super::super::encryption::kdf;
+use super::atomic;
+use super::backup;
+use super::enc_auth::AUTH;
 use super::init::ROOT_REG;
-use super::vault::Vault;
+use super::vault::{KeySlot, Vault};
 use crate::encryption::kdf::{derive_fast_key, derive_slow_key};
 use crate::error::{self, CreateErr};
-use crate::storage::vault::VAULT_N;
+use crate::storage::vault::{VAULT_N, VAULT_VERSION};
 use std::fmt::format;
 use std::io::Read;
 use std::io::Seek;
@@ -17,18 +21,22 @@ use std::io::Write;
 use std::os::unix::fs::OpenOptionsExt;
 use std::{
     env,
-    fs::{File, OpenOptions, create_dir_all as mksafe_dir},
+    fs::{self, File, OpenOptions, create_dir_all as mksafe_dir},
     path::PathBuf,
 };
+use zeroize::Zeroizing;
 
 type DynamicErr = Box<dyn std::error::Error>;
 
 #[derive(Debug)]
 pub struct VaultMod {
     pub p: PathBuf,
+    // Hashed folder name of the register, bound into every ciphertext so a vault
+    // file only opens inside the register it was written for.
+    pub id: String,
     pub pathfP: Option<PathBuf>,
-    pub salt: Option<[u8; 16]>,
-    pub nonce: Option<[u8; 12]>,
+    // Parsed header of vault.bin, loaded once through `load_header`.
+    pub header: Option<Vault>,
 }
 
 impl VaultMod {
@@ -39,50 +47,688 @@ impl VaultMod {
         Ok(self)
     }
 
-    pub fn load_salt(&mut self) -> Result<[u8; 16], Box<dyn std::error::Error>> {
-        if self.salt.is_some() {
-            return Ok(self.salt.unwrap());
+    pub fn load_header(&mut self) -> Result<Vault, Box<dyn std::error::Error>> {
+        if let Some(header) = &self.header {
+            return Ok(header.clone());
         }
-        let path = self.pathfP.as_ref().unwrap();
-        let mut file = OpenOptions::new().read(true).open(path)?;
-        file.seek(SeekFrom::Start(6))?;
-        let mut salt = [0u8; 16];
-        file.read_exact(&mut salt)?;
-        self.salt = Some(salt);
-        Ok(salt)
-    }
-
-    pub fn load_nonce(&mut self) -> Result<[u8; 12], Box<dyn std::error::Error>> {
-        if self.nonce.is_some() {
-            return Ok(self.nonce.unwrap());
+        let bytes = fs::read(self.pathfP.as_ref().unwrap())?;
+        let (header, _) = Vault::from_bytes(&bytes)?;
+        self.header = Some(header.clone());
+        Ok(header)
+    }
+
+    // Encrypts the register under the vault's current header. Every call draws a new
+    // nonce and writes it into the header, a (key, nonce) pair is never used twice.
+    pub fn seal(&mut self, key: [u8; 32], plaintext: Vec<u8>) -> Result<(), DynamicErr> {
+        let header = self.load_header()?;
+        self.seal_with(header, key, plaintext)
+    }
+
+    // Same as `seal`, but with caller-provided header fields (a new salt or KDF
+    // parameters when the key itself changes).
+    pub fn seal_with(
+        &mut self,
+        mut header: Vault,
+        key: [u8; 32],
+        plaintext: Vec<u8>,
+    ) -> Result<(), DynamicErr> {
+        let previous = self.header.as_ref().map(|h| h.nonce.clone());
+        let mut nonce = header.cipher.generate_nonce();
+        while previous.as_ref() == Some(&nonce) {
+            nonce = header.cipher.generate_nonce();
         }
-        let mut file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open(self.pathfP.as_ref().unwrap())?;
-        file.seek(SeekFrom::Start(22))?;
-        let mut nonce = [0u8; 12];
-        file.read_exact(&mut nonce)?;
-        self.nonce = Some(nonce);
-        Ok(nonce)
+        header.nonce = nonce;
+        let ciphertext = self.encrypt_with(&mut header, key, plaintext)?;
+        self.rewrite(&header, &ciphertext)
     }
 
-    pub fn validate_f_header(&self) -> Result<(), DynamicErr> {
-        let mut t_file = OpenOptions::new()
-            .read(true)
-            .open(self.pathfP.as_ref().unwrap())?;
-        t_file.seek(SeekFrom::Start(0));
-        let mut t_file_magic = [0u8; 4];
-        let mut t_file_version = [0u8; 2];
-        t_file.read_exact(&mut t_file_magic)?;
-        t_file.read_exact(&mut t_file_version)?;
-        if t_file_magic != *b"PWMN" {
+    // Encrypts under `header` as it will be written, nonce included, binding the
+    // header (key slots aside) and this register's id. Callers pick the nonce. The
+    // plaintext is wiped once it is encrypted.
+    pub fn encrypt_with(
+        &self,
+        header: &mut Vault,
+        key: [u8; 32],
+        plaintext: Vec<u8>,
+    ) -> Result<Vec<u8>, DynamicErr> {
+        let plaintext = Zeroizing::new(plaintext);
+        header.version = VAULT_VERSION;
+        let aad = self.associated_data(&header.bound_bytes());
+        Ok(header.cipher.seal(&key, &header.nonce, &plaintext, &aad)?)
+    }
+
+    // Decrypts a full vault file (header + ciphertext) with its data key, as given by
+    // `Vault::unlock_key`. Before v4 the key is checked against the header's key check
+    // to tell a wrong password apart, an edited salt still looks like one there. From
+    // v4 on an edited header fails its check in `Vault::from_bytes` and a wrong
+    // password never gets this far, so any failure means the file was modified or moved.
+    pub fn open(&self, key: [u8; 32], bytes: &[u8]) -> Result<Zeroizing<Vec<u8>>, DynamicErr> {
+        let (header, offset) = Vault::from_bytes(bytes)?;
+        let encrypted = bytes[offset..].to_vec();
+        let aad = match header.version {
+            1 => return Ok(header.cipher.open(&key, &header.nonce, &encrypted, &[])?),
+            2 | 3 => {
+                if header.key_check != Vault::key_check(&key) {
+                    return Err(Box::new(DecryptionErr::DecryptionErr));
+                }
+                self.associated_data(&bytes[..offset])
+            }
+            _ => self.associated_data(&header.bound_bytes()),
+        };
+        let plaintext = header
+            .cipher
+            .open(&key, &header.nonce, &encrypted, &aad)
+            .map_err(|_| VaultValidationErr::TamperedVault)?;
+        Ok(plaintext)
+    }
+
+    // Rewrites a vault that is still in an older format, once its key is known. The
+    // old file is kept next to it as vault.bin.v<N>.bak. The data is re-encrypted with
+    // a new data key under the default cipher, wrapped in a slot under the old key, so
+    // the password, keyfile and KDF stay what they were. Returns the version it had and the data key.
+    pub fn migrate(
+        &mut self,
+        key: [u8; 32],
+        plaintext: &[u8],
+    ) -> Result<Option<(u16, [u8; 32])>, DynamicErr> {
+        let mut header = self.load_header()?;
+        if !header.is_outdated() {
+            return Ok(None);
+        }
+        let vault_p = self.pathfP.as_ref().unwrap().clone();
+        let backup = self.p.join(format!("{}.v{}.bak", VAULT_N, header.version));
+        fs::copy(&vault_p, &backup)?;
+
+        let old_version = header.version;
+        let data_key = Vault::generate_data_key();
+        header.upgrade_slots(key, &data_key)?;
+        header.cipher = DEFAULT_CIPHER;
+        self.seal_with(header, data_key, plaintext.to_vec())?;
+        Ok(Some((old_version, data_key)))
+    }
+
+    // Writes new key slots in front of the unchanged ciphertext, which is how the
+    // password changes without re-encrypting the register. Everything but the slots
+    // must match the header on disk, it is bound to the data.
+    pub fn rewrap(&mut self, header: Vault) -> Result<(), DynamicErr> {
+        let bytes = fs::read(self.p.join(VAULT_N))?;
+        let (current, offset) = Vault::from_bytes(&bytes)?;
+        if current.is_outdated() || current.bound_bytes() != header.bound_bytes() {
             return Err(Box::new(VaultValidationErr::MismatchedFileHeader));
         }
+        self.rewrite(&header, &bytes[offset..])
+    }
+
+    // The register as seen by a file bound to the folder id `id`, such as a backup a
+    // rehash set aside. Only good for opening that file.
+    pub fn bound_to(&self, id: &str) -> VaultMod {
+        VaultMod {
+            p: self.p.clone(),
+            id: id.to_string(),
+            pathfP: None,
+            header: None,
+        }
+    }
+
+    // Seals the `plaintext` of a vault file bound to another folder for this one, under
+    // the file's own key slots, and returns the new file. `key` is what its slots open.
+    // An older format moves to a new data key on the way, like `migrate`.
+    pub fn rebind(
+        &self,
+        bytes: &[u8],
+        key: [u8; 32],
+        plaintext: &[u8],
+    ) -> Result<Vec<u8>, DynamicErr> {
+        let (mut header, _) = Vault::from_bytes(bytes)?;
+        let data_key = if header.is_outdated() {
+            let data_key = Zeroizing::new(Vault::generate_data_key());
+            header.upgrade_slots(key, &data_key)?;
+            header.cipher = DEFAULT_CIPHER;
+            data_key
+        } else {
+            Zeroizing::new(key)
+        };
+        header.nonce = header.cipher.generate_nonce();
+        let ciphertext = self.encrypt_with(&mut header, *data_key, plaintext.to_vec())?;
+        let mut rebound = header.to_bytes();
+        rebound.extend_from_slice(&ciphertext);
+        Ok(rebound)
+    }
+
+    fn associated_data(&self, header: &[u8]) -> Vec<u8> {
+        let mut aad = header.to_vec();
+        aad.extend_from_slice(self.id.as_bytes());
+        aad
+    }
+
+    // Replaces both the vault file and its auth copy with a new header and ciphertext.
+    pub fn rewrite(&mut self, header: &Vault, ciphertext: &[u8]) -> Result<(), DynamicErr> {
+        let mut vault_bytes = header.to_bytes();
+        vault_bytes.extend_from_slice(ciphertext);
+        self.replace(&vault_bytes)?;
+        self.header = Some(header.clone());
+        Ok(())
+    }
+
+    // Writes a complete vault file, header included, over vault.bin and auth.pwmn after
+    // backing up the file it replaces. vault.bin is always written first and is the
+    // authoritative copy: a crash between the two writes leaves auth.pwmn one version
+    // behind, which `sync_auth` repairs on the next connect.
+    pub fn replace(&mut self, vault_bytes: &[u8]) -> Result<(), DynamicErr> {
+        let vault_p = self.p.join(VAULT_N);
+        self.backup_current()?;
+
+        // The auth file is a byte-for-byte copy of the vault, header included, so it can
+        // be opened on its own and never needs a nonce of its own.
+        atomic::write_atomic(&vault_p, vault_bytes)?;
+        atomic::write_atomic(&self.p.join(AUTH), vault_bytes)?;
+
+        self.pathfP = Some(vault_p);
+        self.header = None;
+        Ok(())
+    }
+
+    // A freshly allocated vault.bin holds only a header, there is nothing in it to keep.
+    fn backup_current(&self) -> Result<Option<PathBuf>, DynamicErr> {
+        let bytes = match fs::read(self.p.join(VAULT_N)) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(Box::new(e)),
+        };
+        match Vault::from_bytes(&bytes) {
+            Ok((_, offset)) if bytes.len() > offset => Ok(Some(backup::snapshot(&self.p, &bytes)?)),
+            _ => Ok(None),
+        }
+    }
+
+    // Every older copy of the register: its backups, newest first, those set aside by a
+    // move to another folder, and the files kept by format upgrades (vault.bin.v<N>.bak).
+    pub fn older_copies(&self) -> Result<Vec<PathBuf>, DynamicErr> {
+        let mut paths: Vec<PathBuf> = backup::list(&self.p)?
+            .into_iter()
+            .chain(backup::list_stale(&self.p)?)
+            .map(|b| b.path)
+            .collect();
+        for entry in fs::read_dir(&self.p)? {
+            let name = entry?.file_name().to_string_lossy().to_string();
+            if name.starts_with(&format!("{}.v", VAULT_N)) && name.ends_with(".bak") {
+                paths.push(self.p.join(name));
+            }
+        }
+        Ok(paths)
+    }
+
+    // Takes `slot` out of every backup that still has it, set-aside ones included. The
+    // slots aren't bound to the data, so only the header in front of the ciphertext
+    // changes. Returns how many backups had it.
+    pub fn remove_slot_from_backups(&self, slot: &KeySlot) -> Result<usize, DynamicErr> {
+        let mut changed = 0;
+        for b in backup::list(&self.p)?
+            .into_iter()
+            .chain(backup::list_stale(&self.p)?)
+        {
+            let bytes = fs::read(&b.path)?;
+            let Ok((mut header, offset)) = Vault::from_bytes(&bytes) else {
+                continue;
+            };
+            let before = header.slots.len();
+            header.slots.retain(|s| s != slot);
+            if header.slots.len() == before || header.is_outdated() {
+                continue;
+            }
+            let mut rewritten = header.to_bytes();
+            rewritten.extend_from_slice(&bytes[offset..]);
+            atomic::write_atomic(&b.path, &rewritten)?;
+            changed += 1;
+        }
+        Ok(changed)
+    }
+
+    // Moves the backups that open with `old_key` to `new_key`, each under `slots` and
+    // a fresh nonce, and returns how many did. The others are left as they are.
+    pub fn reseal_backups(
+        &self,
+        old_key: [u8; 32],
+        new_key: [u8; 32],
+        slots: &[KeySlot],
+    ) -> Result<usize, DynamicErr> {
+        let mut resealed = 0;
+        for b in backup::list(&self.p)? {
+            let bytes = fs::read(&b.path)?;
+            let opened = Vault::from_bytes(&bytes)
+                .ok()
+                .filter(|(header, _)| !header.is_outdated())
+                .and_then(|(header, _)| Some((header, self.open(old_key, &bytes).ok()?)));
+            let Some((mut header, plaintext)) = opened else {
+                continue;
+            };
+            header.slots = slots.to_vec();
+            header.nonce = header.cipher.generate_nonce();
+            let ciphertext = self.encrypt_with(&mut header, new_key, plaintext.to_vec())?;
+            let mut rebound = header.to_bytes();
+            rebound.extend_from_slice(&ciphertext);
+            atomic::write_atomic(&b.path, &rebound)?;
+            resealed += 1;
+        }
+        Ok(resealed)
+    }
+
+    // Brings the register folder back to a consistent state after an interrupted
+    // write. Stale temp files are dropped, and a vault.bin that is missing or has an
+    // unreadable header is restored from auth.pwmn when that copy is intact.
+    pub fn recover(&mut self) -> Result<bool, DynamicErr> {
+        atomic::remove_stale(&self.p)?;
+
+        let vault_p = self.p.join(VAULT_N);
+        let vault_ok = fs::read(&vault_p)
+            .map(|bytes| Vault::from_bytes(&bytes).is_ok())
+            .unwrap_or(false);
+        if vault_ok {
+            return Ok(false);
+        }
+        let auth_bytes = match fs::read(self.p.join(AUTH)) {
+            Ok(bytes) if Vault::from_bytes(&bytes).is_ok() => bytes,
+            _ => return Ok(false),
+        };
+        atomic::write_atomic(&vault_p, &auth_bytes)?;
+        self.pathfP = Some(vault_p);
+        self.header = None;
+        Ok(true)
+    }
 
-        if u16::from_le_bytes(t_file_version) != 1 {
-            return (Err(Box::new(error::VaultValidationErr::MismatchedFileHeader)));
+    // Re-copies vault.bin into auth.pwmn when the two have drifted apart. Only called
+    // once vault.bin has been opened successfully, so a good copy is never replaced by
+    // a bad one.
+    pub fn sync_auth(&self) -> Result<bool, DynamicErr> {
+        let vault_bytes = fs::read(self.p.join(VAULT_N))?;
+        let auth_p = self.p.join(AUTH);
+        if fs::read(&auth_p).is_ok_and(|auth_bytes| auth_bytes == vault_bytes) {
+            return Ok(false);
         }
+        atomic::write_atomic(&auth_p, &vault_bytes)?;
+        Ok(true)
+    }
+
+    pub fn validate_f_header(&self) -> Result<(), DynamicErr> {
+        let bytes = fs::read(self.pathfP.as_ref().unwrap())?;
+        Vault::from_bytes(&bytes)?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_register() -> VaultMod {
+        let id = format!("pwmn-test-{}", uuid::Uuid::new_v4());
+        let p = env::temp_dir().join(&id);
+        mksafe_dir(&p).unwrap();
+        let mut vault = VaultMod {
+            p,
+            id,
+            pathfP: None,
+            header: None,
+        };
+        vault.allocate().unwrap();
+        vault
+    }
+
+    fn read_header(p: &PathBuf) -> Vault {
+        Vault::from_bytes(&fs::read(p).unwrap()).unwrap().0
+    }
+
+    #[test]
+    fn test_every_seal_uses_a_fresh_nonce() {
+        let mut vault = temp_register();
+        let vault_p = vault.p.join(VAULT_N);
+
+        let key = seal_with_password(&mut vault, "password1", b"first");
+        let first = read_header(&vault_p);
+        vault.seal(key, b"second".to_vec()).unwrap();
+        let second = read_header(&vault_p);
+
+        // The key slots stay put, only the nonce moves.
+        assert_eq!(first.slots, second.slots);
+        assert_ne!(first.nonce, second.nonce);
+
+        let bytes = fs::read(&vault_p).unwrap();
+        assert_eq!(*vault.open(key, &bytes).unwrap(), b"second");
+
+        fs::remove_dir_all(&vault.p).unwrap();
+    }
+
+    #[test]
+    fn test_each_cipher_seals_a_register() {
+        for cipher in [
+            CipherId::XChaCha20Poly1305,
+            CipherId::Aes256GcmSiv,
+            CipherId::ChaCha20Poly1305,
+        ] {
+            let mut vault = temp_register();
+            let mut header = vault.load_header().unwrap();
+            header.cipher = cipher;
+            let key = [9u8; 32];
+            vault.seal_with(header, key, b"register".to_vec()).unwrap();
+
+            let bytes = fs::read(vault.p.join(VAULT_N)).unwrap();
+            let (header, _) = Vault::from_bytes(&bytes).unwrap();
+            assert_eq!(header.cipher, cipher);
+            assert_eq!(header.nonce.len(), cipher.nonce_len());
+            assert_eq!(*vault.open(key, &bytes).unwrap(), b"register");
+
+            fs::remove_dir_all(&vault.p).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_auth_copy_matches_vault() {
+        let mut vault = temp_register();
+        vault.seal([1u8; 32], b"register".to_vec()).unwrap();
+
+        let vault_bytes = fs::read(vault.p.join(VAULT_N)).unwrap();
+        let auth_bytes = fs::read(vault.p.join(AUTH)).unwrap();
+        assert_eq!(vault_bytes, auth_bytes);
+
+        fs::remove_dir_all(&vault.p).unwrap();
+    }
+
+    // Cheap enough for tests, the real parameters take a second per derivation.
+    const TEST_KDF: kdf::KdfParams = kdf::KdfParams {
+        m_cost: 64,
+        t_cost: 1,
+        p_cost: 1,
+    };
+
+    fn seal_with_password(vault: &mut VaultMod, password: &str, plaintext: &[u8]) -> [u8; 32] {
+        let mut header = vault.load_header().unwrap();
+        let data_key = Vault::generate_data_key();
+        let slot = header
+            .password_slot(password, None, TEST_KDF, &data_key)
+            .unwrap();
+        header.slots = vec![slot];
+        vault
+            .seal_with(header, data_key, plaintext.to_vec())
+            .unwrap();
+        data_key
+    }
+
+    #[test]
+    fn test_tampered_header_is_not_a_wrong_password() {
+        let mut vault = temp_register();
+        let key = seal_with_password(&mut vault, "password1", b"register");
+        let bytes = fs::read(vault.p.join(VAULT_N)).unwrap();
+
+        let (header, _) = Vault::from_bytes(&bytes).unwrap();
+        let err = header.unlock_key("password2", None).unwrap_err();
+        assert!(err.downcast_ref::<DecryptionErr>().is_some());
+
+        // Flip a nonce byte: the header check catches it on its own.
+        let nonce_at = bytes
+            .windows(header.nonce.len())
+            .position(|w| w == header.nonce)
+            .unwrap();
+        let mut flipped = bytes.clone();
+        flipped[nonce_at] ^= 0x01;
+        assert!(matches!(
+            Vault::from_bytes(&flipped),
+            Err(VaultValidationErr::TamperedHeader)
+        ));
+
+        // Written with a matching check, the key slot still opens but the associated
+        // data does not.
+        let (mut header, offset) = Vault::from_bytes(&bytes).unwrap();
+        header.nonce[0] ^= 0x01;
+        let bytes = [header.to_bytes(), bytes[offset..].to_vec()].concat();
+        let (header, _) = Vault::from_bytes(&bytes).unwrap();
+        assert_eq!(header.unlock_key("password1", None).unwrap().0, key);
+        let err = vault.open(key, &bytes).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<VaultValidationErr>(),
+            Some(VaultValidationErr::TamperedVault)
+        ));
+
+        fs::remove_dir_all(&vault.p).unwrap();
+    }
+
+    // The way CONNECT gets there: header, then the password through Argon2, then
+    // the data.
+    fn unlock(
+        vault: &VaultMod,
+        bytes: &[u8],
+        password: &str,
+    ) -> Result<Zeroizing<Vec<u8>>, DynamicErr> {
+        let (header, _) = Vault::from_bytes(bytes)?;
+        let (key, _) = header.unlock_key(password, None)?;
+        vault.open(key, bytes)
+    }
+
+    #[test]
+    fn test_tampered_key_slot_is_not_a_wrong_password() {
+        let mut vault = temp_register();
+        seal_with_password(&mut vault, "password1", b"register");
+        let bytes = fs::read(vault.p.join(VAULT_N)).unwrap();
+        assert_eq!(*unlock(&vault, &bytes, "password1").unwrap(), b"register");
+        let err = unlock(&vault, &bytes, "password2").unwrap_err();
+        assert!(err.downcast_ref::<DecryptionErr>().is_some());
+
+        // Salt and KDF change the derived key, the header check tells them apart.
+        let (header, _) = Vault::from_bytes(&bytes).unwrap();
+        let slot = &header.slots[0];
+        let m_cost = slot.kdf.m_cost.to_le_bytes();
+        for field in [&slot.salt[..], &m_cost[..], &slot.wrapped[..]] {
+            let at = bytes.windows(field.len()).position(|w| w == field).unwrap();
+            let mut tampered = bytes.clone();
+            tampered[at] ^= 0x01;
+            let err = unlock(&vault, &tampered, "password1").unwrap_err();
+            assert!(matches!(
+                err.downcast_ref::<VaultValidationErr>(),
+                Some(VaultValidationErr::TamperedHeader)
+            ));
+        }
+
+        fs::remove_dir_all(&vault.p).unwrap();
+    }
+
+    #[test]
+    fn test_vault_does_not_open_in_another_register() {
+        let mut vault = temp_register();
+        let key = [5u8; 32];
+        vault.seal(key, b"register".to_vec()).unwrap();
+        let bytes = fs::read(vault.p.join(VAULT_N)).unwrap();
+
+        let mut other = temp_register();
+        let err = other.open(key, &bytes).unwrap_err();
+        assert!(err.downcast_ref::<VaultValidationErr>().is_some());
+
+        fs::remove_dir_all(&vault.p).unwrap();
+        fs::remove_dir_all(&other.p).unwrap();
+    }
+
+    #[test]
+    fn test_v1_vault_is_migrated_with_backup() {
+        let mut vault = temp_register();
+        let key = [6u8; 32];
+        let (salt, nonce) = ([1u8; 16], [2u8; 12]);
+        let mut v1 = b"PWMN".to_vec();
+        v1.extend_from_slice(&1u16.to_le_bytes());
+        v1.extend_from_slice(&salt);
+        v1.extend_from_slice(&nonce);
+        v1.extend_from_slice(&aead::encrypt(key, nonce, b"legacy".to_vec(), &[]).unwrap());
+        fs::write(vault.p.join(VAULT_N), &v1).unwrap();
+        vault.header = None;
+
+        let plaintext = vault.open(key, &v1).unwrap();
+        let (old_version, data_key) = vault.migrate(key, &plaintext).unwrap().unwrap();
+        assert_eq!(old_version, 1);
+        assert_ne!(data_key, key);
+        assert_eq!(fs::read(vault.p.join("vault.bin.v1.bak")).unwrap(), v1);
+
+        // The old key now wraps the data key in a slot with the old salt and KDF.
+        let bytes = fs::read(vault.p.join(VAULT_N)).unwrap();
+        let (header, _) = Vault::from_bytes(&bytes).unwrap();
+        assert_eq!(header.version, VAULT_VERSION);
+        assert_eq!(header.cipher, DEFAULT_CIPHER);
+        assert_eq!(header.slots.len(), 1);
+        assert!(!header.slots[0].is_legacy());
+        assert_eq!(header.slots[0].salt, salt);
+        assert_eq!(*vault.open(data_key, &bytes).unwrap(), b"legacy");
+        assert!(vault.open(key, &bytes).is_err());
+        assert_eq!(vault.migrate(data_key, b"legacy").unwrap(), None);
+
+        fs::remove_dir_all(&vault.p).unwrap();
+    }
+
+    #[test]
+    fn test_password_change_only_rewraps_the_key() {
+        let mut vault = temp_register();
+        let data_key = seal_with_password(&mut vault, "password1", b"register");
+        let before = fs::read(vault.p.join(VAULT_N)).unwrap();
+        let (_, offset) = Vault::from_bytes(&before).unwrap();
+
+        let mut header = vault.load_header().unwrap();
+        let (key, slot) = header.unlock_key("password1", None).unwrap();
+        header.slots[slot] = header
+            .password_slot("password2", None, TEST_KDF, &key)
+            .unwrap();
+        vault.rewrap(header).unwrap();
+
+        let after = fs::read(vault.p.join(VAULT_N)).unwrap();
+        let (header, after_offset) = Vault::from_bytes(&after).unwrap();
+        assert_eq!(&after[after_offset..], &before[offset..]);
+        assert!(header.unlock_key("password1", None).is_err());
+        assert_eq!(header.unlock_key("password2", None).unwrap().0, data_key);
+        assert_eq!(*vault.open(data_key, &after).unwrap(), b"register");
+        assert_eq!(after, fs::read(vault.p.join(AUTH)).unwrap());
+
+        // Anything but the slots is bound to the data and can't change this way.
+        let mut header = vault.load_header().unwrap();
+        header.nonce[0] ^= 0x01;
+        assert!(vault.rewrap(header).is_err());
+
+        fs::remove_dir_all(&vault.p).unwrap();
+    }
+
+    #[test]
+    fn test_removed_slot_is_taken_out_of_backups() {
+        let mut vault = temp_register();
+        let key = seal_with_password(&mut vault, "password1", b"first");
+        let mut header = vault.load_header().unwrap();
+        let extra = header
+            .password_slot("password2", None, TEST_KDF, &key)
+            .unwrap();
+        header.slots.push(extra.clone());
+        vault.rewrap(header).unwrap();
+        vault.seal(key, b"second".to_vec()).unwrap();
+
+        let mut header = vault.load_header().unwrap();
+        header.slots.retain(|s| s != &extra);
+        vault.rewrap(header).unwrap();
+
+        // The slot was in the two backups taken since it was added.
+        assert_eq!(backup::list(&vault.p).unwrap().len(), 3);
+        assert_eq!(vault.remove_slot_from_backups(&extra).unwrap(), 2);
+        for b in backup::list(&vault.p).unwrap() {
+            let bytes = fs::read(&b.path).unwrap();
+            let (header, _) = Vault::from_bytes(&bytes).unwrap();
+            assert!(header.unlock_key("password2", None).is_err());
+            assert_eq!(header.unlock_key("password1", None).unwrap().0, key);
+            assert!(vault.open(key, &bytes).is_ok());
+        }
+        assert_eq!(vault.remove_slot_from_backups(&extra).unwrap(), 0);
+
+        fs::remove_dir_all(&vault.p).unwrap();
+    }
+
+    #[test]
+    fn test_backups_follow_a_new_data_key() {
+        let mut vault = temp_register();
+        let earliest = seal_with_password(&mut vault, "password0", b"zeroth");
+        let old_key = seal_with_password(&mut vault, "password1", b"first");
+        vault.seal(old_key, b"second".to_vec()).unwrap();
+
+        let new_key = seal_with_password(&mut vault, "password2", b"third");
+        let slots = vault.load_header().unwrap().slots;
+        assert_eq!(vault.reseal_backups(old_key, new_key, &slots).unwrap(), 2);
+
+        let backups = backup::list(&vault.p).unwrap();
+        let opened: Vec<_> = backups
+            .iter()
+            .map(|b| {
+                let bytes = fs::read(&b.path).unwrap();
+                (vault.open(new_key, &bytes).ok(), read_header(&b.path).slots)
+            })
+            .collect();
+        assert_eq!(*opened[0].0.clone().unwrap(), b"second");
+        assert_eq!(*opened[1].0.clone().unwrap(), b"first");
+        assert_eq!((&opened[0].1, &opened[1].1), (&slots, &slots));
+        // The oldest is under a key the register never had at the same time.
+        assert!(opened[2].0.is_none());
+        let oldest = fs::read(&backups[2].path).unwrap();
+        assert_eq!(*vault.open(earliest, &oldest).unwrap(), b"zeroth");
+        assert_eq!(vault.older_copies().unwrap().len(), 3);
+
+        fs::remove_dir_all(&vault.p).unwrap();
+    }
+
+    #[test]
+    fn test_shorter_rewrite_leaves_no_trailing_bytes() {
+        let mut vault = temp_register();
+        let key = [7u8; 32];
+        vault.seal(key, vec![0xAA; 512]).unwrap();
+        vault.seal(key, b"short".to_vec()).unwrap();
+
+        let bytes = fs::read(vault.p.join(VAULT_N)).unwrap();
+        assert_eq!(*vault.open(key, &bytes).unwrap(), b"short");
+        let leftovers: Vec<_> = fs::read_dir(&vault.p)
+            .unwrap()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_name().to_string_lossy().ends_with(".tmp"))
+            .collect();
+        assert!(leftovers.is_empty());
+
+        fs::remove_dir_all(&vault.p).unwrap();
+    }
+
+    #[test]
+    fn test_interrupted_write_is_recovered() {
+        let mut vault = temp_register();
+        let key = [8u8; 32];
+        vault.seal(key, b"first".to_vec()).unwrap();
+        let good = fs::read(vault.p.join(VAULT_N)).unwrap();
+
+        // Crash before the rename: a stray temp file and a truncated vault.bin.
+        let stray = vault.p.join(".vault.bin.0123456789abcdef.tmp");
+        fs::write(&stray, b"partial").unwrap();
+        fs::write(vault.p.join(VAULT_N), &good[..10]).unwrap();
+
+        // Another process may still be writing a fresh one.
+        let in_flight = vault.p.join(".auth.pwmn.fedcba9876543210.tmp");
+        fs::write(&in_flight, b"partial").unwrap();
+        let an_hour_ago = std::time::SystemTime::now() - std::time::Duration::from_secs(3600);
+        File::options()
+            .write(true)
+            .open(&stray)
+            .unwrap()
+            .set_modified(an_hour_ago)
+            .unwrap();
+
+        assert!(vault.recover().unwrap());
+        assert!(!stray.exists());
+        assert!(in_flight.exists());
+        fs::remove_file(&in_flight).unwrap();
+        assert_eq!(fs::read(vault.p.join(VAULT_N)).unwrap(), good);
+
+        // Crash between the two renames: auth.pwmn still holds the previous save.
+        vault.seal(key, b"second".to_vec()).unwrap();
+        fs::write(vault.p.join(AUTH), &good).unwrap();
+        assert!(!vault.recover().unwrap());
+        assert!(vault.sync_auth().unwrap());
+        assert_eq!(
+            fs::read(vault.p.join(AUTH)).unwrap(),
+            fs::read(vault.p.join(VAULT_N)).unwrap()
+        );
+
+        fs::remove_dir_all(&vault.p).unwrap();
+    }
+}